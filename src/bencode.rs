@@ -0,0 +1,523 @@
+use std::collections::BTreeMap;
+
+/// nesting of lists and dictionaries deeper than this is rejected, so hostile input can't blow
+/// the stack
+pub const DEFAULT_MAX_DEPTH: usize = 100;
+/// values decoded from one input at most, so hostile input can't use up memory
+pub const DEFAULT_MAX_ITEMS: usize = 2_000_000;
+
+/// Why a bencoded input was rejected
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorKind {
+    /// the input stops in the middle of a value
+    UnexpectedEnd,
+    UnexpectedByte(u8),
+    /// an integer or string length that isn't a number or doesn't fit
+    InvalidInteger,
+    /// leading zeros or `-0`, which have a shorter encoding
+    NonCanonicalInteger,
+    UnsortedKeys,
+    DuplicateKey,
+    TooDeep,
+    TooManyItems,
+    /// bytes left over after the value
+    TrailingData,
+}
+
+impl std::fmt::Display for ErrorKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ErrorKind::UnexpectedEnd => f.write_str("unexpected end of input"),
+            ErrorKind::UnexpectedByte(byte) => write!(f, "unexpected byte {:?}", *byte as char),
+            ErrorKind::InvalidInteger => f.write_str("invalid integer"),
+            ErrorKind::NonCanonicalInteger => f.write_str("non-canonical integer"),
+            ErrorKind::UnsortedKeys => f.write_str("dictionary keys out of order"),
+            ErrorKind::DuplicateKey => f.write_str("duplicate dictionary key"),
+            ErrorKind::TooDeep => f.write_str("nested too deep"),
+            ErrorKind::TooManyItems => f.write_str("too many values"),
+            ErrorKind::TrailingData => f.write_str("trailing data"),
+        }
+    }
+}
+
+/// A bencoded input that couldn't be decoded, and where
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Error {
+    pub kind: ErrorKind,
+    /// offset of the offending byte in the input
+    pub position: usize,
+}
+
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} at byte {}", self.kind, self.position)
+    }
+}
+
+impl std::error::Error for Error {}
+
+/// A bencoded value, borrowing its strings from the input it was decoded from
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Value<'a> {
+    Int(i64),
+    Bytes(&'a [u8]),
+    List(Vec<Value<'a>>),
+    Dict(Dict<'a>),
+}
+
+/// A bencoded dictionary, which remembers where it was in the input when decoded
+#[derive(Debug, Clone, Default)]
+pub struct Dict<'a> {
+    entries: BTreeMap<&'a [u8], Value<'a>>,
+    /// the dictionary exactly as it was decoded, empty for one built in code
+    raw: &'a [u8],
+}
+
+/// two dictionaries are equal when their entries are, wherever they came from
+impl PartialEq for Dict<'_> {
+    fn eq(&self, other: &Self) -> bool {
+        self.entries == other.entries
+    }
+}
+
+impl Eq for Dict<'_> {}
+
+impl<'a> Dict<'a> {
+    pub fn new() -> Self {
+        Dict::default()
+    }
+
+    pub fn get(&self, key: impl AsRef<[u8]>) -> Option<&Value<'a>> {
+        self.entries.get(key.as_ref())
+    }
+
+    pub fn insert(&mut self, key: &'a [u8], value: Value<'a>) {
+        self.entries.insert(key, value);
+    }
+
+    /// Iterates over the entries in key order
+    pub fn iter(&self) -> impl Iterator<Item = (&'a [u8], &Value<'a>)> {
+        self.entries.iter().map(|(key, value)| (*key, value))
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// The encoded dictionary as it appeared in the input, `None` if it wasn't decoded
+    pub fn raw(&self) -> Option<&'a [u8]> {
+        (!self.raw.is_empty()).then_some(self.raw)
+    }
+
+    pub fn get_int(&self, key: impl AsRef<[u8]>) -> Option<i64> {
+        self.get(key)?.as_int()
+    }
+
+    pub fn get_bytes(&self, key: impl AsRef<[u8]>) -> Option<&'a [u8]> {
+        self.get(key)?.as_bytes()
+    }
+
+    pub fn get_str(&self, key: impl AsRef<[u8]>) -> Option<&'a str> {
+        self.get(key)?.as_str()
+    }
+
+    pub fn get_list(&self, key: impl AsRef<[u8]>) -> Option<&[Value<'a>]> {
+        self.get(key)?.as_list()
+    }
+
+    pub fn get_dict(&self, key: impl AsRef<[u8]>) -> Option<&Dict<'a>> {
+        self.get(key)?.as_dict()
+    }
+}
+
+impl<'a> FromIterator<(&'a [u8], Value<'a>)> for Dict<'a> {
+    fn from_iter<I: IntoIterator<Item = (&'a [u8], Value<'a>)>>(iter: I) -> Self {
+        Dict {
+            entries: iter.into_iter().collect(),
+            raw: &[],
+        }
+    }
+}
+
+impl<'a> Value<'a> {
+    pub fn as_int(&self) -> Option<i64> {
+        match self {
+            Value::Int(n) => Some(*n),
+            _ => None,
+        }
+    }
+
+    pub fn as_bytes(&self) -> Option<&'a [u8]> {
+        match self {
+            Value::Bytes(bytes) => Some(bytes),
+            _ => None,
+        }
+    }
+
+    /// The string, if it is valid UTF-8
+    pub fn as_str(&self) -> Option<&'a str> {
+        std::str::from_utf8(self.as_bytes()?).ok()
+    }
+
+    pub fn as_list(&self) -> Option<&[Value<'a>]> {
+        match self {
+            Value::List(list) => Some(list),
+            _ => None,
+        }
+    }
+
+    pub fn as_dict(&self) -> Option<&Dict<'a>> {
+        match self {
+            Value::Dict(dict) => Some(dict),
+            _ => None,
+        }
+    }
+
+    /// Follows `path` through nested dictionaries, e.g. `["info", "name"]`
+    pub fn lookup(&self, path: &[&str]) -> Option<&Value<'a>> {
+        path.iter()
+            .try_fold(self, |value, key| value.as_dict()?.get(key))
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut out = Vec::new();
+        self.encode_to(&mut out);
+        out
+    }
+
+    /// Appends the canonical encoding of the value to `out`
+    pub fn encode_to(&self, out: &mut Vec<u8>) {
+        match self {
+            Value::Int(n) => {
+                out.push(b'i');
+                out.extend_from_slice(n.to_string().as_bytes());
+                out.push(b'e');
+            }
+            Value::Bytes(bytes) => encode_bytes(bytes, out),
+            Value::List(list) => {
+                out.push(b'l');
+                for value in list {
+                    value.encode_to(out);
+                }
+                out.push(b'e');
+            }
+            Value::Dict(dict) => {
+                out.push(b'd');
+                for (key, value) in dict.iter() {
+                    encode_bytes(key, out);
+                    value.encode_to(out);
+                }
+                out.push(b'e');
+            }
+        }
+    }
+}
+
+fn encode_bytes(bytes: &[u8], out: &mut Vec<u8>) {
+    out.extend_from_slice(bytes.len().to_string().as_bytes());
+    out.push(b':');
+    out.extend_from_slice(bytes);
+}
+
+/// Decodes `bytes`, which must hold exactly one value in canonical form
+pub fn decode(bytes: &[u8]) -> Result<Value<'_>, Error> {
+    Decoder::new(bytes).decode_all()
+}
+
+/// Decodes bencoded values without copying strings out of the input
+#[derive(Debug)]
+pub struct Decoder<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+    /// reject anything that doesn't have exactly one encoding
+    strict: bool,
+    max_depth: usize,
+    max_items: usize,
+    depth: usize,
+    items: usize,
+}
+
+impl<'a> Decoder<'a> {
+    pub fn new(bytes: &'a [u8]) -> Self {
+        Decoder {
+            bytes,
+            pos: 0,
+            strict: true,
+            max_depth: DEFAULT_MAX_DEPTH,
+            max_items: DEFAULT_MAX_ITEMS,
+            depth: 0,
+            items: 0,
+        }
+    }
+
+    /// Accepts unsorted dictionary keys and numbers with leading zeros, which some trackers send
+    pub fn lenient(mut self) -> Self {
+        self.strict = false;
+        self
+    }
+
+    pub fn max_depth(mut self, max_depth: usize) -> Self {
+        self.max_depth = max_depth;
+        self
+    }
+
+    pub fn max_items(mut self, max_items: usize) -> Self {
+        self.max_items = max_items;
+        self
+    }
+
+    /// Offset of the next byte to decode, e.g. where the payload after a message starts
+    pub fn position(&self) -> usize {
+        self.pos
+    }
+
+    /// Decodes the next value, leaving whatever follows it
+    pub fn decode(&mut self) -> Result<Value<'a>, Error> {
+        self.items += 1;
+        if self.items > self.max_items {
+            return Err(self.error(ErrorKind::TooManyItems));
+        }
+        match self.peek()? {
+            b'i' => {
+                self.pos += 1;
+                let n = self.integer(b'e')?;
+                Ok(Value::Int(n))
+            }
+            b'0'..=b'9' => Ok(Value::Bytes(self.bytes()?)),
+            b'l' => {
+                self.enter()?;
+                let mut list = Vec::new();
+                while self.peek()? != b'e' {
+                    list.push(self.decode()?);
+                }
+                self.pos += 1;
+                self.depth -= 1;
+                Ok(Value::List(list))
+            }
+            b'd' => {
+                let start = self.pos;
+                self.enter()?;
+                let mut entries = BTreeMap::new();
+                let mut last: Option<&[u8]> = None;
+                while self.peek()? != b'e' {
+                    let key_pos = self.pos;
+                    if !self.peek()?.is_ascii_digit() {
+                        return Err(self.error(ErrorKind::UnexpectedByte(self.peek()?)));
+                    }
+                    let key = self.bytes()?;
+                    if self.strict && last.is_some_and(|last| key < last) {
+                        return Err(Error {
+                            kind: ErrorKind::UnsortedKeys,
+                            position: key_pos,
+                        });
+                    }
+                    let value = self.decode()?;
+                    if entries.insert(key, value).is_some() {
+                        return Err(Error {
+                            kind: ErrorKind::DuplicateKey,
+                            position: key_pos,
+                        });
+                    }
+                    last = Some(key);
+                }
+                self.pos += 1;
+                self.depth -= 1;
+                Ok(Value::Dict(Dict {
+                    entries,
+                    raw: &self.bytes[start..self.pos],
+                }))
+            }
+            byte => Err(self.error(ErrorKind::UnexpectedByte(byte))),
+        }
+    }
+
+    /// Decodes one value and fails if anything follows it
+    pub fn decode_all(&mut self) -> Result<Value<'a>, Error> {
+        let value = self.decode()?;
+        if self.pos != self.bytes.len() {
+            return Err(self.error(ErrorKind::TrailingData));
+        }
+        Ok(value)
+    }
+
+    fn error(&self, kind: ErrorKind) -> Error {
+        Error {
+            kind,
+            position: self.pos,
+        }
+    }
+
+    fn peek(&self) -> Result<u8, Error> {
+        self.bytes
+            .get(self.pos)
+            .copied()
+            .ok_or(self.error(ErrorKind::UnexpectedEnd))
+    }
+
+    /// Steps into a list or dictionary
+    fn enter(&mut self) -> Result<(), Error> {
+        if self.depth == self.max_depth {
+            return Err(self.error(ErrorKind::TooDeep));
+        }
+        self.depth += 1;
+        self.pos += 1;
+        Ok(())
+    }
+
+    /// Reads a decimal number up to `end` and steps over `end`
+    fn integer(&mut self, end: u8) -> Result<i64, Error> {
+        let start = self.pos;
+        let negative = self.peek()? == b'-';
+        if negative {
+            self.pos += 1;
+        }
+        let digits = self.pos;
+        let mut n: i64 = 0;
+        loop {
+            match self.peek()? {
+                byte @ b'0'..=b'9' => {
+                    let digit = i64::from(byte - b'0');
+                    n = n
+                        .checked_mul(10)
+                        .and_then(|n| {
+                            if negative {
+                                n.checked_sub(digit)
+                            } else {
+                                n.checked_add(digit)
+                            }
+                        })
+                        .ok_or(Error {
+                            kind: ErrorKind::InvalidInteger,
+                            position: start,
+                        })?;
+                    self.pos += 1;
+                }
+                byte if byte == end => break,
+                byte => return Err(self.error(ErrorKind::UnexpectedByte(byte))),
+            }
+        }
+        let len = self.pos - digits;
+        if len == 0 {
+            return Err(self.error(ErrorKind::InvalidInteger));
+        }
+        if self.strict && self.bytes[digits] == b'0' && (len > 1 || negative) {
+            return Err(Error {
+                kind: ErrorKind::NonCanonicalInteger,
+                position: start,
+            });
+        }
+        self.pos += 1;
+        Ok(n)
+    }
+
+    /// Reads a length-prefixed string
+    fn bytes(&mut self) -> Result<&'a [u8], Error> {
+        let start = self.pos;
+        let len = usize::try_from(self.integer(b':')?).map_err(|_| Error {
+            kind: ErrorKind::InvalidInteger,
+            position: start,
+        })?;
+        let end = self
+            .pos
+            .checked_add(len)
+            .filter(|&end| end <= self.bytes.len())
+            .ok_or(self.error(ErrorKind::UnexpectedEnd))?;
+        let bytes = &self.bytes[self.pos..end];
+        self.pos = end;
+        Ok(bytes)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_decode() {
+        let input = b"d4:infod4:name1:a6:lengthi-3ee4:listli1e0:e1:xi0ee";
+        let value = Decoder::new(input).lenient().decode_all().unwrap();
+        assert_eq!(
+            value.lookup(&["info", "name"]).and_then(Value::as_str),
+            Some("a")
+        );
+        assert_eq!(
+            value.lookup(&["info", "length"]).and_then(Value::as_int),
+            Some(-3)
+        );
+        let top = value.as_dict().unwrap();
+        assert_eq!(
+            top.get_list("list").unwrap(),
+            [Value::Int(1), Value::Bytes(b"")]
+        );
+        assert_eq!(top.get_int("x"), Some(0));
+        // the span of a nested dictionary is kept
+        assert_eq!(
+            top.get_dict("info").unwrap().raw(),
+            Some(&b"d4:name1:a6:lengthi-3ee"[..])
+        );
+        assert_eq!(top.raw(), Some(&input[..]));
+    }
+
+    #[test]
+    fn test_decode_errors() {
+        let kind = |input: &[u8]| decode(input).unwrap_err().kind;
+        assert_eq!(kind(b"i03e"), ErrorKind::NonCanonicalInteger);
+        assert_eq!(kind(b"i-0e"), ErrorKind::NonCanonicalInteger);
+        assert_eq!(kind(b"ie"), ErrorKind::InvalidInteger);
+        assert_eq!(kind(b"i99999999999999999999e"), ErrorKind::InvalidInteger);
+        assert_eq!(kind(b"02:ab"), ErrorKind::NonCanonicalInteger);
+        assert_eq!(kind(b"5:ab"), ErrorKind::UnexpectedEnd);
+        assert_eq!(kind(b"d1:b0:1:a0:e"), ErrorKind::UnsortedKeys);
+        assert_eq!(kind(b"d1:a0:1:a0:e"), ErrorKind::DuplicateKey);
+        assert_eq!(kind(b"di1e0:e"), ErrorKind::UnexpectedByte(b'i'));
+        assert_eq!(kind(b"l"), ErrorKind::UnexpectedEnd);
+        assert_eq!(kind(b"i1ei2e"), ErrorKind::TrailingData);
+        assert_eq!(decode(b"i1ex").unwrap_err().position, 3);
+
+        let nested = [b"l".repeat(200), b"e".repeat(200)].concat();
+        assert_eq!(kind(&nested), ErrorKind::TooDeep);
+        let nested = [b"l".repeat(50), b"e".repeat(50)].concat();
+        assert!(decode(&nested).is_ok());
+        let many = b"li1ei2ei3ee";
+        assert_eq!(
+            Decoder::new(many).max_items(3).decode().unwrap_err().kind,
+            ErrorKind::TooManyItems
+        );
+
+        // lenient decoding takes what some trackers send
+        let value = Decoder::new(b"d1:bi01e1:a0:e")
+            .lenient()
+            .decode_all()
+            .unwrap();
+        assert_eq!(value.as_dict().unwrap().get_int("b"), Some(1));
+    }
+
+    #[test]
+    fn test_encode() {
+        let mut dict: Dict = [(&b"z"[..], Value::Int(-7)), (b"a", Value::Bytes(b"xy"))]
+            .into_iter()
+            .collect();
+        dict.insert(
+            b"m",
+            Value::List(vec![Value::Int(0), Value::Dict(Dict::new())]),
+        );
+        let encoded = Value::Dict(dict).encode();
+        assert_eq!(encoded, b"d1:a2:xy1:mli0edee1:zi-7ee");
+        // canonical input survives a round trip unchanged
+        assert_eq!(decode(&encoded).unwrap().encode(), encoded);
+    }
+
+    #[test]
+    fn test_decode_prefix() {
+        // a message followed by a payload, as in metadata extension messages
+        let input = b"d5:piecei0ee\x01\x02";
+        let mut decoder = Decoder::new(input);
+        let value = decoder.decode().unwrap();
+        assert_eq!(value.as_dict().unwrap().get_int("piece"), Some(0));
+        assert_eq!(&input[decoder.position()..], [1, 2]);
+    }
+}
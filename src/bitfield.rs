@@ -1,25 +1,35 @@
 use std::io::{Error, ErrorKind};
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, Clone, PartialEq)]
 pub struct BitField {
     pub payload: Vec<u8>,
+    /// number of bits, the payload may hold a few more to fill its last byte
     pub len: usize,
 }
 
 impl BitField {
+    /// Wraps a payload received from a peer, every bit of it counts
     pub fn new(payload: Vec<u8>) -> BitField {
-        let len = payload.len();
+        let len = payload.len() * 8;
         BitField { payload, len }
     }
 
+    /// Creates an empty bitfield able to hold `num_pieces` bits
+    pub fn with_len(num_pieces: usize) -> BitField {
+        BitField {
+            payload: vec![0; num_pieces.div_ceil(8)],
+            len: num_pieces,
+        }
+    }
+
     pub fn from_bytes(bytes: &[u8]) -> Result<BitField, Error> {
-        if bytes.len() < 1 {
+        if bytes.is_empty() {
             return Err(Error::new(
                 ErrorKind::InvalidData,
                 "BitField message should be at least 1 byte long",
             ));
         }
-        let len = bytes.len() - 1;
+        let len = (bytes.len() - 1) * 8;
         let bitfield = bytes[1..].to_vec();
         Ok(BitField {
             payload: bitfield,
@@ -28,18 +38,18 @@ impl BitField {
     }
 
     pub fn to_bytes(&self) -> Vec<u8> {
-        let mut bytes = vec![0; self.len + 1];
-        bytes[0] = self.len as u8;
+        let mut bytes = vec![0; self.payload.len() + 1];
+        bytes[0] = self.payload.len() as u8;
         bytes[1..].copy_from_slice(&self.payload);
         bytes
     }
 
-    /// Returns true if the bit at the given index is set.
+    /// Returns true if the bit at the given index is set, false past the end
     pub fn is_set(&self, index: usize) -> bool {
         let byte = index / 8;
         let bit = index % 8;
         let mask = 1 << (7 - bit);
-        self.payload[byte] & mask != 0
+        index < self.len && self.payload.get(byte).is_some_and(|byte| byte & mask != 0)
     }
 
     pub fn set(&mut self, index: usize) {
@@ -60,7 +70,11 @@ impl BitField {
         self.len
     }
 
-    pub fn iter(&self) -> BitfieldIter {
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn iter(&self) -> BitfieldIter<'_> {
         BitfieldIter {
             bitfield: self,
             index: 0,
@@ -113,7 +127,9 @@ mod tests {
     #[test]
     fn test_bitfield_new() {
         let bitfield = BitField::new(vec![0b00000001, 0b00000000]);
-        assert_eq!(bitfield.len, 2);
+        assert_eq!(bitfield.len, 16);
+        assert_eq!(bitfield.pieces(), vec![7]);
+        assert_eq!(BitField::with_len(10).len, 10);
         assert_eq!(bitfield.payload, vec![0b00000001, 0b00000000]);
     }
 
@@ -128,5 +144,7 @@ mod tests {
         assert!(!bf.has_piece(7));
         assert!(!bf.has_piece(8));
         assert!(bf.has_piece(15));
+        assert!(!bf.has_piece(16));
+        assert!(!bf.has_piece(usize::MAX));
     }
 }
@@ -0,0 +1,372 @@
+use crate::sanitize;
+use crate::torrent::{MetainfoError, Torrent};
+use std::ops::Range;
+use std::path::PathBuf;
+
+/// BEP 47 attributes of a file
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct FileAttributes {
+    /// zeroes aligning the next file to a piece boundary, never stored on disk
+    pub padding: bool,
+    pub executable: bool,
+    pub hidden: bool,
+    pub symlink: bool,
+}
+
+impl FileAttributes {
+    /// Parses the `attr` string of a torrent file, unknown letters are ignored
+    pub fn parse(attr: &str) -> FileAttributes {
+        FileAttributes {
+            padding: attr.contains('p'),
+            executable: attr.contains('x'),
+            hidden: attr.contains('h'),
+            symlink: attr.contains('l'),
+        }
+    }
+}
+
+/// A file of the torrent and where it sits in the concatenation of all files
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FileEntry {
+    /// path relative to the download directory, starting with the torrent name
+    pub path: PathBuf,
+    pub length: u64,
+    /// offset of the first byte of the file in the torrent
+    pub offset: u64,
+    pub attributes: FileAttributes,
+    /// where a symlink points, relative to the torrent directory
+    pub symlink_target: Option<PathBuf>,
+    /// SHA-1 of the whole file, if the torrent has one
+    pub sha1: Option<[u8; 20]>,
+}
+
+impl FileEntry {
+    /// Returns the offset one past the last byte of the file in the torrent
+    pub fn end(&self) -> u64 {
+        self.offset + self.length
+    }
+
+    /// Returns false for padding files and symlinks, which have nothing to store
+    pub fn has_data(&self) -> bool {
+        !self.attributes.padding && !self.attributes.symlink
+    }
+}
+
+/// A byte range inside one file
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FileSlice {
+    pub file_index: usize,
+    /// offset inside the file
+    pub offset: u64,
+    pub length: u64,
+}
+
+/// Maps pieces to the files they cover and back
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Layout {
+    files: Vec<FileEntry>,
+    piece_length: u64,
+    total_length: u64,
+}
+
+impl Layout {
+    /// Builds a layout from `(relative path, length)` pairs in torrent order, fails if the files
+    /// add up to more than a `u64` holds
+    pub fn new(files: Vec<(PathBuf, u64)>, piece_length: u64) -> Result<Layout, MetainfoError> {
+        let mut offset = 0u64;
+        let files = files
+            .into_iter()
+            .map(|(path, length)| {
+                let entry = FileEntry {
+                    path,
+                    length,
+                    offset,
+                    attributes: FileAttributes::default(),
+                    symlink_target: None,
+                    sha1: None,
+                };
+                offset = offset
+                    .checked_add(length)
+                    .ok_or(MetainfoError::Invalid("length"))?;
+                Ok(entry)
+            })
+            .collect::<Result<_, MetainfoError>>()?;
+        Ok(Layout {
+            files,
+            piece_length,
+            total_length: offset,
+        })
+    }
+
+    /// Builds the layout of a torrent: a single-file torrent is the file `name`, a multi-file
+    /// torrent puts each of its files under the directory `name`. The lengths of a torrent were
+    /// already checked when it was loaded.
+    pub fn from_torrent(torrent: &Torrent) -> Layout {
+        // names and paths come from whoever made the torrent and must not escape the download
+        // directory
+        let root =
+            PathBuf::from(sanitize::sanitize_component(torrent.name()).unwrap_or("_".into()));
+        let info = &torrent.info;
+        let Some(files) = &info.files else {
+            let mut layout = Layout::new(vec![(root, torrent.length())], torrent.piece_length())
+                .expect("length of a loaded torrent");
+            let attributes = FileAttributes::parse(info.attr.as_deref().unwrap_or_default());
+            layout.set_attributes(0, attributes, None);
+            layout.set_file_hash(0, info.sha1);
+            return layout;
+        };
+        let mut paths: Vec<PathBuf> = files
+            .iter()
+            .map(|f| root.join(sanitize::sanitize_path(&torrent.file_path(f))))
+            .collect();
+        sanitize::make_unique(&mut paths);
+        let mut layout = Layout::new(
+            paths
+                .into_iter()
+                .zip(files)
+                .map(|(path, f)| (path, f.length))
+                .collect(),
+            torrent.piece_length(),
+        )
+        .expect("length of a loaded torrent");
+        for (index, file) in files.iter().enumerate() {
+            let attributes = FileAttributes::parse(file.attr.as_deref().unwrap_or_default());
+            let target = file.symlink_path.as_ref().map(|path| {
+                let path: Vec<String> = path.iter().map(|c| torrent.decode_text(c)).collect();
+                sanitize::sanitize_path(&path)
+            });
+            layout.set_attributes(index, attributes, target);
+            layout.set_file_hash(index, file.sha1);
+        }
+        layout
+    }
+
+    /// Sets the BEP 47 attributes of a file, and where it points if it is a symlink
+    pub fn set_attributes(
+        &mut self,
+        index: usize,
+        attributes: FileAttributes,
+        symlink_target: Option<PathBuf>,
+    ) {
+        let entry = &mut self.files[index];
+        entry.attributes = attributes;
+        entry.symlink_target = symlink_target.filter(|_| attributes.symlink);
+    }
+
+    /// Sets the SHA-1 of a whole file
+    pub fn set_file_hash(&mut self, index: usize, sha1: Option<[u8; 20]>) {
+        self.files[index].sha1 = sha1;
+    }
+
+    /// Returns true if every byte of a piece falls in padding files, so it is known to be zeroes
+    pub fn is_padding(&self, piece_index: usize) -> bool {
+        self.piece_files(piece_index)
+            .iter()
+            .all(|slice| self.files[slice.file_index].attributes.padding)
+    }
+
+    pub fn files(&self) -> &[FileEntry] {
+        &self.files
+    }
+
+    pub fn piece_length(&self) -> u64 {
+        self.piece_length
+    }
+
+    pub fn total_length(&self) -> u64 {
+        self.total_length
+    }
+
+    pub fn num_pieces(&self) -> usize {
+        self.total_length.div_ceil(self.piece_length) as usize
+    }
+
+    /// Returns the offset of a piece in the torrent
+    pub fn piece_offset(&self, piece_index: usize) -> u64 {
+        piece_index as u64 * self.piece_length
+    }
+
+    /// Returns the size of a piece, only the last one may be shorter than `piece_length`
+    pub fn piece_size(&self, piece_index: usize) -> u64 {
+        let offset = self.piece_offset(piece_index);
+        self.piece_length
+            .min(self.total_length.saturating_sub(offset))
+    }
+
+    /// Splits `length` bytes at `offset` in the torrent into per-file slices, zero-length files
+    /// never appear
+    pub fn map(&self, offset: u64, length: u64) -> Vec<FileSlice> {
+        let mut slices = Vec::new();
+        let end = (offset + length).min(self.total_length);
+        let mut index = self.files.partition_point(|f| f.end() <= offset);
+        let mut offset = offset;
+        while offset < end && index < self.files.len() {
+            let file = &self.files[index];
+            let len = file.end().min(end) - offset;
+            if len > 0 {
+                slices.push(FileSlice {
+                    file_index: index,
+                    offset: offset - file.offset,
+                    length: len,
+                });
+            }
+            offset += len;
+            index += 1;
+        }
+        slices
+    }
+
+    /// Returns the byte ranges of every file a piece touches
+    pub fn piece_files(&self, piece_index: usize) -> Vec<FileSlice> {
+        self.map(self.piece_offset(piece_index), self.piece_size(piece_index))
+    }
+
+    /// Returns the pieces holding any byte of a file, empty for zero-length files
+    pub fn file_pieces(&self, file_index: usize) -> Range<usize> {
+        let file = &self.files[file_index];
+        if file.length == 0 {
+            return 0..0;
+        }
+        let first = file.offset / self.piece_length;
+        let last = (file.end() - 1) / self.piece_length;
+        first as usize..last as usize + 1
+    }
+}
+
+/// The torrent most tests use: 9 bytes counting up from 0 in pieces of 4 over `t/a` (3 bytes)
+/// and `t/b` (6 bytes), with its data and piece hashes
+#[cfg(test)]
+pub(crate) fn test_torrent() -> (Vec<u8>, Layout, Vec<[u8; 20]>) {
+    use sha1::{Digest, Sha1};
+    let data: Vec<u8> = (0..9).collect();
+    let piece_hashes = data
+        .chunks(4)
+        .map(|chunk| Sha1::digest(chunk).into())
+        .collect();
+    let layout = Layout::new(
+        vec![(PathBuf::from("t/a"), 3), (PathBuf::from("t/b"), 6)],
+        4,
+    )
+    .unwrap();
+    (data, layout, piece_hashes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_layout_rejects_overflow() {
+        let files = vec![(PathBuf::from("t/a"), u64::MAX), (PathBuf::from("t/b"), 1)];
+        assert!(Layout::new(files, 4).is_err());
+    }
+
+    #[test]
+    fn test_layout_piece_files() {
+        let (_, layout, _) = test_torrent();
+        assert_eq!(layout.num_pieces(), 3);
+        assert_eq!(layout.piece_size(2), 1);
+        assert_eq!(
+            layout.piece_files(0),
+            vec![
+                FileSlice {
+                    file_index: 0,
+                    offset: 0,
+                    length: 3
+                },
+                FileSlice {
+                    file_index: 1,
+                    offset: 0,
+                    length: 1
+                },
+            ]
+        );
+        assert_eq!(
+            layout.piece_files(2),
+            vec![FileSlice {
+                file_index: 1,
+                offset: 5,
+                length: 1
+            }]
+        );
+    }
+
+    #[test]
+    fn test_layout_file_pieces() {
+        // pieces of 4 bytes over files of 3, 0 and 6 bytes
+        let layout = Layout::new(
+            vec![
+                (PathBuf::from("t/a"), 3),
+                (PathBuf::from("t/empty"), 0),
+                (PathBuf::from("t/b"), 6),
+            ],
+            4,
+        )
+        .unwrap();
+        assert_eq!(layout.file_pieces(0), 0..1);
+        assert_eq!(layout.file_pieces(1), 0..0);
+        assert_eq!(layout.file_pieces(2), 0..3);
+    }
+
+    #[test]
+    fn test_layout_attributes() {
+        let attr = FileAttributes::parse("xh");
+        assert!(attr.executable && attr.hidden && !attr.padding && !attr.symlink);
+
+        // a padding file aligning `b` to the second piece
+        let mut layout = Layout::new(
+            vec![
+                (PathBuf::from("t/a"), 3),
+                (PathBuf::from("t/.pad/1"), 5),
+                (PathBuf::from("t/b"), 4),
+            ],
+            4,
+        )
+        .unwrap();
+        layout.set_attributes(1, FileAttributes::parse("p"), None);
+        assert!(!layout.files()[1].has_data());
+        assert!(!layout.is_padding(0));
+        assert!(layout.is_padding(1));
+        assert!(!layout.is_padding(2));
+    }
+
+    #[test]
+    fn test_layout_debian() {
+        let torrent = Torrent::from_file(crate::DEBIAN_FILE).unwrap();
+        let layout = torrent.layout();
+        assert_eq!(layout.files().len(), 1);
+        assert_eq!(layout.files()[0].path, PathBuf::from(torrent.name()));
+        assert_eq!(layout.num_pieces(), torrent.piece_hashes().len());
+        assert_eq!(layout.file_pieces(0), 0..torrent.piece_hashes().len());
+    }
+
+    #[test]
+    fn test_layout_sanitized() {
+        let torrent = Torrent::from_bytes(
+            b"d4:infod5:filesl\
+              d6:lengthi1e4:pathl2:..2:..6:passwdee\
+              d6:lengthi1e4:pathl1:a3:b:cee\
+              d6:lengthi1e4:pathl1:a3:b:cee\
+              d6:lengthi1e4:pathl4:/abs3:nulee\
+              d4:attr1:l6:lengthi0e4:pathl4:linke12:symlink pathl2:..3:etceee\
+              4:name4:../t12:piece lengthi4e6:pieces20:aaaaaaaaaaaaaaaaaaaaee",
+        )
+        .unwrap();
+        let layout = torrent.layout();
+        let paths: Vec<_> = layout.files().iter().map(|f| f.path.clone()).collect();
+        assert_eq!(
+            paths,
+            [
+                ".._t/passwd",
+                ".._t/a/b_c",
+                ".._t/a/b_c.1",
+                ".._t/_abs/nul_",
+                ".._t/link"
+            ]
+            .iter()
+            .map(PathBuf::from)
+            .collect::<Vec<_>>()
+        );
+        assert_eq!(layout.files()[4].symlink_target, Some(PathBuf::from("etc")));
+    }
+}
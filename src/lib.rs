@@ -1,5 +1,9 @@
+pub mod bencode;
 pub mod bitfield;
-
+pub mod layout;
+pub mod picker;
+pub mod resume;
+pub mod sanitize;
 pub mod storage;
 pub mod torrent;
 pub mod utils;
@@ -9,7 +13,10 @@ pub mod tracker {
 }
 
 pub mod peer {
+    pub mod codec;
     pub mod connection;
+    pub mod listener;
+    pub mod manager;
     pub mod message;
 }
 
@@ -1,6 +1,28 @@
-use bobby_bit::torrent::{self, Torrent};
+use anyhow::Context;
+use bobby_bit::bitfield::BitField;
+use bobby_bit::layout::Layout;
+use bobby_bit::peer::manager::{Limits, Manager, PeerEvent};
+use bobby_bit::peer::message::Message;
+use bobby_bit::picker::{PeerRequests, PiecePicker, Priority};
+use bobby_bit::resume::{self, ResumeData, ResumeWriter};
+use bobby_bit::storage::check::{CheckMode, CheckProgress};
+use bobby_bit::storage::disk::{DiskEvent, DiskIo};
+use bobby_bit::storage::error::StorageError;
+use bobby_bit::storage::hasher::HashPool;
+use bobby_bit::storage::{self, Allocation, Storage, StorageOptions};
+use bobby_bit::torrent::builder::TorrentBuilder;
+use bobby_bit::torrent::Torrent;
 use bobby_bit::utils;
-use clap::Parser;
+use clap::{Args, CommandFactory, Parser, Subcommand};
+use crossbeam::channel::{self, Receiver};
+use mio::{Token, Waker};
+use std::collections::HashMap;
+use std::io::Write;
+use std::net::SocketAddr;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
 /*
 TODO:
@@ -10,26 +32,664 @@ TODO:
 
 */
 
+/// how often resume data is written while downloading
+const RESUME_INTERVAL: Duration = Duration::from_secs(30);
+/// how often writing is retried while the download is paused by a disk error
+const DISK_RETRY_INTERVAL: Duration = Duration::from_secs(10);
+
+/// largest block served to peers, bigger requests are ignored
+const MAX_REQUEST_LENGTH: u32 = 16 * 1024;
+
+/// set by SIGINT or SIGTERM, the download saves its resume data and exits
+static SHUTDOWN: AtomicBool = AtomicBool::new(false);
+
 #[derive(Parser, Debug)]
+#[command(args_conflicts_with_subcommands = true)]
 struct Cli {
+    #[command(subcommand)]
+    command: Option<Command>,
+    /// the download arguments without `download`, as before there were subcommands
+    #[command(flatten)]
+    download: Option<DownloadArgs>,
+}
+
+#[derive(Args, Debug)]
+struct DownloadArgs {
     #[clap(short, long, help = "path to *.torrent file")]
     file: String,
     #[clap(short, long, default_value = "6969")]
     port: u16,
     #[clap(short, long, help = "path where to save the downloaded file")]
     out: String,
+    #[clap(
+        short,
+        long,
+        default_value = "sparse",
+        help = "how files are created: sparse, full or none (on first write)"
+    )]
+    allocation: Allocation,
+    #[clap(
+        long,
+        help = "directory to download to, the files are moved to --out once complete"
+    )]
+    incomplete: Option<String>,
+    #[clap(long, help = "add a .part suffix to files until they are complete")]
+    part_suffix: bool,
+    #[clap(long, default_value = "512", help = "most files kept open at once")]
+    max_open_files: usize,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// download a torrent, checking data that already exists first. File priorities can be
+    /// changed while it runs by typing `priority <file index> <skip|low|normal|high>`
+    Download(DownloadArgs),
+    /// hash-check downloaded data and report the bad pieces
+    Verify {
+        #[clap(short, long, help = "path to *.torrent file")]
+        file: String,
+        #[clap(short, long, help = "path where the torrent was downloaded")]
+        out: String,
+    },
+    /// create a .torrent of a file or directory
+    Create {
+        #[clap(short, long, help = "file or directory to share")]
+        path: String,
+        #[clap(
+            short,
+            long,
+            help = "where to write the .torrent, <name>.torrent by default"
+        )]
+        out: Option<String>,
+        #[clap(
+            short,
+            long,
+            help = "tracker URL, each one is a tier, comma separated URLs share a tier"
+        )]
+        tracker: Vec<String>,
+        #[clap(short, long, help = "URL of a web seed serving the files")]
+        web_seed: Vec<String>,
+        #[clap(
+            long,
+            help = "piece length in bytes, picked from the total size by default"
+        )]
+        piece_length: Option<u64>,
+        #[clap(short, long)]
+        comment: Option<String>,
+        #[clap(long, help = "only get peers from the trackers")]
+        private: bool,
+        #[clap(
+            long,
+            help = "source tag, gives the torrent a different info hash per site"
+        )]
+        source: Option<String>,
+        #[clap(long, help = "align each file to a piece with padding files")]
+        pad: bool,
+        #[clap(
+            long,
+            help = "leave out the creation date so the torrent is reproducible"
+        )]
+        no_date: bool,
+    },
 }
 
 fn main() {
     let args = Cli::parse();
     println!("{:?}", args);
 
+    let command = match (args.command, args.download) {
+        (Some(command), _) => command,
+        (None, Some(download)) => Command::Download(download),
+        (None, None) => {
+            let _ = Cli::command().print_help();
+            std::process::exit(2);
+        }
+    };
+    match command {
+        Command::Download(DownloadArgs {
+            file,
+            port,
+            out,
+            allocation,
+            incomplete,
+            part_suffix,
+            max_open_files,
+        }) => {
+            let options = StorageOptions {
+                allocation,
+                part_suffix,
+                max_open_files,
+                read_only: false,
+            };
+            download(&file, port, &out, incomplete.as_deref(), options)
+        }
+        Command::Verify { file, out } => verify(&file, &out),
+        Command::Create {
+            path,
+            out,
+            tracker,
+            web_seed,
+            piece_length,
+            comment,
+            private,
+            source,
+            pad,
+            no_date,
+        } => {
+            let mut builder = TorrentBuilder::new(&path);
+            for tier in tracker {
+                builder.add_tier(tier.split(',').map(str::to_string).collect());
+            }
+            for url in web_seed {
+                builder.add_web_seed(url);
+            }
+            if let Some(piece_length) = piece_length {
+                builder.set_piece_length(piece_length);
+            }
+            if let Some(comment) = comment {
+                builder.set_comment(comment);
+            }
+            if let Some(source) = source {
+                builder.set_source(source);
+            }
+            builder.set_private(private);
+            builder.set_pad_files(pad);
+            if no_date {
+                builder.set_creation_date(None);
+            }
+            create(builder, out.as_deref())
+        }
+    }
+}
+
+/// Hashes the files and writes the .torrent, exiting with an error if that fails
+fn create(builder: TorrentBuilder, out: Option<&str>) {
+    if let Err(e) = write_torrent(builder, out) {
+        eprintln!("{:#}", e);
+        std::process::exit(1);
+    }
+}
+
+fn write_torrent(builder: TorrentBuilder, out: Option<&str>) -> anyhow::Result<()> {
+    let bytes = builder.build()?;
+    let torrent = Torrent::from_bytes(&bytes)?;
+    let out = match out {
+        Some(out) => out.to_string(),
+        None => format!("{}.torrent", torrent.name()),
+    };
+    std::fs::write(&out, &bytes).with_context(|| format!("could not write {}", out))?;
+    let info_hash: String = torrent
+        .info_hash()
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect();
+    println!(
+        "wrote {} ({} pieces, info hash {})",
+        out,
+        torrent.piece_hashes().len(),
+        info_hash
+    );
+    Ok(())
+}
+
+/// Prints the progress of a recheck on a single line
+fn print_progress(progress: CheckProgress) {
+    print!("\rchecked {}/{} pieces", progress.checked, progress.total);
+    if progress.checked == progress.total {
+        println!();
+    }
+    let _ = std::io::stdout().flush();
+}
+
+fn verify(file: &str, out: &str) {
+    let torrent = Torrent::from_file(file).unwrap();
+    // only look at what is there, nothing is created, resized or written
+    let options = StorageOptions {
+        read_only: true,
+        ..StorageOptions::default()
+    };
+    let mut storage = Storage::new(&torrent, Path::new(out), options).unwrap();
+    let mut pool = HashPool::default();
+    let report = storage
+        .recheck_parallel(
+            CheckMode::Full,
+            &mut pool,
+            &AtomicBool::new(false),
+            print_progress,
+        )
+        .unwrap();
+
+    if report.bad.is_empty() {
+        println!("all {} pieces are good", report.checked);
+        return;
+    }
+    println!("{} of {} pieces are bad", report.bad.len(), report.checked);
+    let paths = storage.paths();
+    for (file_index, pieces) in report.bad_files(storage.layout()) {
+        println!("{}: pieces {:?}", paths[file_index].display(), pieces);
+    }
+    std::process::exit(1);
+}
+
+#[cfg(unix)]
+extern "C" fn on_shutdown_signal(_signal: libc::c_int) {
+    SHUTDOWN.store(true, Ordering::Relaxed);
+}
+
+/// Makes SIGINT and SIGTERM set `SHUTDOWN` instead of killing the process
+#[cfg(unix)]
+fn handle_shutdown_signals() {
+    let handler = on_shutdown_signal as extern "C" fn(libc::c_int) as libc::sighandler_t;
+    unsafe {
+        libc::signal(libc::SIGINT, handler);
+        libc::signal(libc::SIGTERM, handler);
+    }
+}
+
+#[cfg(not(unix))]
+fn handle_shutdown_signals() {}
+
+/// Returns true if we have the piece and the requested block lies within it, no larger than
+/// `MAX_REQUEST_LENGTH`
+fn is_valid_request(layout: &Layout, have: &BitField, index: u32, begin: u32, length: u32) -> bool {
+    have.is_set(index as usize)
+        && length > 0
+        && length <= MAX_REQUEST_LENGTH
+        && begin as u64 + length as u64 <= layout.piece_size(index as usize)
+}
+
+/// Reads priority commands from stdin on a thread of its own, waking the event loop for each
+fn read_commands(waker: Arc<Waker>) -> Receiver<(usize, Priority)> {
+    let (sender, receiver) = channel::unbounded();
+    std::thread::spawn(move || {
+        for line in std::io::stdin().lines() {
+            let Ok(line) = line else { break };
+            match parse_priority(&line) {
+                Ok(command) => {
+                    if sender.send(command).is_err() {
+                        break;
+                    }
+                    let _ = waker.wake();
+                }
+                Err(e) => eprintln!("{}", e),
+            }
+        }
+    });
+    receiver
+}
+
+/// Parses `priority <file index> <skip|low|normal|high>`
+fn parse_priority(line: &str) -> anyhow::Result<(usize, Priority)> {
+    match line.split_whitespace().collect::<Vec<_>>()[..] {
+        ["priority", index, priority] => Ok((index.parse()?, priority.parse()?)),
+        _ => anyhow::bail!("expected: priority <file index> <skip|low|normal|high>"),
+    }
+}
+
+fn download(
+    file: &str,
+    port: u16,
+    out: &str,
+    incomplete: Option<&str>,
+    mut options: StorageOptions,
+) {
     // generate a random peer id
     let peer_id = utils::generate_peer_id();
 
     // read the torrent file
-    let torrent: Torrent = Torrent::from_file(&args.file).unwrap();
+    let torrent: Torrent = Torrent::from_file(file).unwrap();
+
+    // a single event loop drives every peer, incoming ones arrive on the announced port
+    let mut manager = Manager::new(peer_id, Limits::default()).unwrap();
+
+    // pick up where we left off if the files haven't changed since the last run
+    let info_hash = torrent.info_hash();
+    let out = Path::new(out);
+    let on_disk = |root: &Path, part_suffix: bool| {
+        torrent.layout().files().iter().any(|f| {
+            let path = root.join(&f.path);
+            if part_suffix {
+                storage::with_part_suffix(&path).exists()
+            } else {
+                path.exists()
+            }
+        })
+    };
+    // a download that already finished is seeded from where it was moved to, anything else
+    // goes to the incomplete directory until it is done. Without either option there is
+    // nothing to do on completion.
+    let mut finished = on_disk(out, false) || incomplete.is_none() && !options.part_suffix;
+    let root = match incomplete {
+        Some(dir) if !finished => Path::new(dir),
+        _ => out,
+    };
+    if finished {
+        options.part_suffix = false;
+    }
+    let existing = on_disk(root, options.part_suffix);
+    let mut storage = Storage::new(&torrent, root, options).unwrap();
+    let resume_path = resume::resume_path(out, &info_hash);
+    let mut known_peers: Vec<SocketAddr> = Vec::new();
+    // bytes sent to peers over every session of the torrent
+    let mut uploaded = 0;
+    let resumed = match ResumeData::load(&resume_path) {
+        Ok(data) => match data.restore(info_hash, &mut storage) {
+            Ok(true) => {
+                log::info!("resumed with {} pieces", storage.have().pieces().len());
+                known_peers = data.peers();
+                uploaded = data.uploaded;
+                true
+            }
+            Ok(false) => {
+                log::info!("resume data is stale, ignoring it");
+                false
+            }
+            Err(e) => {
+                log::warn!("could not restore resume data: {}", e);
+                false
+            }
+        },
+        Err(e) => {
+            log::debug!("no resume data: {}", e);
+            false
+        }
+    };
+    // otherwise whatever is already on disk has to be hashed before we download anything
+    if existing && !resumed {
+        let report = storage
+            .recheck_parallel(
+                CheckMode::Full,
+                &mut HashPool::default(),
+                &AtomicBool::new(false),
+                print_progress,
+            )
+            .unwrap();
+        log::info!(
+            "{} pieces already downloaded",
+            storage.have().pieces().len()
+        );
+        log::debug!("recheck found {} bad pieces", report.bad.len());
+    }
+    if let Err(e) = storage.check_free_space() {
+        eprintln!("{}", e);
+        std::process::exit(1);
+    }
+    let mut resume_writer = ResumeWriter::new(resume_path, RESUME_INTERVAL);
+
+    let port = manager.listen(port).unwrap();
+    manager.add_torrent(info_hash, storage.have().clone());
+    let mut have = storage.have().clone();
+
+    // pieces are picked rarest first among the ones we want, one at a time for each peer
+    let layout = storage.layout().clone();
+    let mut picker = PiecePicker::new(layout.num_pieces());
+    picker.set_priorities(storage.piece_priorities());
+    for piece in have.pieces() {
+        picker.set_have(piece);
+    }
+    let mut requests: HashMap<Token, PeerRequests> = HashMap::new();
+    let commands = read_commands(manager.waker());
+
+    // from here on the storage lives on the disk thread, which hashes pieces on a pool as they
+    // complete
+    let disk = DiskIo::new(storage, Some(manager.waker()));
+    // complete before it started, e.g. stopped while the files were being moved
+    if picker.is_done() && !finished {
+        disk.finish(Some(out.to_path_buf()));
+        finished = true;
+    }
 
     // find peers (will try to use udp if possible)
-    let peers = utils::find_peers(&torrent, peer_id, args.port);
+    let peers = utils::find_peers(&torrent, peer_id, port);
+    log::info!("found {} peers", peers.len());
+    for peer in peers {
+        if !known_peers.contains(&peer) {
+            known_peers.push(peer);
+        }
+    }
+    for &peer in &known_peers {
+        if let Err(e) = manager.connect(peer, info_hash) {
+            log::warn!("could not connect to {:?}: {}", peer, e);
+        }
+    }
+
+    // set while a disk error keeps us from writing, with when writing was last tried
+    let mut disk_error: Option<(StorageError, Instant)> = None;
+    let capture = |uploaded: u64| -> anyhow::Result<ResumeData> {
+        let mut data = disk.call(move |storage| ResumeData::capture(info_hash, storage))?;
+        data.uploaded = uploaded;
+        data.set_peers(&known_peers);
+        Ok(data)
+    };
+
+    handle_shutdown_signals();
+    while !SHUTDOWN.load(Ordering::Relaxed) {
+        let events = match manager.poll(Some(Duration::from_secs(1))) {
+            Ok(events) => events,
+            // a signal arrived while waiting
+            Err(e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
+            Err(e) => panic!("polling failed: {}", e),
+        };
+        for event in events {
+            match event {
+                PeerEvent::Connected { token, addr, .. } => {
+                    log::info!("connected to {:?}", addr);
+                    requests.insert(token, PeerRequests::new(layout.num_pieces()));
+                    let _ = manager.send(token, Message::Interested);
+                }
+                PeerEvent::Message {
+                    token,
+                    message: Message::Bitfield(payload),
+                } => {
+                    if let Some(peer) = requests.get_mut(&token) {
+                        peer.set_bitfield(&mut picker, &BitField::new(payload));
+                    }
+                }
+                PeerEvent::Message {
+                    token,
+                    message: Message::Have(index),
+                } => {
+                    if let Some(peer) = requests.get_mut(&token) {
+                        peer.set_have(&mut picker, index as usize);
+                    }
+                }
+                // a peer drops our requests when it chokes us
+                PeerEvent::Message {
+                    token,
+                    message: Message::Choke,
+                } => {
+                    if let Some(peer) = requests.get_mut(&token) {
+                        peer.cancel(&mut picker);
+                    }
+                }
+                PeerEvent::Message {
+                    token,
+                    message: Message::Piece(index, begin, block),
+                } => {
+                    let requested = requests.get_mut(&token).is_some_and(|peer| {
+                        peer.receive(index as usize, begin as usize, block.len())
+                    });
+                    if requested {
+                        disk.write(index as usize, begin as usize, block);
+                    } else {
+                        log::debug!(
+                            "{:?} sent block {} of piece {} unasked",
+                            token,
+                            begin,
+                            index
+                        );
+                    }
+                }
+                // every interested peer is unchoked, there are no upload slots yet
+                PeerEvent::Message {
+                    token,
+                    message: Message::Interested,
+                } => {
+                    let _ = manager.send(token, Message::Unchoke);
+                }
+                PeerEvent::Message {
+                    token,
+                    message: Message::NotInterested,
+                } => {
+                    let _ = manager.send(token, Message::Choke);
+                }
+                PeerEvent::Message {
+                    token,
+                    message: Message::Request(index, begin, length),
+                } => {
+                    let unchoked = manager.peer(token).is_some_and(|peer| !peer.am_choking);
+                    if unchoked && is_valid_request(&layout, &have, index, begin, length) {
+                        disk.read(token.0, index as usize, begin as usize, length as usize)
+                    } else {
+                        log::debug!(
+                            "{:?} sent an invalid request for {} bytes at {} of piece {}",
+                            token,
+                            length,
+                            begin,
+                            index
+                        );
+                    }
+                }
+                PeerEvent::Message { token, message } => {
+                    log::debug!("{:?} sent {:?}", token, message);
+                }
+                PeerEvent::Closed { token, error } => {
+                    log::debug!("{:?} closed: {}", token, error);
+                    if let Some(peer) = requests.remove(&token) {
+                        peer.remove(&mut picker);
+                    }
+                }
+            }
+        }
+
+        while let Ok((index, priority)) = commands.try_recv() {
+            let changed = disk.call(move |storage| {
+                storage.set_file_priority(index, priority)?;
+                anyhow::Ok(storage.piece_priorities())
+            });
+            match changed {
+                Ok(priorities) => {
+                    log::info!("file {} is now {:?}", index, priority);
+                    picker.set_priorities(priorities);
+                }
+                Err(e) => log::warn!("could not change the priority of file {}: {}", index, e),
+            }
+        }
+
+        // peers closed while sending to them get no `Closed` event
+        let gone: Vec<Token> = requests
+            .keys()
+            .filter(|&&token| manager.peer(token).is_none())
+            .copied()
+            .collect();
+        for token in gone {
+            if let Some(peer) = requests.remove(&token) {
+                peer.remove(&mut picker);
+            }
+        }
+        // keep every peer that unchoked us busy with a piece
+        for (&token, peer) in requests.iter_mut() {
+            if manager
+                .peer(token)
+                .is_none_or(|connection| connection.is_choked())
+            {
+                continue;
+            }
+            for (piece, offset, length) in peer.request(&mut picker, &layout) {
+                let message = Message::Request(piece as u32, offset as u32, length as u32);
+                if manager.send(token, message).is_err() {
+                    break;
+                }
+            }
+        }
+
+        while let Some(event) = disk.try_recv() {
+            match event {
+                DiskEvent::PieceVerified {
+                    piece_index,
+                    valid: true,
+                } => {
+                    have.set(piece_index);
+                    picker.set_have(piece_index);
+                    manager.have_piece(&info_hash, piece_index);
+                    if picker.is_done() && !finished {
+                        disk.finish(Some(out.to_path_buf()));
+                        finished = true;
+                    }
+                }
+                DiskEvent::PieceVerified { piece_index, .. } => {
+                    log::warn!("piece {} failed the hash check", piece_index);
+                    picker.cancel(piece_index);
+                }
+                DiskEvent::Read {
+                    tag,
+                    piece_index,
+                    offset,
+                    block,
+                } => match block {
+                    // the peer may have been choked since it asked
+                    Ok(block)
+                        if manager
+                            .peer(Token(tag))
+                            .is_some_and(|peer| !peer.am_choking) =>
+                    {
+                        let length = block.len() as u64;
+                        let message = Message::Piece(piece_index as u32, offset as u32, block);
+                        if manager.send(Token(tag), message).is_ok() {
+                            uploaded += length;
+                        }
+                    }
+                    Ok(_) => {}
+                    Err(e) => log::warn!("could not read piece {}: {}", piece_index, e),
+                },
+                DiskEvent::Error(error) => {
+                    log::error!("download paused, {}", error);
+                    disk_error = Some((error, Instant::now()));
+                }
+                DiskEvent::Finished(Ok(())) => {
+                    log::info!("download complete, moved to {}", out.display())
+                }
+                DiskEvent::Finished(Err(e)) => {
+                    log::error!("could not move the finished download: {}", e)
+                }
+                DiskEvent::Resumed => {
+                    log::info!("disk is writable again, download resumed");
+                    disk_error = None;
+                }
+                DiskEvent::Discarded {
+                    piece_index,
+                    offset,
+                    ..
+                } => {
+                    log::debug!("dropped block {} of piece {}", offset, piece_index);
+                    picker.cancel(piece_index);
+                }
+            }
+        }
+
+        // keep retrying until the user fixed whatever stopped the disk
+        if let Some((error, tried)) = &mut disk_error {
+            if tried.elapsed() >= DISK_RETRY_INTERVAL {
+                log::info!("retrying after {}", error.kind);
+                disk.resume();
+                *tried = Instant::now();
+            }
+        }
+
+        // stop reading from peers while the disk can't keep up or is failing
+        if disk.is_congested() || disk_error.is_some() {
+            manager.pause_reads();
+        } else if manager.reads_paused() {
+            manager.resume_reads();
+        }
+
+        if let Err(e) = resume_writer.save_if_due(|| capture(uploaded)) {
+            log::warn!("could not save resume data: {}", e);
+        }
+    }
+
+    log::info!("shutting down");
+    if let Err(e) = capture(uploaded).and_then(|data| resume_writer.save(&data)) {
+        log::warn!("could not save resume data: {}", e);
+    }
 }
@@ -0,0 +1,288 @@
+use crate::peer::message::{Handshake, Message};
+use bytes::{Buf, Bytes, BytesMut};
+use std::collections::VecDeque;
+use std::io::{Error, ErrorKind, IoSlice, Read, Write};
+
+/// length of a BitTorrent v1 handshake on the wire
+pub const HANDSHAKE_LEN: usize = 68;
+/// default cap on a single message, large enough for a 16 KiB block or the bitfield of a
+/// torrent with ~8 million pieces
+pub const DEFAULT_MAX_MESSAGE_LEN: usize = 1 << 20;
+/// how much room the receive buffer gets before each read
+const READ_CHUNK: usize = 32 * 1024;
+/// how many queued buffers are handed to a single vectored write
+const MAX_IO_SLICES: usize = 64;
+
+/// Incrementally turns bytes read from a socket into a handshake followed by
+/// length-prefixed messages, buffering whatever is incomplete
+#[derive(Debug)]
+pub struct Decoder {
+    buf: BytesMut,
+    max_len: usize,
+}
+
+impl Default for Decoder {
+    fn default() -> Self {
+        Decoder::new(DEFAULT_MAX_MESSAGE_LEN)
+    }
+}
+
+impl Decoder {
+    /// Creates a decoder rejecting messages whose length prefix is above `max_len`
+    pub fn new(max_len: usize) -> Decoder {
+        Decoder {
+            buf: BytesMut::new(),
+            max_len,
+        }
+    }
+
+    /// Appends freshly read bytes
+    pub fn extend(&mut self, bytes: &[u8]) {
+        self.buf.extend_from_slice(bytes);
+    }
+
+    /// Reads once from `reader` straight into the receive buffer, returning the number of bytes
+    /// read (0 at end of stream)
+    pub fn read_from<R: Read>(&mut self, reader: &mut R) -> Result<usize, Error> {
+        let start = self.buf.len();
+        self.buf.resize(start + READ_CHUNK, 0);
+        let result = reader.read(&mut self.buf[start..]);
+        let read = *result.as_ref().unwrap_or(&0);
+        self.buf.truncate(start + read);
+        result
+    }
+
+    /// Returns true once a whole frame of the largest allowed size fits in the buffer, reading
+    /// more before decoding would only grow it
+    pub fn is_full(&self) -> bool {
+        self.buf.len() >= 4 + self.max_len
+    }
+
+    /// Returns the number of buffered bytes
+    pub fn buffered(&self) -> usize {
+        self.buf.len()
+    }
+
+    /// Returns the handshake once all of its 68 bytes are buffered
+    pub fn decode_handshake(&mut self) -> Result<Option<Handshake>, Error> {
+        if self.buf.len() < HANDSHAKE_LEN {
+            return Ok(None);
+        }
+        let handshake = Handshake::from_bytes(&self.buf.split_to(HANDSHAKE_LEN))?;
+        Ok(Some(handshake))
+    }
+
+    /// Returns the next message once its whole frame is buffered. Oversized length prefixes are
+    /// rejected as soon as they arrive, before any of the payload is buffered. A `Piece` block
+    /// keeps pointing into the receive buffer rather than being copied out of it.
+    pub fn decode(&mut self) -> Result<Option<Message>, Error> {
+        if self.buf.len() < 4 {
+            return Ok(None);
+        }
+        let len = u32::from_be_bytes([self.buf[0], self.buf[1], self.buf[2], self.buf[3]]) as usize;
+        if len > self.max_len {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                format!("Message of {} bytes exceeds limit of {}", len, self.max_len),
+            ));
+        }
+        if self.buf.len() < 4 + len {
+            // make room for the rest of the frame in one go
+            self.buf.reserve(4 + len - self.buf.len());
+            return Ok(None);
+        }
+        let frame = self.buf.split_to(4 + len).freeze();
+        Message::from_frame(frame).map(Some)
+    }
+}
+
+/// Serializes outgoing data into a send buffer that is reused across writes. `Piece` blocks are
+/// queued as their own buffers and written with vectored I/O instead of being copied in.
+#[derive(Debug, Default)]
+pub struct Encoder {
+    /// small messages accumulate here until the next write
+    buf: BytesMut,
+    /// frozen chunks waiting for the socket, in wire order
+    queue: VecDeque<Bytes>,
+}
+
+impl Encoder {
+    pub fn new() -> Encoder {
+        Encoder::default()
+    }
+
+    pub fn encode_handshake(&mut self, handshake: &Handshake) {
+        self.buf.extend_from_slice(&handshake.to_bytes());
+    }
+
+    pub fn encode(&mut self, message: &Message) {
+        match message {
+            Message::Piece(_, _, block) => {
+                message.encode_header_into(&mut self.buf);
+                self.queue.push_back(self.buf.split().freeze());
+                self.queue.push_back(block.clone());
+            }
+            _ => message.encode_into(&mut self.buf),
+        }
+    }
+
+    /// Returns true if there are bytes waiting to be written
+    pub fn has_pending(&self) -> bool {
+        !self.buf.is_empty() || !self.queue.is_empty()
+    }
+
+    /// Writes buffered bytes until the buffer is empty or the writer would block, returning how
+    /// many bytes were written
+    pub fn write_to<W: Write>(&mut self, writer: &mut W) -> Result<usize, Error> {
+        if !self.buf.is_empty() {
+            self.queue.push_back(self.buf.split().freeze());
+        }
+
+        let mut written = 0;
+        while !self.queue.is_empty() {
+            let slices: Vec<IoSlice> = self
+                .queue
+                .iter()
+                .take(MAX_IO_SLICES)
+                .map(|chunk| IoSlice::new(chunk))
+                .collect();
+            let mut n = match writer.write_vectored(&slices) {
+                Ok(0) => return Err(Error::new(ErrorKind::WriteZero, "peer stopped reading")),
+                Ok(n) => n,
+                Err(e) if e.kind() == ErrorKind::WouldBlock => break,
+                Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            };
+            written += n;
+
+            while n > 0 {
+                let front = self.queue.front_mut().unwrap();
+                if n >= front.len() {
+                    n -= front.len();
+                    self.queue.pop_front();
+                } else {
+                    front.advance(n);
+                    n = 0;
+                }
+            }
+        }
+        Ok(written)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_decoder_partial_reads() {
+        let frame = [0, 0, 0, 5, 4, 0, 0, 1, 2];
+        let mut decoder = Decoder::default();
+        for byte in &frame[..frame.len() - 1] {
+            decoder.extend(&[*byte]);
+            assert_eq!(decoder.decode().unwrap(), None);
+        }
+        decoder.extend(&frame[frame.len() - 1..]);
+        assert_eq!(decoder.decode().unwrap(), Some(Message::Have(258)));
+        assert_eq!(decoder.buffered(), 0);
+    }
+
+    #[test]
+    fn test_decoder_handshake_then_messages() {
+        let mut bytes = Handshake::new([1; 20], [2; 20]).to_bytes();
+        bytes.extend([0, 0, 0, 0]);
+        bytes.extend([0, 0, 0, 1, 1]);
+        bytes.extend([0, 0, 0, 13, 6, 0, 0, 0, 1, 0, 0, 64, 0, 0, 0, 64, 0]);
+
+        let mut decoder = Decoder::default();
+        decoder.extend(&bytes);
+        let handshake = decoder.decode_handshake().unwrap().unwrap();
+        assert_eq!(handshake.peer_id, [2; 20]);
+        assert_eq!(decoder.decode().unwrap(), Some(Message::KeepAlive));
+        assert_eq!(decoder.decode().unwrap(), Some(Message::Unchoke));
+        assert_eq!(
+            decoder.decode().unwrap(),
+            Some(Message::Request(1, 16384, 16384))
+        );
+        assert_eq!(decoder.decode().unwrap(), None);
+    }
+
+    #[test]
+    fn test_decoder_rejects_oversized() {
+        let mut decoder = Decoder::new(16);
+        decoder.extend(&[0, 0, 0, 17]);
+        assert!(decoder.decode().is_err());
+    }
+
+    #[test]
+    fn test_decoder_fills_up() {
+        let mut decoder = Decoder::new(16);
+        let wire = [0u8; 64];
+        assert_eq!(decoder.read_from(&mut &wire[..]).unwrap(), 64);
+        assert!(decoder.is_full());
+        // decoding the keep-alives makes room again
+        for _ in 0..16 {
+            assert_eq!(decoder.decode().unwrap(), Some(Message::KeepAlive));
+        }
+        assert!(!decoder.is_full());
+    }
+
+    #[test]
+    fn test_decoder_piece() {
+        let mut decoder = Decoder::default();
+        decoder.extend(&[0, 0, 0, 12, 7, 0, 0, 0, 3, 0, 0, 0, 8, 9, 9, 9]);
+        assert_eq!(
+            decoder.decode().unwrap(),
+            Some(Message::Piece(3, 8, Bytes::from(vec![9, 9, 9])))
+        );
+    }
+
+    #[test]
+    fn test_encoder_partial_writes() {
+        /// accepts at most two bytes per write, then blocks
+        struct Slow(Vec<u8>, usize);
+        impl Write for Slow {
+            fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+                if self.1 == 0 {
+                    return Err(Error::new(ErrorKind::WouldBlock, "full"));
+                }
+                self.1 -= 1;
+                let n = buf.len().min(2);
+                self.0.extend_from_slice(&buf[..n]);
+                Ok(n)
+            }
+            fn flush(&mut self) -> std::io::Result<()> {
+                Ok(())
+            }
+        }
+
+        let mut encoder = Encoder::new();
+        encoder.encode(&Message::Have(1));
+        let mut writer = Slow(Vec::new(), 2);
+        assert_eq!(encoder.write_to(&mut writer).unwrap(), 4);
+        assert!(encoder.has_pending());
+        writer.1 = 10;
+        assert_eq!(encoder.write_to(&mut writer).unwrap(), 5);
+        assert!(!encoder.has_pending());
+        assert_eq!(writer.0, vec![0, 0, 0, 5, 4, 0, 0, 0, 1]);
+    }
+
+    #[test]
+    fn test_encoder_piece_round_trip() {
+        let block = Bytes::from(vec![3; 16384]);
+        let mut encoder = Encoder::new();
+        encoder.encode(&Message::Have(1));
+        encoder.encode(&Message::Piece(1, 0, block.clone()));
+        encoder.encode(&Message::Unchoke);
+
+        let mut wire = Vec::new();
+        encoder.write_to(&mut wire).unwrap();
+        assert!(!encoder.has_pending());
+
+        let mut decoder = Decoder::default();
+        decoder.read_from(&mut &wire[..]).unwrap();
+        assert_eq!(decoder.decode().unwrap(), Some(Message::Have(1)));
+        assert_eq!(decoder.decode().unwrap(), Some(Message::Piece(1, 0, block)));
+        assert_eq!(decoder.decode().unwrap(), Some(Message::Unchoke));
+    }
+}
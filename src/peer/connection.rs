@@ -1,21 +1,38 @@
 use crate::bitfield::BitField;
+use crate::peer::codec::{Decoder, Encoder};
 use crate::peer::message::{Handshake, Message};
 use mio::net::TcpStream;
-use mio::{Events, Interest, Poll, Token};
-use std::io::{Error, ErrorKind, Read, Write};
+use mio::{Interest, Registry, Token};
+use std::io::{Error, ErrorKind};
 use std::net::SocketAddr;
-use std::time::Duration;
+use std::time::Instant;
+
+/// Where a connection is in its lifecycle
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum State {
+    /// non-blocking connect has been started but hasn't finished yet
+    Connecting,
+    /// TCP is up, waiting for the handshake of the remote peer
+    Handshaking,
+    /// handshakes have been exchanged, messages can flow
+    Connected,
+}
 
 pub struct Connection {
     /// this id can be changed for different peers to avoid being blacklisted
     pub my_id: [u8; 20],
     pub stream: TcpStream,
-    pub poll: Poll,
     pub token: Token,
     pub addr: SocketAddr,
     /// the peer id of the remote peer (recv in handshake)
     pub peer_id: [u8; 20],
+    /// for incoming peers this is only known once their handshake arrives
     pub info_hash: [u8; 20],
+    /// true if we initiated the connection
+    pub outgoing: bool,
+    pub state: State,
+    /// when the connection entered its current state, used for timeouts
+    pub since: Instant,
 
     // peer state
     pub am_choking: bool,
@@ -26,12 +43,20 @@ pub struct Connection {
     pub downloaded: u32,
     pub uploaded: u32,
     pub left: u32,
+
+    /// bytes received but not yet parsed
+    decoder: Decoder,
+    /// bytes queued but not yet accepted by the socket
+    encoder: Encoder,
 }
 
 impl std::fmt::Debug for Connection {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         f.debug_struct("Connection")
             .field("addr", &self.addr)
+            .field("token", &self.token)
+            .field("state", &self.state)
+            .field("outgoing", &self.outgoing)
             .field("peer_id", &self.peer_id)
             .field("info_hash", &self.info_hash)
             .field("am_choking", &self.am_choking)
@@ -47,131 +72,240 @@ impl std::fmt::Debug for Connection {
 }
 
 impl Connection {
-    /// Creates a new connection to a peer, initiates a handshake, and returns the connection
-    pub fn new(
+    fn with_stream(
+        stream: TcpStream,
+        token: Token,
+        addr: SocketAddr,
+        info_hash: [u8; 20],
+        my_id: [u8; 20],
+        outgoing: bool,
+        state: State,
+    ) -> Connection {
+        Connection {
+            my_id,
+            stream,
+            token,
+            addr,
+            peer_id: [0; 20], // will be set after handshake
+            info_hash,
+            outgoing,
+            state,
+            since: Instant::now(),
+            am_choking: true,
+            am_interested: false,
+            peer_choking: true,
+            peer_interested: false,
+            bitfield: BitField::new(vec![0; 0]),
+            downloaded: 0,
+            uploaded: 0,
+            left: 0,
+            decoder: Decoder::default(),
+            encoder: Encoder::new(),
+        }
+    }
+
+    /// Starts a non-blocking connect to a peer, the handshake is sent once the socket becomes
+    /// writable
+    pub fn connect(
         peer: SocketAddr,
+        token: Token,
         info_hash: [u8; 20],
         my_id: [u8; 20],
     ) -> Result<Connection, Error> {
-        let poll = Poll::new()?;
-        let token = Token(0);
-        let addr = peer;
-        let am_choking = true;
-        let am_interested = false;
-        let peer_choking = true;
-        let peer_interested = false;
-        let bitfield = BitField::new(vec![0; 0]);
-        let downloaded = 0;
-        let uploaded = 0;
-        let left = 0;
-
-        // connect to peer
-        let mut stream = TcpStream::connect(peer)?;
-        let peer_id = [0; 20]; // will be set after handshake
-
-        log::info!("Connected to {:?}", peer);
-
-        // start polling for events, try to send handshake
-        poll.registry()
-            .register(&mut stream, token, Interest::READABLE | Interest::WRITABLE)?;
-        let mut events = Events::with_capacity(1024);
-        let mut connection = Connection {
+        let stream = TcpStream::connect(peer)?;
+        log::debug!("Connecting to {:?}", peer);
+        Ok(Connection::with_stream(
+            stream,
+            token,
+            peer,
+            info_hash,
             my_id,
+            true,
+            State::Connecting,
+        ))
+    }
+
+    /// Wraps a peer that connected to us, we wait for its handshake before answering
+    pub fn accepted(
+        stream: TcpStream,
+        addr: SocketAddr,
+        token: Token,
+        my_id: [u8; 20],
+    ) -> Connection {
+        Connection::with_stream(
             stream,
-            poll,
             token,
             addr,
-            peer_id,
-            info_hash,
-            am_choking,
-            am_interested,
-            peer_choking,
-            peer_interested,
-            bitfield,
-            downloaded,
-            uploaded,
-            left,
-        };
+            [0; 20],
+            my_id,
+            false,
+            State::Handshaking,
+        )
+    }
 
-        let handshake = Handshake::new(info_hash, peer_id);
-        let timeout = Duration::from_secs(3); // Adjust the timeout as needed
+    /// Registers the socket for both readiness kinds, mio is edge triggered so we only hear
+    /// about writability again after a write hits `WouldBlock`
+    pub fn register(&mut self, registry: &Registry) -> Result<(), Error> {
+        registry.register(
+            &mut self.stream,
+            self.token,
+            Interest::READABLE | Interest::WRITABLE,
+        )
+    }
 
-        // TODO: ensure this doesn't block forever
+    pub fn deregister(&mut self, registry: &Registry) {
+        let _ = registry.deregister(&mut self.stream);
+    }
 
+    fn set_state(&mut self, state: State) {
+        self.state = state;
+        self.since = Instant::now();
+    }
+
+    /// Handles a writable event: finishes a pending connect and flushes queued bytes
+    pub fn on_writable(&mut self) -> Result<(), Error> {
+        if self.state == State::Connecting {
+            if let Some(e) = self.stream.take_error()? {
+                return Err(e);
+            }
+            match self.stream.peer_addr() {
+                Ok(_) => {
+                    log::info!("Connected to {:?}", self.addr);
+                    self.set_state(State::Handshaking);
+                    let handshake = Handshake::new(self.info_hash, self.my_id);
+                    self.encoder.encode_handshake(&handshake);
+                }
+                // spurious wakeup, connect is still in progress
+                Err(e) if e.kind() == ErrorKind::NotConnected => return Ok(()),
+                Err(e) => return Err(e),
+            }
+        }
+        self.flush()
+    }
+
+    /// Handles a readable event: drains the socket into the read buffer. Returns true if reading
+    /// stopped because the buffer is full, the socket may still hold data that won't be reported
+    /// again.
+    pub fn on_readable(&mut self) -> Result<bool, Error> {
         loop {
-            connection.poll.poll(&mut events, Some(timeout))?;
-            for event in events.iter() {
-                match event.token() {
-                    Token(0) => {
-                        if event.is_writable() {
-                            // send handshake
-                            let bytes = handshake.to_bytes();
-                            connection.stream.write_all(&bytes)?;
-                            log::debug!("Sent handshake to {:?}", peer);
-
-                            // reregister stream to only listen for readable events
-                            connection.poll.registry().reregister(
-                                &mut connection.stream,
-                                connection.token,
-                                Interest::READABLE,
-                            )?;
-                        }
-                        if event.is_readable() {
-                            // read handshake
-                            let mut buf = vec![0; 68];
-                            connection.stream.read_exact(&mut buf)?;
-                            let handshake = Handshake::from_bytes(&buf)?;
-
-                            // check handshake
-                            if handshake.check(&info_hash) {
-                                log::info!("Handshake check passed");
-                                // reregister stream to listen for both readable and writable events
-                                connection.poll.registry().reregister(
-                                    &mut connection.stream,
-                                    connection.token,
-                                    Interest::READABLE | Interest::WRITABLE,
-                                )?;
-
-                                // set peer id
-                                connection.peer_id = handshake.peer_id;
-
-                                return Ok(connection);
-                            } else {
-                                log::error!("Handshake check failed");
-                                return Err(Error::new(
-                                    ErrorKind::InvalidData,
-                                    "Handshake check failed",
-                                ));
-                            }
-                        }
-                    }
-                    _ => {}
+            if self.decoder.is_full() {
+                return Ok(true);
+            }
+            match self.decoder.read_from(&mut self.stream) {
+                Ok(0) => {
+                    return Err(Error::new(
+                        ErrorKind::UnexpectedEof,
+                        "peer closed the connection",
+                    ))
                 }
+                Ok(_) => {}
+                Err(e) if e.kind() == ErrorKind::WouldBlock => return Ok(false),
+                Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
             }
         }
     }
 
-    /// Sends a message to the peer
+    /// Returns the remote handshake once all of it has been received
+    pub fn take_handshake(&mut self) -> Result<Option<Handshake>, Error> {
+        if self.state != State::Handshaking {
+            return Ok(None);
+        }
+        self.decoder.decode_handshake()
+    }
+
+    /// Records the remote handshake and, for incoming peers, answers with ours and `bitfield`
+    pub fn complete_handshake(
+        &mut self,
+        handshake: &Handshake,
+        bitfield: Option<&BitField>,
+    ) -> Result<(), Error> {
+        if !self.outgoing {
+            self.info_hash = handshake.info_hash;
+            let reply = Handshake::new(self.info_hash, self.my_id);
+            self.encoder.encode_handshake(&reply);
+        }
+        if !handshake.check(&self.info_hash) {
+            return Err(Error::new(ErrorKind::InvalidData, "Handshake check failed"));
+        }
+        self.peer_id = handshake.peer_id;
+        self.set_state(State::Connected);
+        // sized for the torrent, peers may send Have messages without a bitfield first
+        if let Some(bitfield) = bitfield {
+            self.bitfield = BitField::with_len(bitfield.len());
+        }
+
+        // peers with no pieces may skip the bitfield message
+        if let Some(bitfield) = bitfield.filter(|b| b.iter().any(|has| has)) {
+            self.encoder
+                .encode(&Message::Bitfield(bitfield.payload.clone()));
+        }
+        self.flush()
+    }
+
+    /// Returns the next complete message in the read buffer, if any
+    pub fn next_message(&mut self) -> Result<Option<Message>, Error> {
+        if self.state != State::Connected {
+            return Ok(None);
+        }
+        let message = self.decoder.decode()?;
+        if let Some(message) = &message {
+            self.apply(message);
+        }
+        Ok(message)
+    }
+
+    /// Updates the peer state for a received message
+    fn apply(&mut self, message: &Message) {
+        match message {
+            Message::Choke => self.peer_choking = true,
+            Message::Unchoke => self.peer_choking = false,
+            Message::Interested => self.peer_interested = true,
+            Message::NotInterested => self.peer_interested = false,
+            Message::Bitfield(payload) if self.bitfield.is_empty() => {
+                self.bitfield = BitField::new(payload.clone())
+            }
+            Message::Bitfield(payload) => {
+                let len = payload.len().min(self.bitfield.payload.len());
+                self.bitfield.payload[..len].copy_from_slice(&payload[..len]);
+            }
+            Message::Have(index) => {
+                let index = *index as usize;
+                if index < self.bitfield.len() {
+                    self.bitfield.set(index);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// Queues a message for the peer and writes as much as the socket accepts
     pub fn send(&mut self, message: Message) -> Result<(), Error> {
-        let bytes = message.serialize();
-        self.stream.write_all(&bytes)?;
+        match message {
+            Message::Choke => self.am_choking = true,
+            Message::Unchoke => self.am_choking = false,
+            Message::Interested => self.am_interested = true,
+            Message::NotInterested => self.am_interested = false,
+            _ => {}
+        }
+        self.encoder.encode(&message);
+        log::debug!("Queued type {:?} message to {:?}", message.id(), self.addr);
 
-        log::info!("Sent type {:?} message to {:?}", message.id(), self.addr);
+        self.flush()
+    }
 
+    /// Writes queued bytes until the buffer is empty or the socket would block
+    pub fn flush(&mut self) -> Result<(), Error> {
+        if self.state == State::Connecting {
+            return Ok(());
+        }
+        self.encoder.write_to(&mut self.stream)?;
         Ok(())
     }
 
-    /// Receives a message from the peer
-    pub fn recv(&mut self) -> Result<Message, Error> {
-        let mut buf = vec![0; 4];
-        self.stream.read_exact(&mut buf)?;
-        // convert the length prefix to u32
-        let len = u32::from_be_bytes(buf.try_into().unwrap());
-        let mut buf = vec![0; len as usize];
-        self.stream.read_exact(&mut buf)?;
-        let message = Message::deserialize(&buf)?;
-
-        Ok(message)
+    /// Returns true if there are bytes waiting to be written
+    pub fn has_pending_writes(&self) -> bool {
+        self.encoder.has_pending()
     }
 
     /// Closes the connection to the peer
@@ -180,9 +314,9 @@ impl Connection {
         Ok(())
     }
 
-    /// Returns true if the connection is still open
-    pub fn is_open(&self) -> bool {
-        self.stream.peer_addr().is_ok()
+    /// Returns true if handshakes have been exchanged
+    pub fn is_connected(&self) -> bool {
+        self.state == State::Connected
     }
 
     /// Returns true if the connection is choked
@@ -200,14 +334,15 @@ impl Connection {
     }
 }
 
-// TODO: maybe implement Drop trait
-
 #[cfg(test)]
 mod tests {
-    use super::*;
+    use crate::bitfield::BitField;
+    use crate::peer::manager::{Limits, Manager, PeerEvent};
+    use crate::peer::message::Message;
     use crate::torrent::Torrent;
     use crate::utils::{find_peers, generate_peer_id};
     use crate::DEBIAN_FILE;
+    use std::time::Duration;
 
     const PORT: u16 = 6969;
 
@@ -219,15 +354,25 @@ mod tests {
         let peer = peers[0];
         let info_hash = torrent.info_hash();
 
-        let mut connection = Connection::new(peer, info_hash, peer_id).unwrap();
-        log::info!("Connection: {:?}", connection);
+        let mut manager = Manager::new(peer_id, Limits::default()).unwrap();
+        manager.add_torrent(info_hash, BitField::with_len(torrent.piece_hashes().len()));
+        let token = manager.connect(peer, info_hash).unwrap();
 
-        let message = Message::Interested;
-        connection.send(message).unwrap();
-
-        let message = connection.recv().unwrap();
-        log::info!("Received message: {:?}", message);
-
-        connection.close().unwrap();
+        loop {
+            for event in manager.poll(Some(Duration::from_secs(3))).unwrap() {
+                match event {
+                    PeerEvent::Connected { .. } => {
+                        log::info!("Connection: {:?}", manager.peer(token));
+                        manager.send(token, Message::Interested).unwrap();
+                    }
+                    PeerEvent::Message { message, .. } => {
+                        log::info!("Received message: {:?}", message);
+                        manager.close(token);
+                        return;
+                    }
+                    PeerEvent::Closed { error, .. } => panic!("{}", error),
+                }
+            }
+        }
     }
 }
@@ -0,0 +1,104 @@
+use mio::net::{TcpListener, TcpStream};
+use mio::{Interest, Registry, Token};
+use std::io::{Error, ErrorKind};
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
+
+/// tokens below this are reserved for the listening sockets
+pub const FIRST_PEER_TOKEN: usize = 2;
+
+/// The sockets listening on the client port (IPv4 and IPv6)
+#[derive(Debug)]
+pub struct Listener {
+    sockets: Vec<(Token, TcpListener)>,
+}
+
+impl Listener {
+    /// Binds `port` on all IPv6 and IPv4 interfaces and registers the sockets with `registry`.
+    /// If the system is dual-stack and the IPv6 socket already covers IPv4, only that socket is
+    /// used. Port 0 picks a free port.
+    pub fn bind(port: u16, registry: &Registry) -> Result<Listener, Error> {
+        let mut sockets = Vec::new();
+        let mut port = port;
+
+        match TcpListener::bind(SocketAddr::new(Ipv6Addr::UNSPECIFIED.into(), port)) {
+            Ok(socket) => {
+                port = socket.local_addr()?.port();
+                sockets.push(socket);
+            }
+            Err(e) => log::warn!("Could not listen on IPv6: {}", e),
+        }
+        match TcpListener::bind(SocketAddr::new(Ipv4Addr::UNSPECIFIED.into(), port)) {
+            Ok(socket) => sockets.push(socket),
+            // the IPv6 socket accepts IPv4 peers too
+            Err(e) if e.kind() == ErrorKind::AddrInUse && !sockets.is_empty() => {}
+            Err(e) => return Err(e),
+        }
+
+        let mut registered = Vec::new();
+        for (i, mut socket) in sockets.into_iter().enumerate() {
+            let token = Token(i);
+            registry.register(&mut socket, token, Interest::READABLE)?;
+            log::info!("Listening for peers on {:?}", socket.local_addr()?);
+            registered.push((token, socket));
+        }
+
+        Ok(Listener {
+            sockets: registered,
+        })
+    }
+
+    /// Returns the addresses the listener is bound to
+    pub fn local_addrs(&self) -> Vec<SocketAddr> {
+        self.sockets
+            .iter()
+            .filter_map(|(_, socket)| socket.local_addr().ok())
+            .collect()
+    }
+
+    /// Returns the port the listener is bound to
+    pub fn port(&self) -> u16 {
+        self.local_addrs().first().map_or(0, |addr| addr.port())
+    }
+
+    /// Returns true if `token` belongs to one of the listening sockets
+    pub fn owns(&self, token: Token) -> bool {
+        self.sockets.iter().any(|(t, _)| *t == token)
+    }
+
+    /// Accepts every pending connection on the socket behind `token`. Connections that died
+    /// while queued are skipped, and running out of file descriptors keeps what was accepted
+    /// so far.
+    pub fn accept(&self, token: Token) -> Result<Vec<(TcpStream, SocketAddr)>, Error> {
+        let mut accepted = Vec::new();
+        let Some((_, socket)) = self.sockets.iter().find(|(t, _)| *t == token) else {
+            return Ok(accepted);
+        };
+
+        loop {
+            match socket.accept() {
+                Ok(peer) => accepted.push(peer),
+                Err(e) if e.kind() == ErrorKind::WouldBlock => return Ok(accepted),
+                Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                // the peer gave up while it was waiting in the backlog
+                Err(e) if e.kind() == ErrorKind::ConnectionAborted => continue,
+                Err(e) if e.kind() == ErrorKind::ConnectionReset => continue,
+                Err(e) if is_out_of_files(&e) => {
+                    log::warn!("Not accepting peers: {}", e);
+                    return Ok(accepted);
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+}
+
+/// Returns true if the process or the system ran out of file descriptors
+#[cfg(unix)]
+fn is_out_of_files(error: &Error) -> bool {
+    matches!(error.raw_os_error(), Some(libc::EMFILE | libc::ENFILE))
+}
+
+#[cfg(not(unix))]
+fn is_out_of_files(_error: &Error) -> bool {
+    false
+}
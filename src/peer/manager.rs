@@ -0,0 +1,652 @@
+use crate::bitfield::BitField;
+use crate::peer::connection::{Connection, State};
+use crate::peer::listener::{Listener, FIRST_PEER_TOKEN};
+use crate::peer::message::Message;
+use mio::{Events, Poll, Token, Waker};
+use std::collections::{HashMap, HashSet};
+use std::io::{Error, ErrorKind};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// token of the waker that lets other threads interrupt `poll`
+const WAKER_TOKEN: Token = Token(usize::MAX - 1);
+
+/// Limits applied to peer connections
+#[derive(Debug, Clone, Copy)]
+pub struct Limits {
+    /// maximum number of peers across all torrents, including ones still connecting
+    pub max_connections: usize,
+    /// maximum number of peers for a single torrent
+    pub max_connections_per_torrent: usize,
+    /// how long a non-blocking connect may take
+    pub connect_timeout: Duration,
+    /// how long a peer has to send its handshake once TCP is up
+    pub handshake_timeout: Duration,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Limits {
+            max_connections: 200,
+            max_connections_per_torrent: 50,
+            connect_timeout: Duration::from_secs(10),
+            handshake_timeout: Duration::from_secs(10),
+        }
+    }
+}
+
+/// Something that happened on one of the peer connections
+#[derive(Debug)]
+pub enum PeerEvent {
+    /// handshakes were exchanged, the peer is ready for messages
+    Connected {
+        token: Token,
+        addr: SocketAddr,
+        info_hash: [u8; 20],
+        peer_id: [u8; 20],
+    },
+    /// a message arrived from a connected peer
+    Message { token: Token, message: Message },
+    /// the connection was closed, `error` says why
+    Closed { token: Token, error: Error },
+}
+
+/// Drives every peer socket from a single `mio::Poll`: outgoing connects, incoming peers on the
+/// listen port, handshakes and message I/O. Each connection gets its own token.
+pub struct Manager {
+    my_id: [u8; 20],
+    poll: Poll,
+    events: Events,
+    waker: Arc<Waker>,
+    listener: Option<Listener>,
+    peers: HashMap<Token, Connection>,
+    /// torrents we accept peers for, with the bitfield we advertise
+    torrents: HashMap<[u8; 20], BitField>,
+    limits: Limits,
+    next_token: usize,
+    /// set while the disk can't keep up, connected peers aren't read from
+    reads_paused: bool,
+    /// peers whose read buffer filled up before their socket was drained, or that became
+    /// readable while reads were paused
+    unread: HashSet<Token>,
+}
+
+impl std::fmt::Debug for Manager {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Manager")
+            .field("listener", &self.listener)
+            .field("peers", &self.peers.len())
+            .field("torrents", &self.torrents.len())
+            .field("limits", &self.limits)
+            .finish()
+    }
+}
+
+impl Manager {
+    pub fn new(my_id: [u8; 20], limits: Limits) -> Result<Manager, Error> {
+        let poll = Poll::new()?;
+        let waker = Arc::new(Waker::new(poll.registry(), WAKER_TOKEN)?);
+        Ok(Manager {
+            my_id,
+            poll,
+            events: Events::with_capacity(1024),
+            waker,
+            listener: None,
+            peers: HashMap::new(),
+            torrents: HashMap::new(),
+            limits,
+            next_token: FIRST_PEER_TOKEN,
+            reads_paused: false,
+            unread: HashSet::new(),
+        })
+    }
+
+    /// Starts accepting peers on `port`, returns the port actually bound
+    pub fn listen(&mut self, port: u16) -> Result<u16, Error> {
+        let listener = Listener::bind(port, self.poll.registry())?;
+        let port = listener.port();
+        self.listener = Some(listener);
+        Ok(port)
+    }
+
+    /// Adds a torrent, `bitfield` is sent to its peers after the handshake
+    pub fn add_torrent(&mut self, info_hash: [u8; 20], bitfield: BitField) {
+        self.torrents.insert(info_hash, bitfield);
+    }
+
+    /// Removes a torrent and disconnects all of its peers
+    pub fn remove_torrent(&mut self, info_hash: &[u8; 20]) {
+        self.torrents.remove(info_hash);
+        let tokens: Vec<Token> = self
+            .peers
+            .values()
+            .filter(|peer| &peer.info_hash == info_hash)
+            .map(|peer| peer.token)
+            .collect();
+        for token in tokens {
+            self.close(token);
+        }
+    }
+
+    /// Marks a piece as available so later peers see it in our bitfield
+    pub fn set_piece(&mut self, info_hash: &[u8; 20], piece_index: usize) {
+        if let Some(bitfield) = self.torrents.get_mut(info_hash) {
+            bitfield.set(piece_index);
+        }
+    }
+
+    /// Marks a verified piece as available and tells every connected peer of the torrent with
+    /// a `Have` message
+    pub fn have_piece(&mut self, info_hash: &[u8; 20], piece_index: usize) {
+        self.set_piece(info_hash, piece_index);
+        let tokens: Vec<Token> = self
+            .peers
+            .values()
+            .filter(|peer| &peer.info_hash == info_hash && peer.is_connected())
+            .map(|peer| peer.token)
+            .collect();
+        for token in tokens {
+            // a peer that fails here is closed by `send`
+            let _ = self.send(token, Message::Have(piece_index as u32));
+        }
+    }
+
+    /// Starts connecting to a peer without blocking, a `Connected` event follows once the
+    /// handshake is done
+    pub fn connect(&mut self, addr: SocketAddr, info_hash: [u8; 20]) -> Result<Token, Error> {
+        if !self.torrents.contains_key(&info_hash) {
+            return Err(Error::new(ErrorKind::NotFound, "unknown info hash"));
+        }
+        self.check_limits(Some(&info_hash))?;
+
+        let token = self.next_token();
+        let mut peer = Connection::connect(addr, token, info_hash, self.my_id)?;
+        peer.register(self.poll.registry())?;
+        self.peers.insert(token, peer);
+        Ok(token)
+    }
+
+    /// Queues a message for a connected peer
+    pub fn send(&mut self, token: Token, message: Message) -> Result<(), Error> {
+        let peer = self
+            .peers
+            .get_mut(&token)
+            .ok_or_else(|| Error::new(ErrorKind::NotFound, "unknown peer"))?;
+        if !peer.is_connected() {
+            return Err(Error::new(ErrorKind::NotConnected, "handshake not done"));
+        }
+        let result = peer.send(message);
+        if result.is_err() {
+            self.close(token);
+        }
+        result
+    }
+
+    /// Closes a peer connection, no `Closed` event is emitted for it
+    pub fn close(&mut self, token: Token) {
+        self.unread.remove(&token);
+        if let Some(mut peer) = self.peers.remove(&token) {
+            peer.deregister(self.poll.registry());
+            let _ = peer.close();
+        }
+    }
+
+    /// Stops reading from connected peers so TCP flow control slows them down, e.g. while the
+    /// disk queue is full. Handshakes still go through.
+    pub fn pause_reads(&mut self) {
+        self.reads_paused = true;
+    }
+
+    /// Reads again from peers, including whatever arrived while paused
+    pub fn resume_reads(&mut self) {
+        self.reads_paused = false;
+    }
+
+    pub fn reads_paused(&self) -> bool {
+        self.reads_paused
+    }
+
+    /// Returns a connection by token
+    pub fn peer(&self, token: Token) -> Option<&Connection> {
+        self.peers.get(&token)
+    }
+
+    /// Returns all connections, including ones still connecting or handshaking
+    pub fn peers(&self) -> impl Iterator<Item = &Connection> {
+        self.peers.values()
+    }
+
+    /// Returns a waker that makes a pending `poll` return early, e.g. when a worker thread has
+    /// results for the event loop
+    pub fn waker(&self) -> Arc<Waker> {
+        self.waker.clone()
+    }
+
+    /// Waits up to `timeout` for socket events and returns what happened
+    pub fn poll(&mut self, timeout: Option<Duration>) -> Result<Vec<PeerEvent>, Error> {
+        // sockets are edge-triggered, peers left unread won't be reported again
+        let timeout = if self.reads_paused || self.unread.is_empty() {
+            timeout
+        } else {
+            Some(Duration::ZERO)
+        };
+        self.poll.poll(&mut self.events, timeout)?;
+        let mut ready: Vec<(Token, bool, bool)> = self
+            .events
+            .iter()
+            .map(|event| {
+                (
+                    event.token(),
+                    event.is_readable() || event.is_read_closed() || event.is_error(),
+                    event.is_writable(),
+                )
+            })
+            .collect();
+        if !self.reads_paused {
+            ready.extend(self.unread.drain().map(|token| (token, true, false)));
+        }
+
+        let mut events = Vec::new();
+        for (token, readable, writable) in ready {
+            if token == WAKER_TOKEN {
+                continue;
+            } else if self.listener.as_ref().is_some_and(|l| l.owns(token)) {
+                self.accept(token);
+            } else if let Err(error) = self.drive(token, readable, writable, &mut events) {
+                log::debug!("Closing {:?}: {}", token, error);
+                self.close(token);
+                events.push(PeerEvent::Closed { token, error });
+            }
+        }
+        self.expire(&mut events);
+
+        Ok(events)
+    }
+
+    /// Accepts pending peers on a listening socket, they are kept until their handshake tells
+    /// us which torrent they want. Failures only cost the peers involved.
+    fn accept(&mut self, token: Token) {
+        let Some(listener) = &self.listener else {
+            return;
+        };
+        let accepted = match listener.accept(token) {
+            Ok(accepted) => accepted,
+            Err(e) => {
+                log::warn!("Could not accept peers: {}", e);
+                return;
+            }
+        };
+        for (stream, addr) in accepted {
+            if let Err(e) = self.check_limits(None) {
+                log::debug!("Rejecting {:?}: {}", addr, e);
+                continue;
+            }
+            let token = self.next_token();
+            let mut peer = Connection::accepted(stream, addr, token, self.my_id);
+            if let Err(e) = peer.register(self.poll.registry()) {
+                log::warn!("Could not register {:?}: {}", addr, e);
+                continue;
+            }
+            log::debug!("Accepted connection from {:?}", addr);
+            self.peers.insert(token, peer);
+        }
+    }
+
+    /// Advances a connection's state machine after a readiness event
+    fn drive(
+        &mut self,
+        token: Token,
+        readable: bool,
+        writable: bool,
+        events: &mut Vec<PeerEvent>,
+    ) -> Result<(), Error> {
+        let Some(peer) = self.peers.get_mut(&token) else {
+            return Ok(());
+        };
+        if writable {
+            peer.on_writable()?;
+        }
+        // a full read buffer leaves the rest of the socket for the next poll, once the messages
+        // in it are decoded
+        let mut unread = false;
+        if readable {
+            if self.reads_paused && peer.state == State::Connected {
+                self.unread.insert(token);
+            } else {
+                unread = peer.on_readable()?;
+            }
+        }
+
+        if let Some(handshake) = peer.take_handshake()? {
+            if !peer.outgoing {
+                // route the incoming peer to its torrent
+                if !self.torrents.contains_key(&handshake.info_hash) {
+                    return Err(Error::new(ErrorKind::NotFound, "unknown info hash"));
+                }
+                self.check_torrent_limit(&handshake.info_hash)?;
+            }
+            let peer = self.peers.get_mut(&token).unwrap();
+            let bitfield = self.torrents.get(&handshake.info_hash);
+            peer.complete_handshake(&handshake, bitfield)?;
+            log::info!("Handshake with {:?} complete", peer.addr);
+            events.push(PeerEvent::Connected {
+                token,
+                addr: peer.addr,
+                info_hash: peer.info_hash,
+                peer_id: peer.peer_id,
+            });
+        }
+
+        let peer = self.peers.get_mut(&token).unwrap();
+        while let Some(message) = peer.next_message()? {
+            events.push(PeerEvent::Message { token, message });
+        }
+        if unread {
+            self.unread.insert(token);
+        }
+        Ok(())
+    }
+
+    /// Closes connections that are stuck connecting or handshaking
+    fn expire(&mut self, events: &mut Vec<PeerEvent>) {
+        let expired: Vec<Token> = self
+            .peers
+            .values()
+            .filter(|peer| match peer.state {
+                State::Connecting => peer.since.elapsed() >= self.limits.connect_timeout,
+                State::Handshaking => peer.since.elapsed() >= self.limits.handshake_timeout,
+                State::Connected => false,
+            })
+            .map(|peer| peer.token)
+            .collect();
+        for token in expired {
+            self.close(token);
+            events.push(PeerEvent::Closed {
+                token,
+                error: Error::new(ErrorKind::TimedOut, "peer timed out"),
+            });
+        }
+    }
+
+    /// Fails if another connection would exceed the global limit or, given an info hash, the
+    /// per-torrent limit
+    fn check_limits(&self, info_hash: Option<&[u8; 20]>) -> Result<(), Error> {
+        if self.peers.len() >= self.limits.max_connections {
+            return Err(Error::new(
+                ErrorKind::ConnectionRefused,
+                "connection limit reached",
+            ));
+        }
+        match info_hash {
+            Some(info_hash) => self.check_torrent_limit(info_hash),
+            None => Ok(()),
+        }
+    }
+
+    fn check_torrent_limit(&self, info_hash: &[u8; 20]) -> Result<(), Error> {
+        // incoming peers only count once their handshake has routed them
+        let count = self
+            .peers
+            .values()
+            .filter(|peer| &peer.info_hash == info_hash && (peer.outgoing || peer.is_connected()))
+            .count();
+        if count >= self.limits.max_connections_per_torrent {
+            return Err(Error::new(
+                ErrorKind::ConnectionRefused,
+                "torrent connection limit reached",
+            ));
+        }
+        Ok(())
+    }
+
+    fn next_token(&mut self) -> Token {
+        let token = Token(self.next_token);
+        self.next_token += 1;
+        token
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::peer::codec::HANDSHAKE_LEN;
+    use crate::peer::message::Handshake;
+    use std::io::{Read, Write};
+    use std::net::{Ipv4Addr, TcpStream};
+
+    fn connect(port: u16) -> TcpStream {
+        let addr = SocketAddr::new(Ipv4Addr::LOCALHOST.into(), port);
+        let stream = TcpStream::connect(addr).unwrap();
+        stream
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        stream
+    }
+
+    fn poll_for(manager: &mut Manager, rounds: usize) -> Vec<PeerEvent> {
+        let mut events = Vec::new();
+        for _ in 0..rounds {
+            events.extend(manager.poll(Some(Duration::from_millis(50))).unwrap());
+            if !events.is_empty() {
+                break;
+            }
+        }
+        events
+    }
+
+    #[test]
+    fn test_manager_accepts_handshake() {
+        let my_id = [1; 20];
+        let info_hash = [2; 20];
+        let mut manager = Manager::new(my_id, Limits::default()).unwrap();
+        let port = manager.listen(0).unwrap();
+        let mut bitfield = BitField::with_len(8);
+        bitfield.set(0);
+        manager.add_torrent(info_hash, bitfield);
+
+        let mut peer = connect(port);
+        peer.write_all(&Handshake::new(info_hash, [3; 20]).to_bytes())
+            .unwrap();
+
+        let events = poll_for(&mut manager, 100);
+        match &events[..] {
+            [PeerEvent::Connected {
+                info_hash: hash,
+                peer_id,
+                ..
+            }] => {
+                assert_eq!(*hash, info_hash);
+                assert_eq!(*peer_id, [3; 20]);
+            }
+            other => panic!("unexpected events {:?}", other),
+        }
+
+        // our handshake followed by a bitfield with the first piece set
+        let mut reply = vec![0; HANDSHAKE_LEN + 6];
+        peer.read_exact(&mut reply).unwrap();
+        let handshake = Handshake::from_bytes(&reply[..HANDSHAKE_LEN]).unwrap();
+        assert_eq!(handshake.peer_id, my_id);
+        assert_eq!(handshake.info_hash, info_hash);
+        assert_eq!(reply[HANDSHAKE_LEN..], [0, 0, 0, 2, 5, 0b1000_0000]);
+    }
+
+    #[test]
+    fn test_manager_broadcasts_have() {
+        let info_hash = [2; 20];
+        let mut manager = Manager::new([1; 20], Limits::default()).unwrap();
+        let port = manager.listen(0).unwrap();
+        manager.add_torrent(info_hash, BitField::with_len(8));
+
+        let mut peer = connect(port);
+        peer.write_all(&Handshake::new(info_hash, [3; 20]).to_bytes())
+            .unwrap();
+        let events = poll_for(&mut manager, 100);
+        assert!(matches!(events[..], [PeerEvent::Connected { .. }]));
+        // nothing to advertise yet, so no bitfield follows the handshake
+        let mut reply = vec![0; HANDSHAKE_LEN];
+        peer.read_exact(&mut reply).unwrap();
+
+        manager.have_piece(&info_hash, 3);
+        let mut have = [0; 9];
+        peer.read_exact(&mut have).unwrap();
+        assert_eq!(have, [0, 0, 0, 5, 4, 0, 0, 0, 3]);
+        assert!(manager.torrents[&info_hash].is_set(3));
+    }
+
+    #[test]
+    fn test_manager_have_without_bitfield() {
+        let info_hash = [2; 20];
+        let mut manager = Manager::new([1; 20], Limits::default()).unwrap();
+        let port = manager.listen(0).unwrap();
+        manager.add_torrent(info_hash, BitField::with_len(10));
+
+        let mut peer = connect(port);
+        peer.write_all(&Handshake::new(info_hash, [3; 20]).to_bytes())
+            .unwrap();
+        let token = match poll_for(&mut manager, 100)[..] {
+            [PeerEvent::Connected { token, .. }] => token,
+            ref other => panic!("unexpected events {:?}", other),
+        };
+
+        // the second piece index is past the end of the torrent
+        peer.write_all(&[0, 0, 0, 5, 4, 0, 0, 0, 9, 0, 0, 0, 5, 4, 0, 0, 0, 10])
+            .unwrap();
+        let mut events = Vec::new();
+        while events.len() < 2 {
+            let polled = poll_for(&mut manager, 100);
+            assert!(!polled.is_empty());
+            events.extend(polled);
+        }
+        let connection = manager.peer(token).unwrap();
+        assert!(connection.has_piece(9));
+        assert!(!connection.has_piece(10));
+        assert_eq!(connection.bitfield.len(), 10);
+    }
+
+    #[test]
+    fn test_manager_rejects_unknown_info_hash() {
+        let mut manager = Manager::new([1; 20], Limits::default()).unwrap();
+        let port = manager.listen(0).unwrap();
+        manager.add_torrent([2; 20], BitField::with_len(8));
+
+        let mut peer = connect(port);
+        peer.write_all(&Handshake::new([9; 20], [3; 20]).to_bytes())
+            .unwrap();
+
+        let events = poll_for(&mut manager, 100);
+        assert!(matches!(events[..], [PeerEvent::Closed { .. }]));
+        let mut buf = [0; 1];
+        assert_eq!(peer.read(&mut buf).unwrap(), 0);
+    }
+
+    #[test]
+    fn test_manager_per_torrent_limit() {
+        let info_hash = [2; 20];
+        let limits = Limits {
+            max_connections_per_torrent: 1,
+            ..Limits::default()
+        };
+        let mut manager = Manager::new([1; 20], limits).unwrap();
+        let port = manager.listen(0).unwrap();
+        manager.add_torrent(info_hash, BitField::with_len(8));
+
+        let mut first = connect(port);
+        first
+            .write_all(&Handshake::new(info_hash, [3; 20]).to_bytes())
+            .unwrap();
+        let events = poll_for(&mut manager, 100);
+        assert!(matches!(events[..], [PeerEvent::Connected { .. }]));
+
+        let mut second = connect(port);
+        second
+            .write_all(&Handshake::new(info_hash, [4; 20]).to_bytes())
+            .unwrap();
+        let events = poll_for(&mut manager, 100);
+        assert!(matches!(events[..], [PeerEvent::Closed { .. }]));
+        let mut buf = [0; 1];
+        assert_eq!(second.read(&mut buf).unwrap(), 0);
+    }
+
+    #[test]
+    fn test_manager_connects_to_manager() {
+        let info_hash = [2; 20];
+        let mut seeder = Manager::new([1; 20], Limits::default()).unwrap();
+        let port = seeder.listen(0).unwrap();
+        seeder.add_torrent(info_hash, BitField::with_len(8));
+        let mut leecher = Manager::new([3; 20], Limits::default()).unwrap();
+        leecher.add_torrent(info_hash, BitField::with_len(8));
+
+        let addr = SocketAddr::new(Ipv4Addr::LOCALHOST.into(), port);
+        let token = leecher.connect(addr, info_hash).unwrap();
+
+        // drive both ends from this thread until the seeder sees our message
+        let mut seeder_events = Vec::new();
+        let mut sent = false;
+        for _ in 0..200 {
+            for event in leecher.poll(Some(Duration::from_millis(10))).unwrap() {
+                if let PeerEvent::Connected { peer_id, .. } = event {
+                    assert_eq!(peer_id, [1; 20]);
+                    leecher.send(token, Message::Interested).unwrap();
+                    sent = true;
+                }
+            }
+            seeder_events.extend(seeder.poll(Some(Duration::from_millis(10))).unwrap());
+            if sent && seeder_events.len() >= 2 {
+                break;
+            }
+        }
+
+        assert!(matches!(
+            seeder_events[0],
+            PeerEvent::Connected { peer_id, .. } if peer_id == [3; 20]
+        ));
+        assert!(matches!(
+            seeder_events[1],
+            PeerEvent::Message {
+                message: Message::Interested,
+                ..
+            }
+        ));
+        assert!(leecher.peer(token).unwrap().am_interested);
+    }
+
+    #[test]
+    fn test_manager_waker() {
+        let mut manager = Manager::new([1; 20], Limits::default()).unwrap();
+        let waker = manager.waker();
+        let thread = std::thread::spawn(move || waker.wake().unwrap());
+        // would block forever without the wake-up
+        assert!(manager.poll(None).unwrap().is_empty());
+        thread.join().unwrap();
+    }
+
+    #[test]
+    fn test_manager_pause_reads() {
+        let info_hash = [2; 20];
+        let mut manager = Manager::new([1; 20], Limits::default()).unwrap();
+        let port = manager.listen(0).unwrap();
+        manager.add_torrent(info_hash, BitField::with_len(8));
+
+        let mut peer = connect(port);
+        peer.write_all(&Handshake::new(info_hash, [3; 20]).to_bytes())
+            .unwrap();
+        let events = poll_for(&mut manager, 100);
+        assert!(matches!(events[..], [PeerEvent::Connected { .. }]));
+
+        manager.pause_reads();
+        peer.write_all(&Message::Interested.serialize()).unwrap();
+        assert!(poll_for(&mut manager, 5).is_empty());
+
+        // the edge was consumed while paused, resuming still picks the message up
+        manager.resume_reads();
+        let events = poll_for(&mut manager, 100);
+        assert!(matches!(
+            events[..],
+            [PeerEvent::Message {
+                message: Message::Interested,
+                ..
+            }]
+        ));
+    }
+}
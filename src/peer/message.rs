@@ -1,3 +1,4 @@
+use bytes::{BufMut, Bytes};
 use std::io::{Error, ErrorKind};
 
 #[derive(Debug, PartialEq, Clone)]
@@ -34,15 +35,23 @@ impl Handshake {
                 "Handshake message should start with 19",
             ));
         }
+        if &bytes[1..20] != b"BitTorrent protocol" {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                "Handshake protocol should be BitTorrent protocol",
+            ));
+        }
 
+        let mut reserved = [0; 8];
+        reserved.copy_from_slice(&bytes[20..28]);
         let mut info_hash = [0; 20];
         info_hash.copy_from_slice(&bytes[28..48]);
         let mut peer_id = [0; 20];
         peer_id.copy_from_slice(&bytes[48..68]);
 
         Ok(Handshake {
-            pstr: String::from_utf8(bytes[1..20].to_vec()).unwrap(),
-            reserved: [0; 8],
+            pstr: "BitTorrent protocol".to_string(),
+            reserved,
             info_hash,
             peer_id,
         })
@@ -52,6 +61,7 @@ impl Handshake {
         let mut bytes = vec![0; 68];
         bytes[0] = 19;
         bytes[1..20].copy_from_slice(self.pstr.as_bytes());
+        bytes[20..28].copy_from_slice(&self.reserved);
         bytes[28..48].copy_from_slice(&self.info_hash);
         bytes[48..68].copy_from_slice(&self.peer_id);
         bytes
@@ -72,163 +82,160 @@ pub enum Message {
     Have(u32),
     Bitfield(Vec<u8>),
     Request(u32, u32, u32),
-    Piece(u32, u32, Vec<u8>),
+    /// the block shares the receive buffer it was decoded from
+    Piece(u32, u32, Bytes),
     Cancel(u32, u32, u32),
     Port(u16),
 }
 
 impl Message {
     pub fn serialize(&self) -> Vec<u8> {
+        let mut msg = Vec::with_capacity(4 + self.len());
+        self.encode_into(&mut msg);
+        msg
+    }
+
+    /// Appends the frame (big-endian u32 length prefix, id, payload) to `buf`
+    pub fn encode_into<B: BufMut>(&self, buf: &mut B) {
+        self.encode_header_into(buf);
+        if let Message::Piece(_, _, block) = self {
+            buf.put_slice(block);
+        }
+    }
+
+    /// Appends everything but the block of a `Piece`, so the block can be written from its own
+    /// buffer without copying
+    pub fn encode_header_into<B: BufMut>(&self, buf: &mut B) {
+        buf.put_u32(self.len() as u32);
+        if let Some(id) = self.id() {
+            buf.put_u8(id);
+        }
         match self {
-            Message::KeepAlive => vec![0, 0, 0, 0],
-            Message::Choke => vec![0, 0, 0, 1, 0],
-            Message::Unchoke => vec![0, 0, 0, 1, 1],
-            Message::Interested => vec![0, 0, 0, 1, 2],
-            Message::NotInterested => vec![0, 0, 0, 1, 3],
-            Message::Have(index) => {
-                let mut msg = vec![0, 0, 0, 5, 4];
-                msg.extend_from_slice(&index.to_be_bytes());
-                msg
-            }
-            Message::Bitfield(bitfield) => {
-                let mut msg = vec![0, 0, 0, 1 + bitfield.len() as u8, 5];
-                msg.extend_from_slice(bitfield);
-                msg
-            }
-            Message::Request(index, begin, length) => {
-                let mut msg = vec![0, 0, 0, 13, 6];
-                msg.extend_from_slice(&index.to_be_bytes());
-                msg.extend_from_slice(&begin.to_be_bytes());
-                msg.extend_from_slice(&length.to_be_bytes());
-                msg
-            }
-            Message::Piece(index, begin, block) => {
-                let mut msg = vec![0, 0, 0, 9 + block.len() as u8, 7];
-                msg.extend_from_slice(&index.to_be_bytes());
-                msg.extend_from_slice(&begin.to_be_bytes());
-                msg.extend_from_slice(block);
-                msg
+            Message::KeepAlive
+            | Message::Choke
+            | Message::Unchoke
+            | Message::Interested
+            | Message::NotInterested => {}
+            Message::Have(index) => buf.put_u32(*index),
+            Message::Bitfield(bitfield) => buf.put_slice(bitfield),
+            Message::Request(index, begin, length) | Message::Cancel(index, begin, length) => {
+                buf.put_u32(*index);
+                buf.put_u32(*begin);
+                buf.put_u32(*length);
             }
-            Message::Cancel(index, begin, length) => {
-                let mut msg = vec![0, 0, 0, 13, 8];
-                msg.extend_from_slice(&index.to_be_bytes());
-                msg.extend_from_slice(&begin.to_be_bytes());
-                msg.extend_from_slice(&length.to_be_bytes());
-                msg
-            }
-            Message::Port(port) => {
-                let mut msg = vec![0, 0, 0, 3, 9];
-                msg.extend_from_slice(&port.to_be_bytes());
-                msg
+            Message::Piece(index, begin, _) => {
+                buf.put_u32(*index);
+                buf.put_u32(*begin);
             }
+            Message::Port(port) => buf.put_u16(*port),
         }
     }
 
+    /// Parses a whole frame: 4-byte big-endian length prefix, 1-byte id, then the payload
     pub fn deserialize(data: &[u8]) -> Result<Message, Error> {
-        // first 4 bytes are the length prefix and if they are 0, it's a keep-alive message
-        if data.len() == 4 && data == [0, 0, 0, 0] {
-            return Ok(Message::KeepAlive);
-        }
+        Message::from_frame(Bytes::copy_from_slice(data))
+    }
 
-        // only keep-alive message will be 4 bytes long
-        if data.len() < 5 {
+    /// Like `deserialize`, but a `Piece` block is sliced out of `frame` instead of copied
+    pub fn from_frame(frame: Bytes) -> Result<Message, Error> {
+        let data = &frame[..];
+        if data.len() < 4 {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                "Message too short to hold a length prefix",
+            ));
+        }
+        let len = u32::from_be_bytes([data[0], data[1], data[2], data[3]]) as usize;
+        if data.len() - 4 != len {
             return Err(Error::new(
                 ErrorKind::InvalidData,
-                "Message too short to be valid",
+                format!(
+                    "Length prefix {} does not match {} bytes",
+                    len,
+                    data.len() - 4
+                ),
             ));
         }
 
+        // a length of 0 is a keep-alive message
+        if len == 0 {
+            return Ok(Message::KeepAlive);
+        }
+
         let id = data[4];
+        let payload = &data[5..];
         let msg = match id {
-            0 => Message::Choke,
-            1 => Message::Unchoke,
-            2 => Message::Interested,
-            3 => Message::NotInterested,
-            4 => {
-                if data.len() != 5 {
+            0..=3 => {
+                if !payload.is_empty() {
                     return Err(Error::new(
                         ErrorKind::InvalidData,
-                        "Have message should be 5 bytes long",
+                        format!("Message with id {} should have no payload", id),
                     ));
                 }
-                let mut index = [0; 4];
-                index.copy_from_slice(&data[1..5]);
-                Message::Have(u32::from_be_bytes(index))
+                match id {
+                    0 => Message::Choke,
+                    1 => Message::Unchoke,
+                    2 => Message::Interested,
+                    _ => Message::NotInterested,
+                }
             }
-            5 => {
-                if data.len() < 6 {
+            4 => {
+                if payload.len() != 4 {
                     return Err(Error::new(
                         ErrorKind::InvalidData,
-                        "Bitfield message should be at least 6 bytes long",
+                        "Have message should have a 4 byte payload",
                     ));
                 }
-                Message::Bitfield(data[1..].to_vec())
+                Message::Have(read_u32(payload, 0))
             }
+            5 => Message::Bitfield(payload.to_vec()),
             6 => {
-                if data.len() != 13 {
+                if payload.len() != 12 {
                     return Err(Error::new(
                         ErrorKind::InvalidData,
-                        "Request message should be 13 bytes long",
+                        "Request message should have a 12 byte payload",
                     ));
                 }
-                let mut index = [0; 4];
-                index.copy_from_slice(&data[1..5]);
-                let mut begin = [0; 4];
-                begin.copy_from_slice(&data[5..9]);
-                let mut length = [0; 4];
-                length.copy_from_slice(&data[9..13]);
                 Message::Request(
-                    u32::from_be_bytes(index),
-                    u32::from_be_bytes(begin),
-                    u32::from_be_bytes(length),
+                    read_u32(payload, 0),
+                    read_u32(payload, 4),
+                    read_u32(payload, 8),
                 )
             }
             7 => {
-                if data.len() < 9 {
+                if payload.len() < 8 {
                     return Err(Error::new(
                         ErrorKind::InvalidData,
-                        "Piece message should be at least 9 bytes long",
+                        "Piece message should have at least an 8 byte payload",
                     ));
                 }
-                let mut index = [0; 4];
-                index.copy_from_slice(&data[1..5]);
-                let mut begin = [0; 4];
-                begin.copy_from_slice(&data[5..9]);
                 Message::Piece(
-                    u32::from_be_bytes(index),
-                    u32::from_be_bytes(begin),
-                    data[9..].to_vec(),
+                    read_u32(payload, 0),
+                    read_u32(payload, 4),
+                    frame.slice(13..),
                 )
             }
             8 => {
-                if data.len() != 13 {
+                if payload.len() != 12 {
                     return Err(Error::new(
                         ErrorKind::InvalidData,
-                        "Cancel message should be 13 bytes long",
+                        "Cancel message should have a 12 byte payload",
                     ));
                 }
-                let mut index = [0; 4];
-                index.copy_from_slice(&data[1..5]);
-                let mut begin = [0; 4];
-                begin.copy_from_slice(&data[5..9]);
-                let mut length = [0; 4];
-                length.copy_from_slice(&data[9..13]);
                 Message::Cancel(
-                    u32::from_be_bytes(index),
-                    u32::from_be_bytes(begin),
-                    u32::from_be_bytes(length),
+                    read_u32(payload, 0),
+                    read_u32(payload, 4),
+                    read_u32(payload, 8),
                 )
             }
             9 => {
-                if data.len() != 3 {
+                if payload.len() != 2 {
                     return Err(Error::new(
                         ErrorKind::InvalidData,
-                        "Port message should be 3 bytes long",
+                        "Port message should have a 2 byte payload",
                     ));
                 }
-                let mut port = [0; 2];
-                port.copy_from_slice(&data[1..3]);
-                Message::Port(u16::from_be_bytes(port))
+                Message::Port(u16::from_be_bytes([payload[0], payload[1]]))
             }
             _ => {
                 return Err(Error::new(
@@ -240,22 +247,25 @@ impl Message {
         Ok(msg)
     }
 
-    pub fn id(&self) -> u8 {
+    /// Returns the message id, keep-alives have none
+    pub fn id(&self) -> Option<u8> {
         match self {
-            Message::KeepAlive => 0,
-            Message::Choke => 0,
-            Message::Unchoke => 1,
-            Message::Interested => 2,
-            Message::NotInterested => 3,
-            Message::Have(_) => 4,
-            Message::Bitfield(_) => 5,
-            Message::Request(_, _, _) => 6,
-            Message::Piece(_, _, _) => 7,
-            Message::Cancel(_, _, _) => 8,
-            Message::Port(_) => 9,
+            Message::KeepAlive => None,
+            Message::Choke => Some(0),
+            Message::Unchoke => Some(1),
+            Message::Interested => Some(2),
+            Message::NotInterested => Some(3),
+            Message::Have(_) => Some(4),
+            Message::Bitfield(_) => Some(5),
+            Message::Request(_, _, _) => Some(6),
+            Message::Piece(_, _, _) => Some(7),
+            Message::Cancel(_, _, _) => Some(8),
+            Message::Port(_) => Some(9),
         }
     }
 
+    /// Returns the value of the length prefix: the id plus the payload, without the prefix itself
+    #[allow(clippy::len_without_is_empty)]
     pub fn len(&self) -> usize {
         match self {
             Message::KeepAlive => 0,
@@ -273,6 +283,16 @@ impl Message {
     }
 }
 
+/// reads a big-endian u32 at `offset`, the caller checks the length
+fn read_u32(bytes: &[u8], offset: usize) -> u32 {
+    u32::from_be_bytes([
+        bytes[offset],
+        bytes[offset + 1],
+        bytes[offset + 2],
+        bytes[offset + 3],
+    ])
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -288,6 +308,19 @@ mod tests {
         assert_eq!(handshake, handshake2);
     }
 
+    #[test]
+    fn test_handshake_rejects_bad_input() {
+        let mut bytes = Handshake::new([1; 20], [2; 20]).to_bytes();
+        bytes[20] = 0x10;
+        let handshake = Handshake::from_bytes(&bytes).unwrap();
+        assert_eq!(handshake.reserved[0], 0x10);
+        assert!(!handshake.check(&[1; 20]));
+
+        bytes[1..20].copy_from_slice(&[0xff; 19]);
+        let err = Handshake::from_bytes(&bytes).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+    }
+
     #[test]
     fn test_message_keep_alive() {
         let msg = Message::KeepAlive;
@@ -305,4 +338,81 @@ mod tests {
         let msg = Message::deserialize(&bytes).unwrap();
         assert_eq!(msg, Message::Choke);
     }
+
+    fn round_trip(msg: Message) {
+        let bytes = msg.serialize();
+        assert_eq!(bytes.len(), 4 + msg.len());
+        assert_eq!(
+            u32::from_be_bytes(bytes[..4].try_into().unwrap()) as usize,
+            msg.len()
+        );
+        assert_eq!(bytes.get(4).copied(), msg.id());
+        assert_eq!(Message::deserialize(&bytes).unwrap(), msg);
+    }
+
+    #[test]
+    fn test_message_round_trip_all_variants() {
+        round_trip(Message::KeepAlive);
+        round_trip(Message::Choke);
+        round_trip(Message::Unchoke);
+        round_trip(Message::Interested);
+        round_trip(Message::NotInterested);
+        round_trip(Message::Have(u32::MAX));
+        round_trip(Message::Bitfield(vec![0b1010_0000]));
+        round_trip(Message::Request(1, 16384, 16384));
+        round_trip(Message::Piece(2, 0, Bytes::from(vec![7; 3])));
+        round_trip(Message::Cancel(1, 16384, 16384));
+        round_trip(Message::Port(6881));
+    }
+
+    #[test]
+    fn test_message_round_trip_large() {
+        // a full 16 KiB block, the largest any client requests
+        let block: Vec<u8> = (0..16384).map(|i| i as u8).collect();
+        let piece = Message::Piece(5, 16384, block.into());
+        assert_eq!(piece.serialize()[..5], [0, 0, 0x40, 0x09, 7]);
+        round_trip(piece);
+
+        // bitfields of more than 254 bytes used to wrap the length prefix
+        let bitfield = Message::Bitfield(vec![0xff; 300]);
+        assert_eq!(bitfield.serialize()[..5], [0, 0, 0x01, 0x2d, 5]);
+        round_trip(bitfield);
+
+        round_trip(Message::Piece(0, 0, Bytes::from(vec![1; 128 * 1024])));
+    }
+
+    #[test]
+    fn test_message_deserialize_payload_offsets() {
+        let have = [0, 0, 0, 5, 4, 0, 0, 0, 7];
+        assert_eq!(Message::deserialize(&have).unwrap(), Message::Have(7));
+
+        let cancel = [0, 0, 0, 13, 8, 0, 0, 0, 1, 0, 0, 0, 2, 0, 0, 0, 3];
+        assert_eq!(
+            Message::deserialize(&cancel).unwrap(),
+            Message::Cancel(1, 2, 3)
+        );
+
+        let port = [0, 0, 0, 3, 9, 0x1a, 0xe1];
+        assert_eq!(Message::deserialize(&port).unwrap(), Message::Port(6881));
+    }
+
+    #[test]
+    fn test_message_piece_shares_frame() {
+        let frame = Bytes::from(vec![0, 0, 0, 12, 7, 0, 0, 0, 1, 0, 0, 0, 0, 4, 5, 6]);
+        let Message::Piece(_, _, block) = Message::from_frame(frame.clone()).unwrap() else {
+            panic!("expected a piece");
+        };
+        assert_eq!(block, [4, 5, 6][..]);
+        assert_eq!(block.as_ptr(), frame[13..].as_ptr());
+    }
+
+    #[test]
+    fn test_message_deserialize_rejects_bad_lengths() {
+        // prefix says 5 but only 4 bytes follow
+        assert!(Message::deserialize(&[0, 0, 0, 5, 4, 0, 0, 0]).is_err());
+        // choke with a payload
+        assert!(Message::deserialize(&[0, 0, 0, 2, 0, 1]).is_err());
+        // unknown id
+        assert!(Message::deserialize(&[0, 0, 0, 1, 42]).is_err());
+    }
 }
@@ -0,0 +1,341 @@
+use crate::bitfield::BitField;
+use crate::layout::Layout;
+use crate::storage::BLOCK_SIZE;
+use anyhow::{bail, Result};
+
+/// How much we want a file, pieces inherit the highest priority of the files they touch
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Default)]
+pub enum Priority {
+    /// not downloaded, the file is never created on disk
+    Skip,
+    Low,
+    #[default]
+    Normal,
+    High,
+}
+
+impl std::str::FromStr for Priority {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "skip" => Ok(Priority::Skip),
+            "low" => Ok(Priority::Low),
+            "normal" => Ok(Priority::Normal),
+            "high" => Ok(Priority::High),
+            _ => bail!(
+                "Unknown priority {:?}, expected skip, low, normal or high",
+                s
+            ),
+        }
+    }
+}
+
+/// Computes the priority of every piece from the priorities of the files, a piece is only
+/// skipped if every file it touches is skipped
+pub fn piece_priorities(layout: &Layout, file_priorities: &[Priority]) -> Vec<Priority> {
+    (0..layout.num_pieces())
+        .map(|piece| {
+            layout
+                .piece_files(piece)
+                .iter()
+                .map(|slice| file_priorities[slice.file_index])
+                .max()
+                .unwrap_or(Priority::Skip)
+        })
+        .collect()
+}
+
+/// Chooses which piece to request next: highest priority first, then the rarest among the
+/// peers we know about
+#[derive(Debug)]
+pub struct PiecePicker {
+    have: BitField,
+    /// number of connected peers that have each piece
+    availability: Vec<u32>,
+    priorities: Vec<Priority>,
+    /// pieces currently being downloaded from some peer
+    requested: Vec<bool>,
+}
+
+impl PiecePicker {
+    pub fn new(num_pieces: usize) -> PiecePicker {
+        PiecePicker {
+            have: BitField::with_len(num_pieces),
+            availability: vec![0; num_pieces],
+            priorities: vec![Priority::Normal; num_pieces],
+            requested: vec![false; num_pieces],
+        }
+    }
+
+    pub fn num_pieces(&self) -> usize {
+        self.priorities.len()
+    }
+
+    /// Replaces the piece priorities, can be called at any time while downloading
+    pub fn set_priorities(&mut self, priorities: Vec<Priority>) {
+        assert_eq!(priorities.len(), self.num_pieces());
+        self.priorities = priorities;
+    }
+
+    pub fn priority(&self, piece_index: usize) -> Priority {
+        self.priorities[piece_index]
+    }
+
+    /// Records the pieces a newly connected peer has
+    pub fn add_peer(&mut self, bitfield: &BitField) {
+        for piece in self.pieces_of(bitfield) {
+            self.availability[piece] += 1;
+        }
+    }
+
+    /// Forgets the pieces of a peer that disconnected
+    pub fn remove_peer(&mut self, bitfield: &BitField) {
+        for piece in self.pieces_of(bitfield) {
+            self.availability[piece] = self.availability[piece].saturating_sub(1);
+        }
+    }
+
+    /// Records a `Have` message from a peer
+    pub fn peer_has(&mut self, piece_index: usize) {
+        if let Some(count) = self.availability.get_mut(piece_index) {
+            *count += 1;
+        }
+    }
+
+    /// Marks a piece as downloaded and verified
+    pub fn set_have(&mut self, piece_index: usize) {
+        self.have.set(piece_index);
+        self.requested[piece_index] = false;
+    }
+
+    pub fn has(&self, piece_index: usize) -> bool {
+        self.have.is_set(piece_index)
+    }
+
+    /// Returns the bitfield of pieces we have
+    pub fn bitfield(&self) -> &BitField {
+        &self.have
+    }
+
+    /// Marks a piece as being downloaded so it isn't picked again
+    pub fn mark_requested(&mut self, piece_index: usize) {
+        self.requested[piece_index] = true;
+    }
+
+    /// Makes a piece pickable again, e.g. after its peer disconnected or it failed the hash check
+    pub fn cancel(&mut self, piece_index: usize) {
+        self.requested[piece_index] = false;
+    }
+
+    /// Picks the next piece to request from a peer with `peer_bitfield`
+    pub fn pick(&self, peer_bitfield: &BitField) -> Option<usize> {
+        self.pieces_of(peer_bitfield)
+            .filter(|&piece| {
+                !self.has(piece)
+                    && !self.requested[piece]
+                    && self.priorities[piece] != Priority::Skip
+            })
+            .max_by_key(|&piece| {
+                (
+                    self.priorities[piece],
+                    std::cmp::Reverse(self.availability[piece]),
+                    // prefer lower indices on ties
+                    std::cmp::Reverse(piece),
+                )
+            })
+    }
+
+    /// Returns true once every piece that isn't skipped has been downloaded
+    pub fn is_done(&self) -> bool {
+        (0..self.num_pieces())
+            .all(|piece| self.has(piece) || self.priorities[piece] == Priority::Skip)
+    }
+
+    /// Iterates the piece indices set in a peer's bitfield, ignoring padding bits
+    fn pieces_of<'a>(&self, bitfield: &'a BitField) -> impl Iterator<Item = usize> + 'a {
+        let num_pieces = self.num_pieces().min(bitfield.payload.len() * 8);
+        (0..num_pieces).filter(move |&piece| bitfield.is_set(piece))
+    }
+}
+
+/// The blocks asked of one peer. Only blocks we asked for are accepted, and their pieces go
+/// back to the picker when the peer chokes us or goes away.
+#[derive(Debug)]
+pub struct PeerRequests {
+    /// pieces the peer has, as counted in the picker's availability
+    bitfield: BitField,
+    /// blocks asked for and not received yet, as (piece, offset, length)
+    pending: Vec<(usize, usize, usize)>,
+}
+
+impl PeerRequests {
+    pub fn new(num_pieces: usize) -> PeerRequests {
+        PeerRequests {
+            bitfield: BitField::with_len(num_pieces),
+            pending: Vec::new(),
+        }
+    }
+
+    /// Replaces what the peer has with a received bitfield, bits past the last piece are
+    /// ignored
+    pub fn set_bitfield(&mut self, picker: &mut PiecePicker, bitfield: &BitField) {
+        picker.remove_peer(&self.bitfield);
+        self.bitfield = BitField::with_len(picker.num_pieces());
+        for piece in picker.pieces_of(bitfield) {
+            self.bitfield.set(piece);
+        }
+        picker.add_peer(&self.bitfield);
+    }
+
+    /// Records a `Have` from the peer, out of range pieces are ignored
+    pub fn set_have(&mut self, picker: &mut PiecePicker, piece_index: usize) {
+        if piece_index < picker.num_pieces() && !self.bitfield.is_set(piece_index) {
+            self.bitfield.set(piece_index);
+            picker.peer_has(piece_index);
+        }
+    }
+
+    /// Returns true while blocks asked of the peer haven't arrived
+    pub fn is_busy(&self) -> bool {
+        !self.pending.is_empty()
+    }
+
+    /// Picks a piece the peer has and returns the blocks to request from it, nothing if the
+    /// peer is still busy or has nothing we want
+    pub fn request(
+        &mut self,
+        picker: &mut PiecePicker,
+        layout: &Layout,
+    ) -> Vec<(usize, usize, usize)> {
+        if self.is_busy() {
+            return Vec::new();
+        }
+        let Some(piece) = picker.pick(&self.bitfield) else {
+            return Vec::new();
+        };
+        picker.mark_requested(piece);
+        let size = layout.piece_size(piece) as usize;
+        self.pending = (0..size)
+            .step_by(BLOCK_SIZE)
+            .map(|offset| (piece, offset, BLOCK_SIZE.min(size - offset)))
+            .collect();
+        self.pending.clone()
+    }
+
+    /// Returns true if the block was asked of the peer, it is no longer pending afterwards
+    pub fn receive(&mut self, piece_index: usize, offset: usize, length: usize) -> bool {
+        match self
+            .pending
+            .iter()
+            .position(|&block| block == (piece_index, offset, length))
+        {
+            Some(position) => {
+                self.pending.swap_remove(position);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Gives the pieces still pending back to the picker, e.g. once the peer choked us and
+    /// dropped our requests
+    pub fn cancel(&mut self, picker: &mut PiecePicker) {
+        for (piece, _, _) in self.pending.drain(..) {
+            picker.cancel(piece);
+        }
+    }
+
+    /// Cancels what is pending and stops counting the peer's pieces, once it disconnected
+    pub fn remove(mut self, picker: &mut PiecePicker) {
+        self.cancel(picker);
+        picker.remove_peer(&self.bitfield);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::layout::test_torrent;
+    use std::path::PathBuf;
+
+    fn bitfield(pieces: &[usize]) -> BitField {
+        let mut bitfield = BitField::with_len(8);
+        for &piece in pieces {
+            bitfield.set(piece);
+        }
+        bitfield
+    }
+
+    #[test]
+    fn test_picker_rarest_first() {
+        let mut picker = PiecePicker::new(4);
+        picker.add_peer(&bitfield(&[0, 1, 2]));
+        picker.add_peer(&bitfield(&[0, 2]));
+        picker.add_peer(&bitfield(&[0]));
+
+        let peer = bitfield(&[0, 1, 2, 3]);
+        assert_eq!(picker.pick(&peer), Some(3));
+        picker.mark_requested(3);
+        assert_eq!(picker.pick(&peer), Some(1));
+        picker.set_have(1);
+        assert_eq!(picker.pick(&peer), Some(2));
+    }
+
+    #[test]
+    fn test_picker_priorities() {
+        let mut picker = PiecePicker::new(4);
+        picker.set_priorities(vec![
+            Priority::Low,
+            Priority::Skip,
+            Priority::High,
+            Priority::Normal,
+        ]);
+        let peer = bitfield(&[0, 1, 2, 3]);
+        assert_eq!(picker.pick(&peer), Some(2));
+        picker.set_have(2);
+        assert_eq!(picker.pick(&peer), Some(3));
+        picker.set_have(3);
+        assert_eq!(picker.pick(&peer), Some(0));
+        picker.set_have(0);
+        assert_eq!(picker.pick(&peer), None);
+        assert!(picker.is_done());
+    }
+
+    #[test]
+    fn test_peer_requests() {
+        let layout = Layout::new(vec![(PathBuf::from("t"), 40000)], 32768).unwrap();
+        let mut picker = PiecePicker::new(2);
+        let mut peer = PeerRequests::new(2);
+        // bits past the last piece don't count
+        peer.set_bitfield(&mut picker, &BitField::new(vec![0xff]));
+        peer.set_have(&mut picker, 9);
+
+        let blocks = peer.request(&mut picker, &layout);
+        assert_eq!(blocks, vec![(0, 0, 16384), (0, 16384, 16384)]);
+        assert!(peer.request(&mut picker, &layout).is_empty());
+        assert!(!peer.receive(0, 0, 100));
+        assert!(peer.receive(0, 0, 16384));
+        assert!(!peer.receive(0, 0, 16384));
+        assert!(peer.receive(0, 16384, 16384));
+
+        assert_eq!(peer.request(&mut picker, &layout), vec![(1, 0, 7232)]);
+        peer.cancel(&mut picker);
+        assert!(!peer.is_busy());
+        peer.remove(&mut picker);
+        assert_eq!(picker.availability, vec![0, 0]);
+        // the piece received in full stays requested until it is verified
+        assert_eq!(picker.requested, vec![true, false]);
+    }
+
+    #[test]
+    fn test_piece_priorities_from_files() {
+        let (_, layout, _) = test_torrent();
+        // the first piece spans both files and takes the higher priority
+        let priorities = piece_priorities(&layout, &[Priority::High, Priority::Skip]);
+        assert_eq!(
+            priorities,
+            vec![Priority::High, Priority::Skip, Priority::Skip]
+        );
+    }
+}
@@ -0,0 +1,390 @@
+use crate::bencode::{Decoder, Dict, Value};
+use crate::bitfield::BitField;
+use crate::storage::{FileStamp, Storage};
+use anyhow::{bail, Context, Result};
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+/// blocks written for a piece that wasn't verified yet
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PartialPiece {
+    pub piece: u64,
+    /// one bit per 16 KiB block
+    pub blocks: Vec<u8>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FileInfo {
+    pub size: u64,
+    pub mtime: i64,
+}
+
+/// Everything needed to pick a torrent back up after a restart without rechecking it, stored
+/// bencoded next to the downloaded data
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ResumeData {
+    pub info_hash: Vec<u8>,
+    pub num_pieces: u64,
+    /// bitfield of verified pieces
+    pub pieces: Vec<u8>,
+    pub partial: Vec<PartialPiece>,
+    /// pieces with bytes stored in the part file
+    pub part_pieces: Vec<u64>,
+    /// size and modification time of each file when the data was saved
+    pub files: Vec<FileInfo>,
+    pub uploaded: u64,
+    pub downloaded: u64,
+    /// known IPv4 peers in compact form
+    pub peers: Vec<u8>,
+    /// known IPv6 peers in compact form
+    pub peers6: Vec<u8>,
+}
+
+impl ResumeData {
+    /// Captures the state of `storage`, session totals and peers are filled in by the caller
+    pub fn capture(info_hash: [u8; 20], storage: &Storage) -> Result<ResumeData> {
+        let have = storage.have();
+        Ok(ResumeData {
+            info_hash: info_hash.to_vec(),
+            num_pieces: have.len() as u64,
+            pieces: have.payload.clone(),
+            partial: storage
+                .partial_pieces()
+                .iter()
+                .map(|(piece, blocks)| PartialPiece {
+                    piece: *piece as u64,
+                    blocks: blocks.payload.clone(),
+                })
+                .collect(),
+            part_pieces: storage.part_pieces().iter().map(|&p| p as u64).collect(),
+            files: storage
+                .file_stamps()?
+                .into_iter()
+                .map(|stamp| FileInfo {
+                    size: stamp.size,
+                    mtime: stamp.mtime,
+                })
+                .collect(),
+            uploaded: 0,
+            downloaded: storage.downloaded() as u64,
+            peers: Vec::new(),
+            peers6: Vec::new(),
+        })
+    }
+
+    /// Parses resume data, `partial`, `part pieces` and the peers may be left out
+    pub fn from_bytes(bytes: &[u8]) -> Result<ResumeData> {
+        let value = Decoder::new(bytes)
+            .lenient()
+            .decode_all()
+            .context("failed to deserialize resume data")?;
+        let dict = value.as_dict().context("resume data is not a dictionary")?;
+        let bytes = |key: &'static str| {
+            dict.get_bytes(key)
+                .map(<[u8]>::to_vec)
+                .with_context(|| format!("resume data lacks {:?}", key))
+        };
+        let optional_bytes = |key| dict.get_bytes(key).unwrap_or_default().to_vec();
+        let list = |key| dict.get_list(key).unwrap_or_default();
+
+        let partial = list("partial")
+            .iter()
+            .map(|value| {
+                let dict = value.as_dict().context("invalid partial piece")?;
+                Ok(PartialPiece {
+                    piece: uint(dict, "piece")?,
+                    blocks: dict
+                        .get_bytes("blocks")
+                        .context("invalid partial piece")?
+                        .to_vec(),
+                })
+            })
+            .collect::<Result<_>>()?;
+        let part_pieces = list("part pieces")
+            .iter()
+            .map(|value| value.as_int().and_then(|p| u64::try_from(p).ok()))
+            .collect::<Option<_>>()
+            .context("invalid part pieces")?;
+        let files = dict
+            .get_list("files")
+            .context("resume data lacks \"files\"")?
+            .iter()
+            .map(|value| {
+                let dict = value.as_dict().context("invalid file")?;
+                Ok(FileInfo {
+                    size: uint(dict, "size")?,
+                    mtime: dict.get_int("mtime").context("invalid file")?,
+                })
+            })
+            .collect::<Result<_>>()?;
+        Ok(ResumeData {
+            info_hash: bytes("info-hash")?,
+            num_pieces: uint(dict, "num pieces")?,
+            pieces: bytes("pieces")?,
+            partial,
+            part_pieces,
+            files,
+            uploaded: uint(dict, "uploaded")?,
+            downloaded: uint(dict, "downloaded")?,
+            peers: optional_bytes("peers"),
+            peers6: optional_bytes("peers6"),
+        })
+    }
+
+    pub fn to_bytes(&self) -> Result<Vec<u8>> {
+        let int = |n: u64| i64::try_from(n).context("failed to serialize resume data");
+        let partial = self
+            .partial
+            .iter()
+            .map(|partial| {
+                let mut dict = Dict::new();
+                dict.insert(b"piece", Value::Int(int(partial.piece)?));
+                dict.insert(b"blocks", Value::Bytes(&partial.blocks));
+                Ok(Value::Dict(dict))
+            })
+            .collect::<Result<_>>()?;
+        let part_pieces = self
+            .part_pieces
+            .iter()
+            .map(|&piece| int(piece).map(Value::Int))
+            .collect::<Result<_>>()?;
+        let files = self
+            .files
+            .iter()
+            .map(|file| {
+                let mut dict = Dict::new();
+                dict.insert(b"size", Value::Int(int(file.size)?));
+                dict.insert(b"mtime", Value::Int(file.mtime));
+                Ok(Value::Dict(dict))
+            })
+            .collect::<Result<_>>()?;
+
+        let mut dict = Dict::new();
+        dict.insert(b"info-hash", Value::Bytes(&self.info_hash));
+        dict.insert(b"num pieces", Value::Int(int(self.num_pieces)?));
+        dict.insert(b"pieces", Value::Bytes(&self.pieces));
+        dict.insert(b"partial", Value::List(partial));
+        dict.insert(b"part pieces", Value::List(part_pieces));
+        dict.insert(b"files", Value::List(files));
+        dict.insert(b"uploaded", Value::Int(int(self.uploaded)?));
+        dict.insert(b"downloaded", Value::Int(int(self.downloaded)?));
+        dict.insert(b"peers", Value::Bytes(&self.peers));
+        dict.insert(b"peers6", Value::Bytes(&self.peers6));
+        Ok(Value::Dict(dict).encode())
+    }
+
+    pub fn load(path: &Path) -> Result<ResumeData> {
+        Self::from_bytes(&std::fs::read(path)?)
+    }
+
+    /// Writes to a temporary file first so a crash never leaves a truncated resume file behind
+    pub fn save(&self, path: &Path) -> Result<()> {
+        let tmp = path.with_extension("resume.tmp");
+        std::fs::write(&tmp, self.to_bytes()?)?;
+        std::fs::rename(&tmp, path)?;
+        Ok(())
+    }
+
+    /// Returns true if the files on disk still look exactly like when the data was saved
+    pub fn matches(&self, info_hash: [u8; 20], storage: &Storage) -> Result<bool> {
+        if self.info_hash.as_slice() != info_hash || self.num_pieces != storage.have().len() as u64
+        {
+            return Ok(false);
+        }
+        let stamps: Vec<FileStamp> = storage.file_stamps()?;
+        Ok(stamps.len() == self.files.len()
+            && stamps
+                .iter()
+                .zip(&self.files)
+                .all(|(stamp, saved)| stamp.size == saved.size && stamp.mtime == saved.mtime))
+    }
+
+    /// Loads the saved pieces into `storage` if the files haven't changed since, returns false
+    /// (leaving `storage` untouched) when they have and the data must be rechecked
+    pub fn restore(&self, info_hash: [u8; 20], storage: &mut Storage) -> Result<bool> {
+        if !self.matches(info_hash, storage)? {
+            return Ok(false);
+        }
+        let num_pieces = self.num_pieces as usize;
+        if self.pieces.len() != num_pieces.div_ceil(8) {
+            bail!("resume bitfield has the wrong length");
+        }
+
+        let have = BitField {
+            payload: self.pieces.to_vec(),
+            len: num_pieces,
+        };
+        for piece in have.pieces() {
+            storage.set_have(piece);
+        }
+        for partial in &self.partial {
+            let piece = partial.piece as usize;
+            if piece < num_pieces {
+                let blocks = BitField {
+                    payload: partial.blocks.to_vec(),
+                    len: storage.blocks_in_piece(piece),
+                };
+                storage.set_partial(piece, blocks);
+            }
+        }
+        let part_pieces: Vec<usize> = self.part_pieces.iter().map(|&p| p as usize).collect();
+        storage.set_part_pieces(&part_pieces);
+        Ok(true)
+    }
+
+    /// Stores peers in compact form, 6 bytes per IPv4 and 18 bytes per IPv6 address
+    pub fn set_peers(&mut self, peers: &[SocketAddr]) {
+        self.peers.clear();
+        self.peers6.clear();
+        for peer in peers {
+            match peer {
+                SocketAddr::V4(addr) => {
+                    self.peers.extend_from_slice(&addr.ip().octets());
+                    self.peers.extend_from_slice(&addr.port().to_be_bytes());
+                }
+                SocketAddr::V6(addr) => {
+                    self.peers6.extend_from_slice(&addr.ip().octets());
+                    self.peers6.extend_from_slice(&addr.port().to_be_bytes());
+                }
+            }
+        }
+    }
+
+    pub fn peers(&self) -> Vec<SocketAddr> {
+        let v4 = self.peers.chunks_exact(6).map(|c| {
+            let ip = Ipv4Addr::new(c[0], c[1], c[2], c[3]);
+            SocketAddr::V4(SocketAddrV4::new(ip, u16::from_be_bytes([c[4], c[5]])))
+        });
+        let v6 = self.peers6.chunks_exact(18).map(|c| {
+            let mut octets = [0; 16];
+            octets.copy_from_slice(&c[..16]);
+            let port = u16::from_be_bytes([c[16], c[17]]);
+            SocketAddr::V6(SocketAddrV6::new(Ipv6Addr::from(octets), port, 0, 0))
+        });
+        v4.chain(v6).collect()
+    }
+}
+
+/// Reads a non-negative integer field
+fn uint(dict: &Dict, key: &'static str) -> Result<u64> {
+    dict.get_int(key)
+        .and_then(|n| u64::try_from(n).ok())
+        .with_context(|| format!("invalid resume data field {:?}", key))
+}
+
+/// Returns where the resume data of a torrent is kept inside its download directory
+pub fn resume_path(download_path: &Path, info_hash: &[u8; 20]) -> PathBuf {
+    let hex: String = info_hash.iter().map(|b| format!("{:02x}", b)).collect();
+    download_path.join(format!(".{}.resume", hex))
+}
+
+/// Decides when resume data is due to be written again
+#[derive(Debug)]
+pub struct ResumeWriter {
+    path: PathBuf,
+    interval: Duration,
+    last_save: Instant,
+}
+
+impl ResumeWriter {
+    pub fn new(path: PathBuf, interval: Duration) -> ResumeWriter {
+        ResumeWriter {
+            path,
+            interval,
+            last_save: Instant::now(),
+        }
+    }
+
+    /// Returns true once `interval` has passed since the last save
+    pub fn is_due(&self) -> bool {
+        self.last_save.elapsed() >= self.interval
+    }
+
+    /// Saves unconditionally, also meant to be called on shutdown
+    pub fn save(&mut self, data: &ResumeData) -> Result<()> {
+        data.save(&self.path)?;
+        self.last_save = Instant::now();
+        log::debug!("Saved resume data to {:?}", self.path);
+        Ok(())
+    }
+
+    /// Saves if the interval has passed, returns whether it did
+    pub fn save_if_due(&mut self, data: impl FnOnce() -> Result<ResumeData>) -> Result<bool> {
+        if !self.is_due() {
+            return Ok(false);
+        }
+        self.save(&data()?)?;
+        Ok(true)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::layout::test_torrent;
+    use tempfile::tempdir;
+
+    fn storage(root: &Path) -> Storage {
+        let (_, layout, piece_hashes) = test_torrent();
+        Storage::open(root, layout, piece_hashes).unwrap()
+    }
+
+    #[test]
+    fn test_resume_round_trip() {
+        let dir = tempdir().unwrap();
+        let info_hash = [7; 20];
+        let mut storage = storage(dir.path());
+        storage.write_block(0, 0, &[0, 1, 2, 3]).unwrap();
+        assert!(storage.verify_piece(0).unwrap());
+        storage.write_block(1, 0, &[4, 5]).unwrap();
+
+        let mut data = ResumeData::capture(info_hash, &storage).unwrap();
+        let peers: Vec<SocketAddr> = vec![
+            "1.2.3.4:6881".parse().unwrap(),
+            "[::1]:6882".parse().unwrap(),
+        ];
+        data.set_peers(&peers);
+        data.uploaded = 42;
+        let path = resume_path(dir.path(), &info_hash);
+        data.save(&path).unwrap();
+        drop(storage);
+
+        let loaded = ResumeData::load(&path).unwrap();
+        assert_eq!(loaded, data);
+        assert_eq!(loaded.peers(), peers);
+
+        let mut storage = self::storage(dir.path());
+        assert!(loaded.restore(info_hash, &mut storage).unwrap());
+        assert!(storage.have().is_set(0));
+        assert!(!storage.have().is_set(1));
+        assert_eq!(storage.downloaded(), 4);
+        assert!(storage.partial_pieces().contains_key(&1));
+    }
+
+    #[test]
+    fn test_resume_rejected_after_file_change() {
+        let dir = tempdir().unwrap();
+        let info_hash = [7; 20];
+        let mut storage = storage(dir.path());
+        storage.write_block(0, 0, &[0, 1, 2, 3]).unwrap();
+        assert!(storage.verify_piece(0).unwrap());
+        let data = ResumeData::capture(info_hash, &storage).unwrap();
+        drop(storage);
+
+        // a file that changed size no longer matches
+        std::fs::write(dir.path().join("t/b"), [0; 2]).unwrap();
+        let mut storage = self::storage(dir.path());
+        std::fs::OpenOptions::new()
+            .write(true)
+            .open(dir.path().join("t/a"))
+            .unwrap()
+            .set_len(1)
+            .unwrap();
+        assert!(!data.restore(info_hash, &mut storage).unwrap());
+        assert_eq!(storage.downloaded(), 0);
+
+        // and neither does another torrent
+        assert!(!data.matches([8; 20], &storage).unwrap());
+    }
+}
@@ -0,0 +1,178 @@
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+
+/// longest file name, in bytes, that common file systems accept
+const MAX_NAME_LEN: usize = 255;
+
+/// names Windows reserves for devices, with or without an extension
+const RESERVED_NAMES: [&str; 22] = [
+    "CON", "PRN", "AUX", "NUL", "COM1", "COM2", "COM3", "COM4", "COM5", "COM6", "COM7", "COM8",
+    "COM9", "LPT1", "LPT2", "LPT3", "LPT4", "LPT5", "LPT6", "LPT7", "LPT8", "LPT9",
+];
+
+/// Makes one path component from a torrent safe to use as a file name. Separators, control
+/// characters and characters Windows doesn't allow become `_`, reserved device names get a `_`
+/// appended and over-long names are shortened, so the same torrent lands on the same paths on
+/// every system. Returns `None` for components that have to be dropped: empty ones, `.` and
+/// `..`.
+pub fn sanitize_component(name: &str) -> Option<String> {
+    let mut name: String = name
+        .chars()
+        .map(|c| match c {
+            '/' | '\\' | '<' | '>' | ':' | '"' | '|' | '?' | '*' => '_',
+            c if c.is_control() => '_',
+            c => c,
+        })
+        .collect();
+    // Windows drops trailing dots and spaces, which would make `a.` and `a` the same file
+    let trimmed = name.trim_end_matches(['.', ' ']).len();
+    if trimmed == 0 {
+        return None;
+    }
+    name.truncate(trimmed);
+
+    let stem = name.split('.').next().unwrap_or_default();
+    if RESERVED_NAMES
+        .iter()
+        .any(|reserved| stem.eq_ignore_ascii_case(reserved))
+    {
+        name.insert(stem.len(), '_');
+    }
+    Some(truncate(name))
+}
+
+/// Shortens a name to `MAX_NAME_LEN` bytes on a character boundary, keeping a short extension
+fn truncate(name: String) -> String {
+    if name.len() <= MAX_NAME_LEN {
+        return name;
+    }
+    let extension = match name.rfind('.') {
+        Some(dot) if name.len() - dot <= 10 => &name[dot..],
+        _ => "",
+    };
+    let mut end = MAX_NAME_LEN - extension.len();
+    while !name.is_char_boundary(end) {
+        end -= 1;
+    }
+    format!("{}{}", &name[..end], extension)
+}
+
+/// Joins the path components of a torrent file into a relative path that can't leave the
+/// directory it is joined onto. Unusable components are dropped, a path left with none becomes
+/// `_`.
+pub fn sanitize_path<S: AsRef<str>>(components: &[S]) -> PathBuf {
+    let path: PathBuf = components
+        .iter()
+        .filter_map(|component| sanitize_component(component.as_ref()))
+        .collect();
+    if path.as_os_str().is_empty() {
+        PathBuf::from("_")
+    } else {
+        path
+    }
+}
+
+/// Renames paths that clash with an earlier one, either because they are the same or because
+/// one is a file and the other needs it to be a directory. `a/b.txt` becomes `a/b.1.txt`,
+/// then `a/b.2.txt` and so on.
+pub fn make_unique(paths: &mut [PathBuf]) {
+    let mut files: HashSet<PathBuf> = HashSet::new();
+    let mut dirs: HashSet<PathBuf> = HashSet::new();
+    for path in paths.iter_mut() {
+        *path = unique(path, &files, &dirs);
+        dirs.extend(
+            path.ancestors()
+                .skip(1)
+                .filter(|dir| !dir.as_os_str().is_empty())
+                .map(Path::to_path_buf),
+        );
+        files.insert(path.clone());
+    }
+}
+
+fn unique(path: &Path, files: &HashSet<PathBuf>, dirs: &HashSet<PathBuf>) -> PathBuf {
+    // a file sits where this path needs a directory, move it under a renamed directory
+    if let Some(dir) = path.ancestors().skip(1).find(|dir| files.contains(*dir)) {
+        let rest = path.strip_prefix(dir).unwrap();
+        let renamed = (1..)
+            .map(|n| numbered(dir, n))
+            .find(|dir| !files.contains(dir))
+            .unwrap();
+        return unique(&renamed.join(rest), files, dirs);
+    }
+    if !files.contains(path) && !dirs.contains(path) {
+        return path.to_path_buf();
+    }
+    (1..)
+        .map(|n| numbered(path, n))
+        .find(|path| !files.contains(path) && !dirs.contains(path))
+        .unwrap()
+}
+
+/// `dir/name.ext` becomes `dir/name.<n>.ext`
+fn numbered(path: &Path, n: usize) -> PathBuf {
+    let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+    let name = match path.extension() {
+        Some(extension) => format!("{}.{}.{}", stem, n, extension.to_string_lossy()),
+        None => format!("{}.{}", stem, n),
+    };
+    path.with_file_name(name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sanitize_component() {
+        assert_eq!(sanitize_component("song.mp3").as_deref(), Some("song.mp3"));
+        assert_eq!(sanitize_component(".."), None);
+        assert_eq!(sanitize_component("."), None);
+        assert_eq!(sanitize_component(""), None);
+        assert_eq!(sanitize_component("a/../b").as_deref(), Some("a_.._b"));
+        assert_eq!(sanitize_component("a\0b\\c").as_deref(), Some("a_b_c"));
+        assert_eq!(sanitize_component("what?: ").as_deref(), Some("what__"));
+        assert_eq!(sanitize_component("con.txt").as_deref(), Some("con_.txt"));
+        assert_eq!(sanitize_component("console").as_deref(), Some("console"));
+
+        let long = format!("{}.mkv", "é".repeat(200));
+        let short = sanitize_component(&long).unwrap();
+        assert!(short.len() <= MAX_NAME_LEN);
+        assert!(short.ends_with("é.mkv"));
+    }
+
+    #[test]
+    fn test_sanitize_path() {
+        assert_eq!(
+            sanitize_path(&["..", "..", "etc", "passwd"]),
+            PathBuf::from("etc/passwd")
+        );
+        assert_eq!(sanitize_path(&["/abs", "x"]), PathBuf::from("_abs/x"));
+        assert_eq!(sanitize_path(&[".."]), PathBuf::from("_"));
+        assert_eq!(sanitize_path::<&str>(&[]), PathBuf::from("_"));
+    }
+
+    #[test]
+    fn test_make_unique() {
+        let mut paths: Vec<PathBuf> = [
+            "t/a.txt", "t/a.txt", "t/a.txt", "t/d", "t/d/x", "t/e/y", "t/e",
+        ]
+        .iter()
+        .map(PathBuf::from)
+        .collect();
+        make_unique(&mut paths);
+        let expected: Vec<PathBuf> = [
+            "t/a.txt",
+            "t/a.1.txt",
+            "t/a.2.txt",
+            "t/d",
+            "t/d.1/x",
+            "t/e/y",
+            "t/e.1",
+        ]
+        .iter()
+        .map(PathBuf::from)
+        .collect();
+        assert_eq!(paths, expected);
+    }
+}
@@ -1,77 +1,546 @@
+use crate::bitfield::BitField;
+use crate::layout::Layout;
+use crate::picker::{self, Priority};
 use crate::torrent::Torrent;
 use anyhow::{bail, Result};
+use backend::{FileBackend, StorageBackend};
+use bytes::Bytes;
+use part_file::PartFile;
 use sha1::{Digest, Sha1};
-use std::fs::{File, OpenOptions};
-use std::io::{Read, Seek, SeekFrom, Write};
-use std::path::{Path, PathBuf};
+use std::collections::{BTreeMap, HashMap};
+use std::path::{Component, Path, PathBuf};
 
-const BLOCK_SIZE: usize = 16384;
+pub mod backend;
+pub mod check;
+pub mod disk;
+pub mod error;
+pub mod hasher;
+pub mod part_file;
+pub mod relocate;
+
+/// size of the blocks pieces are requested and written in
+pub const BLOCK_SIZE: usize = 16384;
+
+/// the torrent name is the first component of every layout path
+fn part_name(path: &Path) -> String {
+    path.components()
+        .next()
+        .map(|c| c.as_os_str().to_string_lossy().into_owned())
+        .unwrap_or_default()
+}
 
 #[derive(Debug)]
 pub struct Storage {
-    file: File,
+    layout: Layout,
+    /// holds the files in the same order as `layout.files()`, skipped files that were never
+    /// created aren't open
+    backend: Box<dyn StorageBackend>,
+    priorities: Vec<Priority>,
+    /// bytes of pieces that fall inside files we don't have open
+    part_file: PartFile,
+    root: PathBuf,
     piece_length: usize,
     total_size: usize,
     downloaded: usize,
     piece_hashes: Vec<[u8; 20]>,
+    /// pieces that passed the hash check
+    have: BitField,
+    /// blocks written for pieces that aren't verified yet
+    partial: BTreeMap<usize, BitField>,
+    /// running hash of the blocks written in order from the start of each piece
+    hashing: HashMap<usize, PieceHash>,
+    options: StorageOptions,
+}
+
+/// How the files of a torrent are created on disk
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Allocation {
+    /// created at full size up front, without reserving blocks
+    #[default]
+    Sparse,
+    /// created with every block reserved, so files aren't fragmented and a full disk shows up
+    /// before downloading
+    Full,
+    /// not created until the first block is written to them
+    None,
+}
+
+/// How the files of a torrent are created and named on disk
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct StorageOptions {
+    pub allocation: Allocation,
+    /// files carry a `.part` suffix until `finish` is called on the complete torrent
+    pub part_suffix: bool,
+    /// file handles kept open at once when the storage creates its own `FileBackend`
+    pub max_open_files: usize,
+    /// only read what is already there, e.g. to verify it: nothing is created, resized or
+    /// written
+    pub read_only: bool,
+}
+
+impl Default for StorageOptions {
+    fn default() -> Self {
+        StorageOptions {
+            allocation: Allocation::default(),
+            part_suffix: false,
+            max_open_files: backend::DEFAULT_MAX_OPEN_FILES,
+            read_only: false,
+        }
+    }
+}
+
+impl std::str::FromStr for Allocation {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "sparse" => Ok(Allocation::Sparse),
+            "full" => Ok(Allocation::Full),
+            "none" => Ok(Allocation::None),
+            _ => bail!(
+                "Unknown allocation mode {:?}, expected sparse, full or none",
+                s
+            ),
+        }
+    }
+}
+
+/// Hash state of a piece whose blocks arrived in order so far
+#[derive(Debug, Default)]
+struct PieceHash {
+    hasher: Sha1,
+    /// bytes from the start of the piece fed into `hasher`
+    hashed: usize,
+}
+
+/// Returns what a symlink at `link`, relative to the download directory, has to contain to
+/// point at `target`, relative to the torrent directory. `None` if the target could lead out
+/// of the torrent directory, or if there is no torrent directory.
+fn symlink_target(link: &Path, target: &Path) -> Option<PathBuf> {
+    let normal = |path: &Path| {
+        path.components()
+            .all(|component| matches!(component, Component::Normal(_)))
+    };
+    if !normal(link) || !normal(target) || target.as_os_str().is_empty() {
+        return None;
+    }
+    // climb from the directory holding the link back up to the torrent directory
+    let depth = link.components().count().checked_sub(2)?;
+    let mut path: PathBuf = std::iter::repeat_n("..", depth).collect();
+    path.push(target);
+    Some(path)
+}
+
+/// `name` becomes `name.part`, where files live until the torrent is complete
+pub fn with_part_suffix(path: &Path) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(".part");
+    PathBuf::from(name)
+}
+
+/// Size and modification time of a file, used to tell whether resume data is still valid
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct FileStamp {
+    pub size: u64,
+    /// seconds since the UNIX epoch, 0 if the file doesn't exist
+    pub mtime: i64,
 }
 
 impl Storage {
-    pub fn new(torrent: &Torrent, download_path: &Path) -> Result<Self> {
-        let total_size = torrent.length() as usize;
-        let piece_length = torrent.piece_length() as usize;
-        let piece_hashes = torrent.piece_hashes();
+    /// Creates the files of `torrent` under `download_path`. A single-file torrent is stored as
+    /// `download_path/name`, a multi-file torrent as `download_path/name/<path>` for each file.
+    pub fn new(torrent: &Torrent, download_path: &Path, options: StorageOptions) -> Result<Self> {
+        let layout = torrent.layout();
+        let priorities = vec![Priority::Normal; layout.files().len()];
+        let backend = Box::new(FileBackend::with_max_open(options.max_open_files));
+        Self::open_with_backend(
+            download_path,
+            layout,
+            torrent.piece_hashes(),
+            priorities,
+            backend,
+            options,
+        )
+    }
 
-        let mut file_path = PathBuf::from(download_path);
-        file_path.push(torrent.name());
+    /// Opens (creating if needed) every file of `layout` relative to `root`
+    pub fn open(root: &Path, layout: Layout, piece_hashes: Vec<[u8; 20]>) -> Result<Self> {
+        let priorities = vec![Priority::Normal; layout.files().len()];
+        Self::open_with_priorities(root, layout, piece_hashes, priorities)
+    }
 
-        let file = OpenOptions::new()
-            .read(true)
-            .write(true)
-            .create(true)
-            .open(file_path)?;
+    /// Like `open`, but files with `Priority::Skip` are not created. Skipped files that already
+    /// exist on disk are still used.
+    pub fn open_with_priorities(
+        root: &Path,
+        layout: Layout,
+        piece_hashes: Vec<[u8; 20]>,
+        priorities: Vec<Priority>,
+    ) -> Result<Self> {
+        let backend = Box::new(FileBackend::new());
+        Self::open_with_backend(
+            root,
+            layout,
+            piece_hashes,
+            priorities,
+            backend,
+            StorageOptions::default(),
+        )
+    }
 
-        file.set_len(total_size as u64)?;
+    /// Like `open_with_priorities`, storing the files in `backend` instead of regular files and
+    /// creating them as `options` say
+    pub fn open_with_backend(
+        root: &Path,
+        layout: Layout,
+        piece_hashes: Vec<[u8; 20]>,
+        priorities: Vec<Priority>,
+        mut backend: Box<dyn StorageBackend>,
+        options: StorageOptions,
+    ) -> Result<Self> {
+        if priorities.len() != layout.files().len() {
+            bail!("Expected {} file priorities", layout.files().len());
+        }
+        // the layout may not come from `Layout::from_torrent`, never write outside of `root`
+        if let Some(entry) = layout.files().iter().find(|entry| {
+            !entry
+                .path
+                .components()
+                .all(|component| matches!(component, Component::Normal(_)))
+        }) {
+            bail!("Unsafe file path {}", entry.path.display());
+        }
 
-        Ok(Storage {
-            file,
-            piece_length,
-            total_size,
+        let part_path = match layout.files().first() {
+            Some(entry) => root.join(format!(".{}.parts", part_name(&entry.path))),
+            None => root.join(".parts"),
+        };
+        let num_files = layout.files().len();
+        let part_file =
+            PartFile::open(&mut *backend, num_files, &part_path, layout.piece_length())?;
+        let mut storage = Storage {
+            piece_length: layout.piece_length() as usize,
+            total_size: layout.total_length() as usize,
+            backend,
+            part_file,
+            layout,
+            priorities,
+            root: root.to_path_buf(),
             downloaded: 0,
+            have: BitField::with_len(piece_hashes.len()),
+            partial: BTreeMap::new(),
+            hashing: HashMap::new(),
             piece_hashes,
-        })
+            options,
+        };
+        for index in 0..num_files {
+            let create = storage.priorities[index] != Priority::Skip
+                && options.allocation != Allocation::None
+                && !options.read_only;
+            storage.open_file(index, create)?;
+        }
+        if !options.read_only {
+            storage.create_symlinks()?;
+        }
+        // pieces made only of padding are zeroes, there is nothing to download
+        for piece in 0..storage.piece_hashes.len() {
+            if storage.layout.is_padding(piece) {
+                storage.set_have(piece);
+            }
+        }
+        Ok(storage)
+    }
+
+    /// Opens the file at `index`, creating it only if `create` is set. Padding files and
+    /// symlinks have no data and are never opened.
+    fn open_file(&mut self, index: usize, create: bool) -> Result<()> {
+        let path = self.file_path(index);
+        let entry = &self.layout.files()[index];
+        if !entry.has_data() || !self.backend.open(index, &path, create)? || self.options.read_only
+        {
+            return Ok(());
+        }
+        // resizing to the same length still bumps the modification time, which would make the
+        // resume data look stale on every start
+        if self.backend.len(index)? != entry.length {
+            match self.options.allocation {
+                Allocation::Full => self.backend.allocate(index, entry.length)?,
+                Allocation::Sparse | Allocation::None => {
+                    self.backend.set_len(index, entry.length)?
+                }
+            }
+        }
+        // hidden files need nothing, a leading dot is what hides them on unix
+        if entry.attributes.executable {
+            self.backend.set_executable(index)?;
+        }
+        Ok(())
+    }
+
+    /// Creates the symlinks of the torrent, refusing any that would point outside the torrent
+    /// directory
+    fn create_symlinks(&mut self) -> Result<()> {
+        for index in 0..self.layout.files().len() {
+            let entry = &self.layout.files()[index];
+            if !entry.attributes.symlink || self.priorities[index] == Priority::Skip {
+                continue;
+            }
+            let target = entry
+                .symlink_target
+                .as_deref()
+                .and_then(|target| symlink_target(&entry.path, target));
+            match target {
+                Some(target) => {
+                    let path = self.root.join(&entry.path);
+                    self.backend.symlink(&path, &target)?;
+                }
+                None => log::warn!("ignoring unsafe symlink {}", entry.path.display()),
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns the bytes the files we want still need on disk: their full size, less whatever
+    /// verified pieces they already hold
+    pub fn required_space(&self) -> u64 {
+        let wanted = |index: usize| {
+            self.priorities[index] != Priority::Skip && self.layout.files()[index].has_data()
+        };
+        let total: u64 = (0..self.priorities.len())
+            .filter(|&index| wanted(index))
+            .map(|index| self.layout.files()[index].length)
+            .sum();
+        let have: u64 = self
+            .have
+            .pieces()
+            .into_iter()
+            .flat_map(|piece| self.layout.piece_files(piece))
+            .filter(|slice| wanted(slice.file_index))
+            .map(|slice| slice.length)
+            .sum();
+        total - have
+    }
+
+    /// Fails if the file system can't fit `required_space` more bytes. Fully allocated files
+    /// already hold their space, so this only matters for the other modes.
+    pub fn check_free_space(&self) -> Result<()> {
+        if self.options.allocation == Allocation::Full {
+            return Ok(());
+        }
+        let Some(available) = self.backend.available_space(&self.root)? else {
+            return Ok(());
+        };
+        let required = self.required_space();
+        if available < required {
+            bail!(
+                "Not enough free space: {} bytes needed, {} available",
+                required,
+                available
+            );
+        }
+        Ok(())
+    }
+
+    /// Returns the piece to file mapping the storage was created with
+    pub fn layout(&self) -> &Layout {
+        &self.layout
+    }
+
+    /// Returns the paths of the files on disk in torrent order
+    pub fn paths(&self) -> Vec<PathBuf> {
+        (0..self.layout.files().len())
+            .map(|index| self.file_path(index))
+            .collect()
+    }
+
+    /// Returns where the file at `index` is on disk, with the `.part` suffix while it applies
+    pub fn file_path(&self, index: usize) -> PathBuf {
+        let path = self.root.join(&self.layout.files()[index].path);
+        if self.options.part_suffix {
+            with_part_suffix(&path)
+        } else {
+            path
+        }
+    }
+
+    /// Returns the directory the files are stored under
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    pub fn file_priorities(&self) -> &[Priority] {
+        &self.priorities
+    }
+
+    /// Returns the priority of every piece, for `PiecePicker::set_priorities`
+    pub fn piece_priorities(&self) -> Vec<Priority> {
+        picker::piece_priorities(&self.layout, &self.priorities)
     }
 
+    /// Changes the priority of a file while the torrent runs. A file that stops being skipped is
+    /// created and receives whatever was already downloaded for it into the part file.
+    pub fn set_file_priority(&mut self, index: usize, priority: Priority) -> Result<()> {
+        if index >= self.priorities.len() {
+            bail!("Invalid file index");
+        }
+        if self.options.read_only {
+            bail!("Storage is read-only");
+        }
+        self.priorities[index] = priority;
+        if priority == Priority::Skip
+            || self.backend.is_open(index)
+            || !self.layout.files()[index].has_data()
+        {
+            return Ok(());
+        }
+
+        self.open_file(index, true)?;
+        let entry = self.layout.files()[index].clone();
+        let pieces: Vec<usize> = self.part_file.pieces().collect();
+        for piece in pieces {
+            for slice in self.layout.piece_files(piece) {
+                if slice.file_index != index {
+                    continue;
+                }
+                let mut buf = vec![0u8; slice.length as usize];
+                self.part_file.read_at(
+                    &mut *self.backend,
+                    entry.offset + slice.offset,
+                    &mut buf,
+                )?;
+                self.backend.write_at(index, slice.offset, &buf)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Writes a block, a received `Message::Piece` block can be passed straight from the receive
+    /// buffer
     pub fn write_block(&mut self, piece_index: usize, offset: usize, data: &[u8]) -> Result<()> {
+        self.write(piece_index, offset, data, true)
+    }
+
+    /// Like `write_block`, for pieces hashed elsewhere, e.g. on a `HashPool`
+    pub fn write_unhashed(&mut self, piece_index: usize, offset: usize, data: &[u8]) -> Result<()> {
+        self.write(piece_index, offset, data, false)
+    }
+
+    fn write(&mut self, piece_index: usize, offset: usize, data: &[u8], hash: bool) -> Result<()> {
+        if self.options.read_only {
+            bail!("Storage is read-only");
+        }
+        self.check_block(piece_index, offset, data.len())?;
         let global_offset = self.piece_length * piece_index + offset;
-        if global_offset + data.len() > self.total_size {
-            bail!("Write exceeds file size");
+
+        let mut written = 0;
+        for slice in self.layout.map(global_offset as u64, data.len() as u64) {
+            let len = slice.length as usize;
+            let data = &data[written..written + len];
+            written += len;
+            // padding is known to be zeroes, there is nothing to store
+            if self.layout.files()[slice.file_index].attributes.padding {
+                continue;
+            }
+            // files allocated lazily are created by their first write
+            if self.options.allocation == Allocation::None
+                && self.priorities[slice.file_index] != Priority::Skip
+                && !self.backend.is_open(slice.file_index)
+            {
+                self.open_file(slice.file_index, true)?;
+            }
+            if self.backend.is_open(slice.file_index) {
+                self.backend
+                    .write_at(slice.file_index, slice.offset, data)?;
+            } else {
+                let offset = self.layout.files()[slice.file_index].offset + slice.offset;
+                self.part_file.write_at(&mut *self.backend, offset, data)?;
+            }
         }
 
-        self.file.seek(SeekFrom::Start(global_offset as u64))?;
-        self.file.write_all(data)?;
+        // blocks continuing where the hash left off are hashed now, saving a read when the piece
+        // is verified
+        if hash {
+            let hash = self.hashing.entry(piece_index).or_default();
+            if hash.hashed == offset {
+                hash.hasher.update(data);
+                hash.hashed += data.len();
+            }
+        }
+
+        let blocks = self.blocks_in_piece(piece_index);
+        let written = self
+            .partial
+            .entry(piece_index)
+            .or_insert_with(|| BitField::with_len(blocks));
+        for block in offset / BLOCK_SIZE..(offset + data.len()).div_ceil(BLOCK_SIZE) {
+            written.set(block);
+        }
 
         Ok(())
     }
 
+    /// Fails unless the block lies within a piece of the torrent, blocks come from peers
+    fn check_block(&self, piece_index: usize, offset: usize, length: usize) -> Result<()> {
+        if piece_index >= self.piece_hashes.len() {
+            bail!("Invalid piece index");
+        }
+        let piece_size = self.layout.piece_size(piece_index);
+        if offset
+            .checked_add(length)
+            .is_none_or(|end| end as u64 > piece_size)
+        {
+            bail!("Block exceeds piece {}", piece_index);
+        }
+        Ok(())
+    }
+
+    /// Returns the number of 16 KiB blocks in a piece
+    pub fn blocks_in_piece(&self, piece_index: usize) -> usize {
+        (self.layout.piece_size(piece_index) as usize).div_ceil(BLOCK_SIZE)
+    }
+
+    /// Reads a block into a fresh buffer that can be sent as a `Message::Piece` without copying
     pub fn read_block(
         &mut self,
         piece_index: usize,
         offset: usize,
         length: usize,
-    ) -> Result<Vec<u8>> {
+    ) -> Result<Bytes> {
+        if piece_index >= self.piece_hashes.len() {
+            bail!("Invalid piece index");
+        }
         let global_offset = self.piece_length * piece_index + offset;
         if global_offset + length > self.total_size {
             bail!("Read exceeds file size");
         }
 
         let mut buffer = vec![0u8; length];
-        self.file.seek(SeekFrom::Start(global_offset as u64))?;
-        self.file.read_exact(&mut buffer)?;
+        self.read_at(global_offset, &mut buffer)?;
+
+        Ok(Bytes::from(buffer))
+    }
 
-        Ok(buffer)
+    /// Fills `buf` from the concatenated files starting at `global_offset`
+    fn read_at(&mut self, global_offset: usize, buf: &mut [u8]) -> Result<()> {
+        let mut read = 0;
+        for slice in self.layout.map(global_offset as u64, buf.len() as u64) {
+            let len = slice.length as usize;
+            let buf = &mut buf[read..read + len];
+            if self.layout.files()[slice.file_index].attributes.padding {
+                buf.fill(0);
+            } else if self.backend.is_open(slice.file_index) {
+                self.backend.read_at(slice.file_index, slice.offset, buf)?;
+            } else {
+                let offset = self.layout.files()[slice.file_index].offset + slice.offset;
+                self.part_file.read_at(&mut *self.backend, offset, buf)?;
+            }
+            read += len;
+        }
+        Ok(())
     }
 
+    /// Checks a piece against its hash. Only the part of the piece that wasn't hashed while it
+    /// was written is read back, nothing at all if its blocks arrived in order.
     pub fn verify_piece(&mut self, piece_index: usize) -> Result<bool> {
         if piece_index >= self.piece_hashes.len() {
             bail!("Invalid piece index");
@@ -80,32 +549,109 @@ impl Storage {
         let start = self.piece_length * piece_index;
         let end = (start + self.piece_length).min(self.total_size);
 
-        let mut hasher = Sha1::new();
+        let PieceHash { mut hasher, hashed } =
+            self.hashing.remove(&piece_index).unwrap_or_default();
         let mut buffer = vec![0u8; BLOCK_SIZE];
 
-        self.file.seek(SeekFrom::Start(start as u64))?;
-        let mut remaining = end - start;
-        while remaining > 0 {
-            let read_length = remaining.min(BLOCK_SIZE);
-            self.file.read_exact(&mut buffer[..read_length])?;
+        let mut offset = start + hashed;
+        while offset < end {
+            let read_length = (end - offset).min(BLOCK_SIZE);
+            self.read_at(offset, &mut buffer[..read_length])?;
             hasher.update(&buffer[..read_length]);
-            remaining -= read_length;
+            offset += read_length;
+        }
+
+        let hash: [u8; 20] = hasher.finalize().into();
+        let valid = hash == self.piece_hashes[piece_index];
+        self.record_hash(piece_index, valid);
+        Ok(valid)
+    }
+
+    /// Records the outcome of a hash check, e.g. one done on a `HashPool`. A piece that failed
+    /// forgets its blocks so they are downloaded again.
+    pub fn record_hash(&mut self, piece_index: usize, valid: bool) {
+        self.hashing.remove(&piece_index);
+        if valid {
+            self.set_have(piece_index);
+        } else {
+            self.partial.remove(&piece_index);
+        }
+    }
+
+    /// Records a verified piece and counts it as downloaded
+    pub fn set_have(&mut self, piece_index: usize) {
+        self.partial.remove(&piece_index);
+        self.hashing.remove(&piece_index);
+        if !self.have.is_set(piece_index) {
+            self.have.set(piece_index);
+            self.downloaded += self.layout.piece_size(piece_index) as usize;
+        }
+    }
+
+    /// Returns the pieces that passed the hash check
+    pub fn have(&self) -> &BitField {
+        &self.have
+    }
+
+    /// Returns, for each piece in progress, which of its blocks have been written
+    pub fn partial_pieces(&self) -> &BTreeMap<usize, BitField> {
+        &self.partial
+    }
+
+    /// Returns true once every block of a piece was written, so it can be hashed
+    pub fn is_piece_written(&self, piece_index: usize) -> bool {
+        self.partial
+            .get(&piece_index)
+            .is_some_and(|blocks| blocks.is_complete())
+    }
+
+    /// Restores the blocks written for a piece in progress
+    pub fn set_partial(&mut self, piece_index: usize, blocks: BitField) {
+        if !self.have.is_set(piece_index) {
+            self.partial.insert(piece_index, blocks);
         }
+    }
+
+    /// Returns the pieces with data in the part file
+    pub fn part_pieces(&self) -> Vec<usize> {
+        self.part_file.pieces().collect()
+    }
+
+    /// Tells the part file which pieces it holds data for, as recorded in resume data
+    pub fn set_part_pieces(&mut self, pieces: &[usize]) {
+        self.part_file.restore_pieces(&*self.backend, pieces);
+    }
+
+    /// Returns the size and modification time of every file, in torrent order
+    pub fn file_stamps(&self) -> Result<Vec<FileStamp>> {
+        (0..self.layout.files().len())
+            .map(|index| {
+                if !self.backend.is_open(index) {
+                    return Ok(FileStamp::default());
+                }
+                Ok(FileStamp {
+                    size: self.backend.len(index)?,
+                    mtime: self.backend.modified(index)?,
+                })
+            })
+            .collect()
+    }
 
-        let hash: [u8; 20] = hasher
-            .finalize()
-            .try_into()
-            .expect("SHA1 hash should be 20 bytes");
-        Ok(hash == self.piece_hashes[piece_index])
+    /// Returns the number of bytes in verified pieces
+    pub fn downloaded(&self) -> usize {
+        self.downloaded
     }
     // Checks if all pieces have been successfully downloaded
     pub fn is_complete(&self) -> bool {
-        // Assuming each piece is of equal length except possibly the last one
-        let num_pieces = self.piece_hashes.len();
-        let expected_downloaded = num_pieces * self.piece_length;
-        let last_piece_length = self.total_size % self.piece_length;
+        self.have.is_complete()
+    }
 
-        self.downloaded >= expected_downloaded - self.piece_length + last_piece_length
+    /// Returns true once every piece we want is verified, skipped files may still be missing
+    pub fn is_done(&self) -> bool {
+        self.piece_priorities()
+            .iter()
+            .enumerate()
+            .all(|(piece, &priority)| priority == Priority::Skip || self.have.is_set(piece))
     }
 
     // Gets the download progress as a percentage
@@ -117,20 +663,37 @@ impl Storage {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use tempfile::tempfile;
+    use crate::layout::{test_torrent, FileAttributes};
+    use backend::MemoryBackend;
+    use tempfile::tempdir;
 
     fn setup_test_storage() -> Storage {
-        let temp_file = tempfile().unwrap();
         let piece_length = 1024; // Example piece length
         let total_size = piece_length * 10; // Example total size
         let piece_hashes = vec![[0u8; 20]; 10]; // Example piece hashes
 
+        let mut backend = Box::new(MemoryBackend::new());
+        backend.open(0, Path::new("t"), true).unwrap();
+        backend.set_len(0, total_size as u64).unwrap();
         Storage {
-            file: temp_file,
+            layout: Layout::new(
+                vec![(PathBuf::from("t"), total_size as u64)],
+                piece_length as u64,
+            )
+            .unwrap(),
+            part_file: PartFile::open(&mut *backend, 1, Path::new(".t.parts"), piece_length as u64)
+                .unwrap(),
+            backend,
+            priorities: vec![Priority::Normal],
+            root: PathBuf::new(),
             piece_length,
             total_size,
             downloaded: 0,
             piece_hashes,
+            have: BitField::with_len(10),
+            partial: BTreeMap::new(),
+            hashing: HashMap::new(),
+            options: StorageOptions::default(),
         }
     }
 
@@ -154,11 +717,300 @@ mod tests {
 
         for i in 0..storage.piece_hashes.len() {
             storage.write_block(i, 0, &data).unwrap();
-            // Update downloaded size
-            storage.downloaded += storage.piece_length;
+            // the total divides evenly into pieces, the last one still counts
+            assert!(!storage.is_complete());
+            storage.set_have(i);
         }
 
         assert!(storage.is_complete());
         assert_eq!(storage.progress(), 100.0);
     }
+
+    #[test]
+    fn test_storage_multi_file_spans() {
+        let dir = tempdir().unwrap();
+        let root = dir.path();
+        // pieces of 4 bytes over files of 3, 0 and 6 bytes
+        let data: Vec<u8> = (0..9).collect();
+        let piece_hashes = data
+            .chunks(4)
+            .map(|chunk| Sha1::digest(chunk).into())
+            .collect();
+        let layout = Layout::new(
+            vec![
+                (PathBuf::from("multi/a"), 3),
+                (PathBuf::from("multi/empty"), 0),
+                (PathBuf::from("multi/sub/b"), 6),
+            ],
+            4,
+        )
+        .unwrap();
+        let mut storage = Storage::open(root, layout, piece_hashes).unwrap();
+
+        // the first piece starts in `a` and ends in `sub/b`
+        storage.write_block(0, 0, &data[..4]).unwrap();
+        storage.write_block(1, 0, &data[4..8]).unwrap();
+        storage.write_block(2, 0, &data[8..]).unwrap();
+
+        assert_eq!(std::fs::read(root.join("multi/a")).unwrap(), [0, 1, 2]);
+        assert!(root.join("multi/empty").exists());
+        assert_eq!(
+            std::fs::read(root.join("multi/sub/b")).unwrap(),
+            [3, 4, 5, 6, 7, 8]
+        );
+        assert_eq!(storage.read_block(0, 2, 4).unwrap(), [2, 3, 4, 5][..]);
+        for i in 0..3 {
+            assert!(storage.verify_piece(i).unwrap());
+        }
+        assert!(storage.write_block(2, 0, &[0, 0]).is_err());
+        assert!(storage.write_block(3, 0, &[0]).is_err());
+        assert!(storage.write_block(0, usize::MAX, &[0]).is_err());
+        assert!(storage.read_block(3, 0, 1).is_err());
+    }
+
+    #[test]
+    fn test_storage_keeps_mtime_of_existing_files() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("t");
+        let file = std::fs::File::create(&path).unwrap();
+        file.set_len(9).unwrap();
+        let mtime = std::time::UNIX_EPOCH + std::time::Duration::from_secs(1_000_000);
+        file.set_modified(mtime).unwrap();
+        drop(file);
+
+        let layout = Layout::new(vec![(PathBuf::from("t"), 9)], 4).unwrap();
+        Storage::open(dir.path(), layout, vec![[0; 20]; 3]).unwrap();
+        let modified = std::fs::metadata(&path).unwrap().modified().unwrap();
+        assert_eq!(modified, mtime);
+    }
+
+    #[test]
+    fn test_storage_skipped_file() {
+        let dir = tempdir().unwrap();
+        let root = dir.path();
+        let (data, layout, piece_hashes) = test_torrent();
+        let priorities = vec![Priority::Normal, Priority::Skip];
+        let mut storage =
+            Storage::open_with_priorities(root, layout, piece_hashes, priorities).unwrap();
+        assert!(!root.join("t/b").exists());
+        assert_eq!(
+            storage.piece_priorities(),
+            vec![Priority::Normal, Priority::Skip, Priority::Skip]
+        );
+
+        // the first piece straddles both files, the part of `b` goes to the part file
+        storage.write_block(0, 0, &data[..4]).unwrap();
+        assert!(storage.verify_piece(0).unwrap());
+        assert!(!root.join("t/b").exists());
+        assert!(root.join(".t.parts").exists());
+
+        // wanting `b` again creates it with the byte already downloaded
+        storage.set_file_priority(1, Priority::High).unwrap();
+        assert_eq!(std::fs::read(root.join("t/b")).unwrap(), [3, 0, 0, 0, 0, 0]);
+        storage.write_block(1, 0, &data[4..8]).unwrap();
+        storage.write_block(2, 0, &data[8..]).unwrap();
+        for i in 0..3 {
+            assert!(storage.verify_piece(i).unwrap());
+        }
+    }
+
+    #[test]
+    fn test_storage_in_memory() {
+        let root = Path::new("/nowhere");
+        let (data, layout, piece_hashes) = test_torrent();
+        let priorities = vec![Priority::Normal, Priority::Skip];
+        let backend = Box::new(MemoryBackend::new());
+        let mut storage = Storage::open_with_backend(
+            root,
+            layout,
+            piece_hashes,
+            priorities,
+            backend,
+            StorageOptions::default(),
+        )
+        .unwrap();
+
+        // the part file lives in memory as well
+        storage.write_block(0, 0, &data[..4]).unwrap();
+        assert!(storage.verify_piece(0).unwrap());
+        assert_eq!(storage.part_pieces(), vec![0]);
+        assert!(!root.exists());
+    }
+
+    #[test]
+    fn test_storage_unsafe_path() {
+        for path in ["../escape", "/etc/passwd", "t/../../escape"] {
+            let layout = Layout::new(vec![(PathBuf::from(path), 3)], 4).unwrap();
+            let result = Storage::open_with_backend(
+                Path::new("/nowhere"),
+                layout,
+                vec![[0; 20]],
+                vec![Priority::Normal],
+                Box::new(MemoryBackend::new()),
+                StorageOptions::default(),
+            );
+            assert!(result.is_err(), "{}", path);
+        }
+    }
+
+    #[test]
+    fn test_storage_lazy_allocation() {
+        let dir = tempdir().unwrap();
+        let root = dir.path();
+        let (data, layout, piece_hashes) = test_torrent();
+        let priorities = vec![Priority::Normal; 2];
+        let backend = Box::new(FileBackend::new());
+        let mut storage = Storage::open_with_backend(
+            root,
+            layout,
+            piece_hashes,
+            priorities,
+            backend,
+            StorageOptions {
+                allocation: Allocation::None,
+                ..StorageOptions::default()
+            },
+        )
+        .unwrap();
+        assert!(!root.join("t").exists());
+        assert_eq!(storage.required_space(), 9);
+        storage.check_free_space().unwrap();
+
+        // the last piece only touches `b`
+        storage.write_block(2, 0, &data[8..]).unwrap();
+        assert!(storage.verify_piece(2).unwrap());
+        assert!(!root.join("t/a").exists());
+        assert_eq!(std::fs::read(root.join("t/b")).unwrap().len(), 6);
+        assert_eq!(storage.required_space(), 8);
+
+        storage.write_block(0, 0, &data[..4]).unwrap();
+        assert_eq!(std::fs::read(root.join("t/a")).unwrap(), [0, 1, 2]);
+        assert!(storage.verify_piece(0).unwrap());
+    }
+
+    #[test]
+    fn test_storage_padding_files() {
+        let dir = tempdir().unwrap();
+        let root = dir.path();
+        // `b` is aligned to the second piece by 5 bytes of padding
+        let data: Vec<u8> = [1, 2, 3, 0, 0, 0, 0, 0, 4, 5, 6, 7].to_vec();
+        let piece_hashes = data
+            .chunks(4)
+            .map(|chunk| Sha1::digest(chunk).into())
+            .collect();
+        let mut layout = Layout::new(
+            vec![
+                (PathBuf::from("t/a"), 3),
+                (PathBuf::from("t/.pad/5"), 5),
+                (PathBuf::from("t/b"), 4),
+            ],
+            4,
+        )
+        .unwrap();
+        layout.set_attributes(1, FileAttributes::parse("p"), None);
+        let mut storage = Storage::open(root, layout, piece_hashes).unwrap();
+        assert!(!root.join("t/.pad").exists());
+        // the piece made only of padding is had from the start
+        assert_eq!(storage.have().pieces(), vec![1]);
+        assert_eq!(storage.required_space(), 7);
+
+        storage.write_block(0, 0, &data[..4]).unwrap();
+        storage.write_block(2, 0, &data[8..]).unwrap();
+        assert!(storage.verify_piece(0).unwrap());
+        assert!(storage.verify_piece(2).unwrap());
+        assert!(storage.verify_piece(1).unwrap());
+        assert!(!root.join("t/.pad").exists());
+        assert!(!root.join(".t.parts").exists());
+        assert!(storage.is_complete());
+    }
+
+    #[test]
+    fn test_symlink_target() {
+        let target = |link: &str, target: &str| {
+            symlink_target(Path::new(link), Path::new(target)).map(|p| p.display().to_string())
+        };
+        assert_eq!(target("t/link", "a"), Some("a".to_string()));
+        assert_eq!(target("t/d/e/link", "x/a"), Some("../../x/a".to_string()));
+        assert_eq!(target("t/link", "../etc/passwd"), None);
+        assert_eq!(target("t/link", "/etc/passwd"), None);
+        assert_eq!(target("t/link", ""), None);
+        // a single-file torrent has no directory to point into
+        assert_eq!(target("link", "a"), None);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_storage_executable_and_symlink() {
+        use std::os::unix::fs::PermissionsExt;
+        let dir = tempdir().unwrap();
+        let root = dir.path();
+        let mut layout = Layout::new(
+            vec![
+                (PathBuf::from("t/bin/run"), 4),
+                (PathBuf::from("t/run"), 0),
+                (PathBuf::from("t/escape"), 0),
+            ],
+            4,
+        )
+        .unwrap();
+        let link = FileAttributes::parse("l");
+        layout.set_attributes(0, FileAttributes::parse("x"), None);
+        layout.set_attributes(1, link, Some(PathBuf::from("bin/run")));
+        layout.set_attributes(2, link, Some(PathBuf::from("../../outside")));
+        let mut storage = Storage::open(root, layout, vec![Sha1::digest(b"#!sh").into()]).unwrap();
+
+        let mode = std::fs::metadata(root.join("t/bin/run"))
+            .unwrap()
+            .permissions()
+            .mode();
+        assert_eq!(mode & 0o100, 0o100);
+        assert_eq!(
+            std::fs::read_link(root.join("t/run")).unwrap(),
+            PathBuf::from("bin/run")
+        );
+        assert!(std::fs::symlink_metadata(root.join("t/escape")).is_err());
+
+        storage.write_block(0, 0, b"#!sh").unwrap();
+        assert!(storage.verify_piece(0).unwrap());
+        assert_eq!(std::fs::read(root.join("t/run")).unwrap(), b"#!sh");
+    }
+
+    #[test]
+    fn test_allocation_from_str() {
+        assert_eq!("full".parse::<Allocation>().unwrap(), Allocation::Full);
+        assert_eq!("none".parse::<Allocation>().unwrap(), Allocation::None);
+        assert!("thick".parse::<Allocation>().is_err());
+    }
+
+    #[test]
+    fn test_storage_hash_while_writing() {
+        let dir = tempdir().unwrap();
+        let data: Vec<u8> = (0..8).collect();
+        let piece_hashes = data
+            .chunks(4)
+            .map(|chunk| Sha1::digest(chunk).into())
+            .collect();
+        let layout = Layout::new(vec![(PathBuf::from("t"), 8)], 4).unwrap();
+        let mut storage = Storage::open(dir.path(), layout, piece_hashes).unwrap();
+
+        // in order, the piece is hashed as it is written: clobbering the file behind the
+        // storage's back goes unnoticed because nothing is read back
+        storage.write_block(0, 0, &data[..2]).unwrap();
+        storage.write_block(0, 2, &data[2..4]).unwrap();
+        std::fs::write(dir.path().join("t"), [9; 8]).unwrap();
+        assert!(storage.verify_piece(0).unwrap());
+
+        // out of order, whatever wasn't hashed yet is read back
+        storage.write_block(1, 2, &data[6..]).unwrap();
+        storage.write_block(1, 0, &data[4..6]).unwrap();
+        std::fs::write(dir.path().join("t"), [9; 8]).unwrap();
+        assert!(!storage.verify_piece(1).unwrap());
+        // and a failure resets just that piece
+        assert!(!storage.partial_pieces().contains_key(&1));
+        assert_eq!(storage.have().pieces(), vec![0]);
+
+        storage.write_block(1, 2, &data[6..]).unwrap();
+        storage.write_block(1, 0, &data[4..6]).unwrap();
+        assert!(storage.verify_piece(1).unwrap());
+    }
 }
@@ -0,0 +1,552 @@
+use anyhow::{bail, Result};
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::UNIX_EPOCH;
+
+pub mod memory;
+pub mod mmap;
+
+pub use memory::MemoryBackend;
+pub use mmap::MmapBackend;
+
+/// Where the bytes of a torrent's files live. Files are addressed by their index in the layout,
+/// the index after the last file is used for the part file.
+pub trait StorageBackend: Send + std::fmt::Debug {
+    /// Opens the file at `index`, creating it and its parent directories only if `create` is
+    /// set. Returns false if the file doesn't exist.
+    fn open(&mut self, index: usize, path: &Path, create: bool) -> Result<bool>;
+
+    fn is_open(&self, index: usize) -> bool;
+
+    /// Grows or truncates an open file
+    fn set_len(&mut self, index: usize, length: u64) -> Result<()>;
+
+    /// Sizes an open file and reserves its blocks up front, so it isn't fragmented and running
+    /// out of space fails here rather than halfway through the download
+    fn allocate(&mut self, index: usize, length: u64) -> Result<()> {
+        self.set_len(index, length)
+    }
+
+    /// Returns the bytes free on the file system holding `path`, `None` if the backend isn't
+    /// limited by one
+    fn available_space(&self, _path: &Path) -> Result<Option<u64>> {
+        Ok(None)
+    }
+
+    /// Marks an open file as executable, where the backend has such a thing
+    fn set_executable(&mut self, _index: usize) -> Result<()> {
+        Ok(())
+    }
+
+    /// Creates a symlink at `path` containing `target` unless something is already there,
+    /// where the backend supports them
+    fn symlink(&mut self, _path: &Path, _target: &Path) -> Result<()> {
+        Ok(())
+    }
+
+    fn len(&self, index: usize) -> Result<u64>;
+
+    /// Returns the modification time in seconds since the UNIX epoch, 0 if unknown
+    fn modified(&self, index: usize) -> Result<i64>;
+
+    /// Fills `buf` from `offset` in an open file, failing if the file is too short
+    fn read_at(&mut self, index: usize, offset: u64, buf: &mut [u8]) -> Result<()>;
+
+    /// Writes `data` at `offset` in an open file, growing it if needed
+    fn write_at(&mut self, index: usize, offset: u64, data: &[u8]) -> Result<()>;
+
+    /// Closes a file and deletes it
+    fn remove(&mut self, index: usize, path: &Path) -> Result<()>;
+
+    /// Returns a handle other threads can read through while this backend keeps writing. Files
+    /// opened later aren't visible through it.
+    fn reader(&self) -> Result<Arc<dyn BackendReader>>;
+}
+
+/// Read-only access to a backend from other threads, see `StorageBackend::reader`
+pub trait BackendReader: Send + Sync + std::fmt::Debug {
+    fn is_open(&self, index: usize) -> bool;
+
+    fn len(&self, index: usize) -> Result<u64>;
+
+    fn read_at(&self, index: usize, offset: u64, buf: &mut [u8]) -> Result<()>;
+}
+
+/// Reads `buf.len()` bytes at `offset` without moving the file cursor, so handles can be shared
+/// between threads
+#[cfg(unix)]
+pub(crate) fn read_exact_at(file: &File, buf: &mut [u8], offset: u64) -> std::io::Result<()> {
+    std::os::unix::fs::FileExt::read_exact_at(file, buf, offset)
+}
+
+#[cfg(windows)]
+pub(crate) fn read_exact_at(
+    file: &File,
+    mut buf: &mut [u8],
+    mut offset: u64,
+) -> std::io::Result<()> {
+    use std::os::windows::fs::FileExt;
+    while !buf.is_empty() {
+        match file.seek_read(buf, offset) {
+            Ok(0) => return Err(ErrorKind::UnexpectedEof.into()),
+            Ok(n) => {
+                buf = &mut buf[n..];
+                offset += n as u64;
+            }
+            Err(e) if e.kind() == ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(())
+}
+
+/// Writes all of `data` at `offset` without moving the file cursor
+#[cfg(unix)]
+pub(crate) fn write_all_at(file: &File, data: &[u8], offset: u64) -> std::io::Result<()> {
+    std::os::unix::fs::FileExt::write_all_at(file, data, offset)
+}
+
+#[cfg(windows)]
+pub(crate) fn write_all_at(file: &File, mut data: &[u8], mut offset: u64) -> std::io::Result<()> {
+    use std::os::windows::fs::FileExt;
+    while !data.is_empty() {
+        match file.seek_write(data, offset) {
+            Ok(0) => return Err(ErrorKind::WriteZero.into()),
+            Ok(n) => {
+                data = &data[n..];
+                offset += n as u64;
+            }
+            Err(e) if e.kind() == ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(())
+}
+
+/// Sets the length of a file and reserves the blocks of the whole of it
+#[cfg(target_os = "linux")]
+pub(crate) fn allocate(file: &File, length: u64) -> Result<()> {
+    use std::os::unix::io::AsRawFd;
+    file.set_len(length)?;
+    if length == 0 {
+        return Ok(());
+    }
+    // SAFETY: the descriptor stays open for the duration of the call
+    match unsafe { libc::posix_fallocate(file.as_raw_fd(), 0, length as libc::off_t) } {
+        0 => Ok(()),
+        errno => Err(std::io::Error::from_raw_os_error(errno).into()),
+    }
+}
+
+/// No portable way to reserve blocks elsewhere, the file is left sparse
+#[cfg(not(target_os = "linux"))]
+pub(crate) fn allocate(file: &File, length: u64) -> Result<()> {
+    Ok(file.set_len(length)?)
+}
+
+/// Returns the bytes available to us on the file system holding `path`, which doesn't need to
+/// exist yet
+#[cfg(unix)]
+pub(crate) fn available_space(path: &Path) -> Result<Option<u64>> {
+    use std::ffi::CString;
+    use std::os::unix::ffi::OsStrExt;
+    let Some(existing) = path.ancestors().find(|p| p.exists()) else {
+        return Ok(None);
+    };
+    let path = CString::new(existing.as_os_str().as_bytes())?;
+    // SAFETY: statvfs only writes to the struct we pass it
+    let mut stat: libc::statvfs = unsafe { std::mem::zeroed() };
+    if unsafe { libc::statvfs(path.as_ptr(), &mut stat) } != 0 {
+        return Err(std::io::Error::last_os_error().into());
+    }
+    Ok(Some(stat.f_bavail as u64 * stat.f_frsize as u64))
+}
+
+#[cfg(not(unix))]
+pub(crate) fn available_space(_path: &Path) -> Result<Option<u64>> {
+    Ok(None)
+}
+
+/// Adds the execute bits matching the read bits of a file, the way `chmod +x` does under the
+/// usual umask
+#[cfg(unix)]
+pub(crate) fn set_executable(file: &File) -> Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+    let mut permissions = file.metadata()?.permissions();
+    let mode = permissions.mode();
+    permissions.set_mode(mode | (mode & 0o444) >> 2);
+    Ok(file.set_permissions(permissions)?)
+}
+
+#[cfg(not(unix))]
+pub(crate) fn set_executable(_file: &File) -> Result<()> {
+    Ok(())
+}
+
+/// Creates a relative symlink, leaving whatever already exists at `path` alone
+#[cfg(unix)]
+pub(crate) fn symlink(path: &Path, target: &Path) -> Result<()> {
+    if std::fs::symlink_metadata(path).is_ok() {
+        return Ok(());
+    }
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    Ok(std::os::unix::fs::symlink(target, path)?)
+}
+
+/// Symlinks need extra privileges on Windows, they are skipped
+#[cfg(not(unix))]
+pub(crate) fn symlink(_path: &Path, _target: &Path) -> Result<()> {
+    Ok(())
+}
+
+/// Opens a file for reading and writing, `None` if it doesn't exist and `create` isn't set
+pub(crate) fn open_file(path: &Path, create: bool) -> Result<Option<File>> {
+    if create {
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+    }
+    match OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(create)
+        .truncate(false)
+        .open(path)
+    {
+        Ok(file) => Ok(Some(file)),
+        Err(e) if !create && e.kind() == ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Returns the modification time of a file in seconds since the UNIX epoch
+pub(crate) fn mtime(file: &File) -> Result<i64> {
+    Ok(file
+        .metadata()?
+        .modified()?
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs() as i64))
+}
+
+/// default number of file handles a `FileBackend` keeps open
+pub const DEFAULT_MAX_OPEN_FILES: usize = 512;
+
+#[derive(Debug)]
+struct Handle {
+    file: Arc<File>,
+    writable: bool,
+    last_used: u64,
+}
+
+/// Paths of the files that exist and the handles currently open for them, the least recently
+/// used handle is closed once `max_open` are open
+#[derive(Debug)]
+struct FileCache {
+    paths: Vec<Option<PathBuf>>,
+    handles: HashMap<usize, Handle>,
+    max_open: usize,
+    tick: u64,
+}
+
+impl FileCache {
+    fn path(&self, index: usize) -> Result<&Path> {
+        match self.paths.get(index) {
+            Some(Some(path)) => Ok(path),
+            _ => bail!("File {} is not open", index),
+        }
+    }
+
+    /// Returns a handle to the file at `index`, opening it if needed. Files are opened
+    /// read-only until something is written to them.
+    fn get(&mut self, index: usize, writable: bool) -> Result<Arc<File>> {
+        self.tick += 1;
+        if let Some(handle) = self.handles.get_mut(&index) {
+            if handle.writable || !writable {
+                handle.last_used = self.tick;
+                return Ok(handle.file.clone());
+            }
+        }
+        let file = OpenOptions::new()
+            .read(true)
+            .write(writable)
+            .open(self.path(index)?)?;
+        if !self.handles.contains_key(&index) && self.handles.len() >= self.max_open {
+            self.evict();
+        }
+        let file = Arc::new(file);
+        let handle = Handle {
+            file: file.clone(),
+            writable,
+            last_used: self.tick,
+        };
+        self.handles.insert(index, handle);
+        Ok(file)
+    }
+
+    fn evict(&mut self) {
+        let oldest = self
+            .handles
+            .iter()
+            .min_by_key(|(_, handle)| handle.last_used)
+            .map(|(&index, _)| index);
+        if let Some(index) = oldest {
+            self.handles.remove(&index);
+        }
+    }
+}
+
+/// Stores each torrent file as a regular file, the default backend. Only up to a limit of
+/// handles are kept open, so torrents with many thousands of files don't run out of file
+/// descriptors. Readers share the same handles.
+#[derive(Debug)]
+pub struct FileBackend {
+    cache: Arc<Mutex<FileCache>>,
+}
+
+impl Default for FileBackend {
+    fn default() -> Self {
+        FileBackend::with_max_open(DEFAULT_MAX_OPEN_FILES)
+    }
+}
+
+impl FileBackend {
+    pub fn new() -> FileBackend {
+        FileBackend::default()
+    }
+
+    /// Keeps at most `max_open` file handles open
+    pub fn with_max_open(max_open: usize) -> FileBackend {
+        let cache = FileCache {
+            paths: Vec::new(),
+            handles: HashMap::new(),
+            max_open: max_open.max(1),
+            tick: 0,
+        };
+        FileBackend {
+            cache: Arc::new(Mutex::new(cache)),
+        }
+    }
+
+    fn cache(&self) -> MutexGuard<'_, FileCache> {
+        self.cache.lock().unwrap()
+    }
+
+    fn file(&self, index: usize, writable: bool) -> Result<Arc<File>> {
+        self.cache().get(index, writable)
+    }
+
+    fn metadata(&self, index: usize) -> Result<std::fs::Metadata> {
+        Ok(std::fs::metadata(self.cache().path(index)?)?)
+    }
+
+    /// Returns the number of handles currently open
+    pub fn open_handles(&self) -> usize {
+        self.cache().handles.len()
+    }
+}
+
+impl StorageBackend for FileBackend {
+    fn open(&mut self, index: usize, path: &Path, create: bool) -> Result<bool> {
+        let mut cache = self.cache();
+        if create {
+            // created right away, the handle is opened again once needed
+            open_file(path, true)?;
+        } else if !path.exists() {
+            return Ok(false);
+        }
+        if cache.paths.len() <= index {
+            cache.paths.resize_with(index + 1, || None);
+        }
+        cache.paths[index] = Some(path.to_path_buf());
+        cache.handles.remove(&index);
+        Ok(true)
+    }
+
+    fn is_open(&self, index: usize) -> bool {
+        matches!(self.cache().paths.get(index), Some(Some(_)))
+    }
+
+    fn set_len(&mut self, index: usize, length: u64) -> Result<()> {
+        Ok(self.file(index, true)?.set_len(length)?)
+    }
+
+    fn allocate(&mut self, index: usize, length: u64) -> Result<()> {
+        allocate(&*self.file(index, true)?, length)
+    }
+
+    fn available_space(&self, path: &Path) -> Result<Option<u64>> {
+        available_space(path)
+    }
+
+    fn set_executable(&mut self, index: usize) -> Result<()> {
+        set_executable(&*self.file(index, false)?)
+    }
+
+    fn symlink(&mut self, path: &Path, target: &Path) -> Result<()> {
+        symlink(path, target)
+    }
+
+    fn len(&self, index: usize) -> Result<u64> {
+        Ok(self.metadata(index)?.len())
+    }
+
+    fn modified(&self, index: usize) -> Result<i64> {
+        Ok(self
+            .metadata(index)?
+            .modified()?
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_secs() as i64))
+    }
+
+    fn read_at(&mut self, index: usize, offset: u64, buf: &mut [u8]) -> Result<()> {
+        Ok(read_exact_at(&*self.file(index, false)?, buf, offset)?)
+    }
+
+    fn write_at(&mut self, index: usize, offset: u64, data: &[u8]) -> Result<()> {
+        Ok(write_all_at(&*self.file(index, true)?, data, offset)?)
+    }
+
+    fn remove(&mut self, index: usize, path: &Path) -> Result<()> {
+        let mut cache = self.cache();
+        cache.handles.remove(&index);
+        if let Some(slot) = cache.paths.get_mut(index) {
+            if slot.take().is_some() {
+                std::fs::remove_file(path)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn reader(&self) -> Result<Arc<dyn BackendReader>> {
+        Ok(Arc::new(FileReader {
+            cache: self.cache.clone(),
+        }))
+    }
+}
+
+/// Reads through the handle cache of a `FileBackend`, the lock is only held to look a handle
+/// up so hashing threads read in parallel
+#[derive(Debug)]
+struct FileReader {
+    cache: Arc<Mutex<FileCache>>,
+}
+
+impl BackendReader for FileReader {
+    fn is_open(&self, index: usize) -> bool {
+        matches!(self.cache.lock().unwrap().paths.get(index), Some(Some(_)))
+    }
+
+    fn len(&self, index: usize) -> Result<u64> {
+        let path = self.cache.lock().unwrap().path(index)?.to_path_buf();
+        Ok(std::fs::metadata(path)?.len())
+    }
+
+    fn read_at(&self, index: usize, offset: u64, buf: &mut [u8]) -> Result<()> {
+        let file = self.cache.lock().unwrap().get(index, false)?;
+        Ok(read_exact_at(&file, buf, offset)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    /// Runs the same checks against every backend
+    pub(super) fn exercise(backend: &mut dyn StorageBackend, root: &Path) {
+        let path = root.join("dir/a");
+        assert!(!backend.open(0, &path, false).unwrap());
+        assert!(!backend.is_open(0));
+        assert!(backend.open(0, &path, true).unwrap());
+        backend.set_len(0, 8).unwrap();
+        assert_eq!(backend.len(0).unwrap(), 8);
+
+        backend.write_at(0, 2, &[1, 2, 3]).unwrap();
+        let mut buf = [9; 5];
+        backend.read_at(0, 1, &mut buf).unwrap();
+        assert_eq!(buf, [0, 1, 2, 3, 0]);
+        assert!(backend.read_at(0, 6, &mut buf).is_err());
+
+        // writes past the end grow the file, like the sparse part file needs
+        backend.write_at(0, 10, &[7]).unwrap();
+        assert_eq!(backend.len(0).unwrap(), 11);
+
+        let reader = backend.reader().unwrap();
+        let mut buf = [0; 3];
+        reader.read_at(0, 2, &mut buf).unwrap();
+        assert_eq!(buf, [1, 2, 3]);
+        assert!(!reader.is_open(1));
+
+        backend.remove(0, &path).unwrap();
+        assert!(!backend.is_open(0));
+        assert!(!backend.open(0, &path, false).unwrap());
+    }
+
+    #[test]
+    fn test_file_backend() {
+        let dir = tempdir().unwrap();
+        exercise(&mut FileBackend::new(), dir.path());
+    }
+
+    #[test]
+    fn test_file_handle_cache() {
+        let dir = tempdir().unwrap();
+        let mut backend = FileBackend::with_max_open(2);
+        for index in 0..3 {
+            let path = dir.path().join(index.to_string());
+            backend.open(index, &path, true).unwrap();
+            backend.write_at(index, 0, &[index as u8; 4]).unwrap();
+        }
+        assert_eq!(backend.open_handles(), 2);
+
+        // the evicted file is opened again on demand
+        let reader = backend.reader().unwrap();
+        for index in 0..3 {
+            let mut buf = [0; 4];
+            reader.read_at(index, 0, &mut buf).unwrap();
+            assert_eq!(buf, [index as u8; 4]);
+        }
+        assert_eq!(backend.open_handles(), 2);
+    }
+
+    #[test]
+    fn test_file_handle_upgrade() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("a");
+        std::fs::write(&path, [1, 2, 3]).unwrap();
+        let mut backend = FileBackend::new();
+        assert!(backend.open(0, &path, false).unwrap());
+
+        // seeding only needs a read-only handle
+        let mut buf = [0; 3];
+        backend.read_at(0, 0, &mut buf).unwrap();
+        assert!(!backend.cache().handles[&0].writable);
+        backend.write_at(0, 1, &[9]).unwrap();
+        assert!(backend.cache().handles[&0].writable);
+        assert_eq!(std::fs::read(&path).unwrap(), [1, 9, 3]);
+    }
+
+    #[test]
+    fn test_file_backend_allocate() {
+        let dir = tempdir().unwrap();
+        let mut backend = FileBackend::new();
+        let path = dir.path().join("a");
+        backend.open(0, &path, true).unwrap();
+        backend.allocate(0, 100_000).unwrap();
+        assert_eq!(backend.len(0).unwrap(), 100_000);
+        #[cfg(target_os = "linux")]
+        {
+            use std::os::unix::fs::MetadataExt;
+            // every block is reserved, unlike a sparse file
+            assert!(std::fs::metadata(&path).unwrap().blocks() * 512 >= 100_000);
+        }
+
+        let missing = dir.path().join("not/yet/created");
+        assert!(backend.available_space(&missing).unwrap().unwrap() > 0);
+    }
+}
@@ -0,0 +1,153 @@
+use super::{BackendReader, StorageBackend};
+use anyhow::{bail, Result};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+
+type Data = Arc<RwLock<Vec<u8>>>;
+
+/// Keeps every file in memory, for tests and for tools that never touch the disk. Files are
+/// remembered by path, so reopening a path finds what was written to it before.
+#[derive(Debug, Default)]
+pub struct MemoryBackend {
+    store: HashMap<PathBuf, Data>,
+    files: Vec<Option<Data>>,
+}
+
+impl MemoryBackend {
+    pub fn new() -> MemoryBackend {
+        MemoryBackend::default()
+    }
+
+    /// Returns a copy of the file stored at `path`
+    pub fn contents(&self, path: &Path) -> Option<Vec<u8>> {
+        self.store
+            .get(path)
+            .map(|data| data.read().unwrap().clone())
+    }
+
+    fn file(&self, index: usize) -> Result<&Data> {
+        match self.files.get(index) {
+            Some(Some(data)) => Ok(data),
+            _ => bail!("File {} is not open", index),
+        }
+    }
+}
+
+fn read(data: &[u8], offset: u64, buf: &mut [u8]) -> Result<()> {
+    let start = offset as usize;
+    match data.get(start..start + buf.len()) {
+        Some(bytes) => buf.copy_from_slice(bytes),
+        None => bail!("Read past the end of the file"),
+    }
+    Ok(())
+}
+
+impl StorageBackend for MemoryBackend {
+    fn open(&mut self, index: usize, path: &Path, create: bool) -> Result<bool> {
+        let data = match self.store.get(path) {
+            Some(data) => data.clone(),
+            None if create => self.store.entry(path.to_path_buf()).or_default().clone(),
+            None => return Ok(false),
+        };
+        if self.files.len() <= index {
+            self.files.resize_with(index + 1, || None);
+        }
+        self.files[index] = Some(data);
+        Ok(true)
+    }
+
+    fn is_open(&self, index: usize) -> bool {
+        matches!(self.files.get(index), Some(Some(_)))
+    }
+
+    fn set_len(&mut self, index: usize, length: u64) -> Result<()> {
+        self.file(index)?
+            .write()
+            .unwrap()
+            .resize(length as usize, 0);
+        Ok(())
+    }
+
+    fn len(&self, index: usize) -> Result<u64> {
+        Ok(self.file(index)?.read().unwrap().len() as u64)
+    }
+
+    fn modified(&self, _index: usize) -> Result<i64> {
+        Ok(0)
+    }
+
+    fn read_at(&mut self, index: usize, offset: u64, buf: &mut [u8]) -> Result<()> {
+        read(&self.file(index)?.read().unwrap(), offset, buf)
+    }
+
+    fn write_at(&mut self, index: usize, offset: u64, data: &[u8]) -> Result<()> {
+        let mut file = self.file(index)?.write().unwrap();
+        let end = offset as usize + data.len();
+        if file.len() < end {
+            file.resize(end, 0);
+        }
+        file[offset as usize..end].copy_from_slice(data);
+        Ok(())
+    }
+
+    fn remove(&mut self, index: usize, path: &Path) -> Result<()> {
+        if let Some(slot) = self.files.get_mut(index) {
+            *slot = None;
+        }
+        self.store.remove(path);
+        Ok(())
+    }
+
+    fn reader(&self) -> Result<Arc<dyn BackendReader>> {
+        Ok(Arc::new(MemoryReader {
+            files: self.files.clone(),
+        }))
+    }
+}
+
+/// Shares the buffers of a `MemoryBackend`, so it sees later writes too
+#[derive(Debug)]
+struct MemoryReader {
+    files: Vec<Option<Data>>,
+}
+
+impl MemoryReader {
+    fn file(&self, index: usize) -> Result<&Data> {
+        match self.files.get(index) {
+            Some(Some(data)) => Ok(data),
+            _ => bail!("File {} is not open", index),
+        }
+    }
+}
+
+impl BackendReader for MemoryReader {
+    fn is_open(&self, index: usize) -> bool {
+        matches!(self.files.get(index), Some(Some(_)))
+    }
+
+    fn len(&self, index: usize) -> Result<u64> {
+        Ok(self.file(index)?.read().unwrap().len() as u64)
+    }
+
+    fn read_at(&self, index: usize, offset: u64, buf: &mut [u8]) -> Result<()> {
+        read(&self.file(index)?.read().unwrap(), offset, buf)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::backend::tests::exercise;
+
+    #[test]
+    fn test_memory_backend() {
+        let mut backend = MemoryBackend::new();
+        exercise(&mut backend, Path::new("/nowhere"));
+        assert!(!Path::new("/nowhere").exists());
+
+        backend.open(0, Path::new("a"), true).unwrap();
+        backend.write_at(0, 1, &[5]).unwrap();
+        assert_eq!(backend.contents(Path::new("a")), Some(vec![0, 5]));
+    }
+}
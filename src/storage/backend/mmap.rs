@@ -0,0 +1,186 @@
+use super::{
+    allocate, available_space, mtime, open_file, set_executable, symlink, write_all_at,
+    BackendReader, StorageBackend,
+};
+use anyhow::{bail, Result};
+use memmap2::Mmap;
+use std::fs::File;
+use std::path::Path;
+use std::sync::Arc;
+
+/// Maps a whole file read-only, `None` for empty files which can't be mapped
+fn map(file: &File) -> Result<Option<Arc<Mmap>>> {
+    if file.metadata()?.len() == 0 {
+        return Ok(None);
+    }
+    // SAFETY: the files belong to the torrent and are only resized through this backend, which
+    // drops its mappings before shrinking a file
+    let map = unsafe { Mmap::map(file)? };
+    Ok(Some(Arc::new(map)))
+}
+
+fn read(map: Option<&Mmap>, offset: u64, buf: &mut [u8]) -> Result<()> {
+    let start = offset as usize;
+    let bytes = map.map_or(&[][..], |map| &map[..]);
+    match bytes.get(start..start + buf.len()) {
+        Some(bytes) => buf.copy_from_slice(bytes),
+        None => bail!("Read past the end of the file"),
+    }
+    Ok(())
+}
+
+#[derive(Debug)]
+struct MappedFile {
+    file: File,
+    /// remapped lazily once writes grew the file past it
+    map: Option<Arc<Mmap>>,
+}
+
+/// Serves reads from memory-mapped files, which makes the random reads of seeding cheap.
+/// Writes go through the file handles and show up in the mappings through the page cache.
+#[derive(Debug, Default)]
+pub struct MmapBackend {
+    files: Vec<Option<MappedFile>>,
+}
+
+impl MmapBackend {
+    pub fn new() -> MmapBackend {
+        MmapBackend::default()
+    }
+
+    fn file(&self, index: usize) -> Result<&MappedFile> {
+        match self.files.get(index) {
+            Some(Some(file)) => Ok(file),
+            _ => bail!("File {} is not open", index),
+        }
+    }
+
+    fn file_mut(&mut self, index: usize) -> Result<&mut MappedFile> {
+        match self.files.get_mut(index) {
+            Some(Some(file)) => Ok(file),
+            _ => bail!("File {} is not open", index),
+        }
+    }
+}
+
+impl StorageBackend for MmapBackend {
+    fn open(&mut self, index: usize, path: &Path, create: bool) -> Result<bool> {
+        let Some(file) = open_file(path, create)? else {
+            return Ok(false);
+        };
+        if self.files.len() <= index {
+            self.files.resize_with(index + 1, || None);
+        }
+        self.files[index] = Some(MappedFile { file, map: None });
+        Ok(true)
+    }
+
+    fn is_open(&self, index: usize) -> bool {
+        matches!(self.files.get(index), Some(Some(_)))
+    }
+
+    fn set_len(&mut self, index: usize, length: u64) -> Result<()> {
+        let mapped = self.file_mut(index)?;
+        // touching a mapping past the end of its file faults, never shrink under one
+        mapped.map = None;
+        mapped.file.set_len(length)?;
+        Ok(())
+    }
+
+    fn allocate(&mut self, index: usize, length: u64) -> Result<()> {
+        let mapped = self.file_mut(index)?;
+        mapped.map = None;
+        allocate(&mapped.file, length)
+    }
+
+    fn available_space(&self, path: &Path) -> Result<Option<u64>> {
+        available_space(path)
+    }
+
+    fn set_executable(&mut self, index: usize) -> Result<()> {
+        set_executable(&self.file(index)?.file)
+    }
+
+    fn symlink(&mut self, path: &Path, target: &Path) -> Result<()> {
+        symlink(path, target)
+    }
+
+    fn len(&self, index: usize) -> Result<u64> {
+        Ok(self.file(index)?.file.metadata()?.len())
+    }
+
+    fn modified(&self, index: usize) -> Result<i64> {
+        mtime(&self.file(index)?.file)
+    }
+
+    fn read_at(&mut self, index: usize, offset: u64, buf: &mut [u8]) -> Result<()> {
+        let mapped = self.file_mut(index)?;
+        let end = offset as usize + buf.len();
+        if mapped.map.as_ref().map_or(0, |map| map.len()) < end {
+            mapped.map = map(&mapped.file)?;
+        }
+        read(mapped.map.as_deref(), offset, buf)
+    }
+
+    fn write_at(&mut self, index: usize, offset: u64, data: &[u8]) -> Result<()> {
+        Ok(write_all_at(&self.file(index)?.file, data, offset)?)
+    }
+
+    fn remove(&mut self, index: usize, path: &Path) -> Result<()> {
+        if let Some(slot) = self.files.get_mut(index) {
+            if slot.take().is_some() {
+                std::fs::remove_file(path)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn reader(&self) -> Result<Arc<dyn BackendReader>> {
+        let maps = self
+            .files
+            .iter()
+            .map(|file| file.as_ref().map(|f| map(&f.file)).transpose())
+            .collect::<Result<_>>()?;
+        Ok(Arc::new(MmapReader { maps }))
+    }
+}
+
+/// Mappings of the files as they were when the reader was created
+#[derive(Debug)]
+struct MmapReader {
+    /// `Some(None)` for open files that were empty
+    maps: Vec<Option<Option<Arc<Mmap>>>>,
+}
+
+impl BackendReader for MmapReader {
+    fn is_open(&self, index: usize) -> bool {
+        matches!(self.maps.get(index), Some(Some(_)))
+    }
+
+    fn len(&self, index: usize) -> Result<u64> {
+        match self.maps.get(index) {
+            Some(Some(map)) => Ok(map.as_ref().map_or(0, |map| map.len() as u64)),
+            _ => bail!("File {} is not open", index),
+        }
+    }
+
+    fn read_at(&self, index: usize, offset: u64, buf: &mut [u8]) -> Result<()> {
+        match self.maps.get(index) {
+            Some(Some(map)) => read(map.as_deref(), offset, buf),
+            _ => bail!("File {} is not open", index),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::backend::tests::exercise;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_mmap_backend() {
+        let dir = tempdir().unwrap();
+        exercise(&mut MmapBackend::new(), dir.path());
+    }
+}
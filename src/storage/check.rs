@@ -0,0 +1,298 @@
+use super::hasher::HashPool;
+use super::{Storage, BLOCK_SIZE};
+use crate::layout::Layout;
+use anyhow::{bail, Result};
+use sha1::{Digest, Sha1};
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+/// Which pieces a recheck hashes
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CheckMode {
+    /// forget what was verified and hash every piece
+    Full,
+    /// keep the pieces already verified, e.g. from resume data, and hash only the rest
+    Incremental,
+}
+
+/// Passed to the progress callback after each piece
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CheckProgress {
+    pub piece_index: usize,
+    pub valid: bool,
+    /// pieces hashed so far
+    pub checked: usize,
+    /// pieces this check is going to hash
+    pub total: usize,
+}
+
+/// Outcome of a recheck
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct CheckReport {
+    pub checked: usize,
+    pub total: usize,
+    /// pieces that failed the hash check, in order
+    pub bad: Vec<usize>,
+    /// true if the check was cancelled before hashing every piece
+    pub cancelled: bool,
+}
+
+impl CheckReport {
+    fn record(
+        &mut self,
+        piece_index: usize,
+        valid: bool,
+        progress: &mut impl FnMut(CheckProgress),
+    ) {
+        if !valid {
+            self.bad.push(piece_index);
+        }
+        self.checked += 1;
+        progress(CheckProgress {
+            piece_index,
+            valid,
+            checked: self.checked,
+            total: self.total,
+        });
+    }
+
+    /// Groups the bad pieces by the files they touch, a piece straddling several files is
+    /// listed under each of them
+    pub fn bad_files(&self, layout: &Layout) -> BTreeMap<usize, Vec<usize>> {
+        let mut files: BTreeMap<usize, Vec<usize>> = BTreeMap::new();
+        for &piece in &self.bad {
+            for slice in layout.piece_files(piece) {
+                files.entry(slice.file_index).or_default().push(piece);
+            }
+        }
+        files
+    }
+}
+
+impl Storage {
+    /// Hashes existing data against the piece hashes and records the pieces that pass in
+    /// `have()`. `cancel` is checked between pieces, pieces verified before it was set are
+    /// kept.
+    pub fn recheck(
+        &mut self,
+        mode: CheckMode,
+        cancel: &AtomicBool,
+        mut progress: impl FnMut(CheckProgress),
+    ) -> Result<CheckReport> {
+        let pieces = self.pieces_to_check(mode);
+        let mut report = CheckReport {
+            total: pieces.len(),
+            ..CheckReport::default()
+        };
+        for piece_index in pieces {
+            if cancel.load(Ordering::Relaxed) {
+                report.cancelled = true;
+                break;
+            }
+            let valid = self.verify_piece(piece_index)?;
+            report.record(piece_index, valid, &mut progress);
+        }
+        Ok(report)
+    }
+
+    /// Like `recheck`, but hashes on every worker of `pool`, which must not have other pieces
+    /// pending. Progress is reported in completion order.
+    pub fn recheck_parallel(
+        &mut self,
+        mode: CheckMode,
+        pool: &mut HashPool,
+        cancel: &AtomicBool,
+        mut progress: impl FnMut(CheckProgress),
+    ) -> Result<CheckReport> {
+        if pool.pending() > 0 {
+            bail!("Hash pool is busy");
+        }
+        let pieces = self.pieces_to_check(mode);
+        let mut report = CheckReport {
+            total: pieces.len(),
+            ..CheckReport::default()
+        };
+        let reader = self.piece_reader()?;
+        // keep every worker busy without reading the whole torrent ahead
+        let max_pending = pool.threads() * 4;
+        let mut pieces = pieces.into_iter();
+        loop {
+            while !report.cancelled && pool.pending() < max_pending {
+                if cancel.load(Ordering::Relaxed) {
+                    report.cancelled = true;
+                    break;
+                }
+                let Some(piece_index) = pieces.next() else {
+                    break;
+                };
+                pool.submit(&reader, piece_index, self.piece_hash(piece_index));
+            }
+            let Some(result) = pool.recv() else {
+                break;
+            };
+            match result.valid {
+                Ok(valid) => {
+                    self.record_hash(result.piece_index, valid);
+                    report.record(result.piece_index, valid, &mut progress);
+                }
+                Err(e) => {
+                    while pool.recv().is_some() {}
+                    return Err(e);
+                }
+            }
+        }
+        report.bad.sort_unstable();
+        Ok(report)
+    }
+
+    /// Hashes a whole file against the SHA-1 the torrent lists for it, `None` if it has none
+    pub fn verify_file(&mut self, index: usize) -> Result<Option<bool>> {
+        let Some(entry) = self.layout.files().get(index) else {
+            bail!("Invalid file index");
+        };
+        let Some(expected) = entry.sha1 else {
+            return Ok(None);
+        };
+        let (start, end) = (entry.offset as usize, entry.end() as usize);
+        let mut hasher = Sha1::new();
+        let mut buffer = vec![0u8; BLOCK_SIZE];
+        let mut offset = start;
+        while offset < end {
+            let len = (end - offset).min(BLOCK_SIZE);
+            self.read_at(offset, &mut buffer[..len])?;
+            hasher.update(&buffer[..len]);
+            offset += len;
+        }
+        Ok(Some(hasher.finalize()[..] == expected))
+    }
+
+    /// Resets what was verified for a full check and returns the pieces left to hash
+    fn pieces_to_check(&mut self, mode: CheckMode) -> Vec<usize> {
+        if mode == CheckMode::Full {
+            self.have.payload.fill(0);
+            self.partial.clear();
+            self.hashing.clear();
+            self.downloaded = 0;
+        }
+        (0..self.piece_hashes.len())
+            .filter(|&piece| !self.have.is_set(piece))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::layout::test_torrent;
+    use crate::picker::Priority;
+    use crate::storage::backend::FileBackend;
+    use crate::storage::StorageOptions;
+    use std::path::PathBuf;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_recheck_existing_data() {
+        let dir = tempdir().unwrap();
+        let (data, layout, piece_hashes) = test_torrent();
+        std::fs::create_dir(dir.path().join("t")).unwrap();
+        std::fs::write(dir.path().join("t/a"), &data[..3]).unwrap();
+        // the last byte of `b` is wrong
+        std::fs::write(dir.path().join("t/b"), [3, 4, 5, 6, 7, 0]).unwrap();
+        let mut storage = Storage::open(dir.path(), layout, piece_hashes).unwrap();
+
+        let mut seen = Vec::new();
+        let report = storage
+            .recheck(CheckMode::Full, &AtomicBool::new(false), |p| {
+                seen.push((p.piece_index, p.valid))
+            })
+            .unwrap();
+        assert_eq!(seen, vec![(0, true), (1, true), (2, false)]);
+        assert_eq!(report.bad, vec![2]);
+        assert_eq!(report.bad_files(storage.layout()), [(1, vec![2])].into());
+        assert_eq!(storage.have().pieces(), vec![0, 1]);
+        assert_eq!(storage.downloaded(), 8);
+
+        // an incremental check only hashes what isn't verified yet
+        storage.write_block(2, 0, &data[8..]).unwrap();
+        let report = storage
+            .recheck(CheckMode::Incremental, &AtomicBool::new(false), |_| {})
+            .unwrap();
+        assert_eq!((report.checked, report.bad.len()), (1, 0));
+        assert!(storage.is_complete());
+
+        // the same on a pool
+        std::fs::write(dir.path().join("t/a"), [0; 3]).unwrap();
+        let mut pool = HashPool::new(2, None);
+        let report = storage
+            .recheck_parallel(CheckMode::Full, &mut pool, &AtomicBool::new(false), |_| {})
+            .unwrap();
+        assert_eq!((report.checked, report.bad.clone()), (3, vec![0]));
+        assert_eq!(storage.have().pieces(), vec![1, 2]);
+    }
+
+    #[test]
+    fn test_verify_file() {
+        let dir = tempdir().unwrap();
+        let mut layout = Layout::new(
+            vec![(PathBuf::from("t/a"), 3), (PathBuf::from("t/b"), 5)],
+            4,
+        )
+        .unwrap();
+        layout.set_file_hash(1, Some(Sha1::digest([4, 5, 6, 7, 8]).into()));
+        std::fs::create_dir(dir.path().join("t")).unwrap();
+        std::fs::write(dir.path().join("t/b"), [4, 5, 6, 7, 8]).unwrap();
+        let mut storage = Storage::open(dir.path(), layout, vec![[0; 20]; 2]).unwrap();
+        assert_eq!(storage.verify_file(0).unwrap(), None);
+        assert_eq!(storage.verify_file(1).unwrap(), Some(true));
+        storage.write_block(1, 3, &[0]).unwrap();
+        assert_eq!(storage.verify_file(1).unwrap(), Some(false));
+    }
+
+    #[test]
+    fn test_recheck_cancel() {
+        let dir = tempdir().unwrap();
+        let layout = Layout::new(vec![(PathBuf::from("t"), 8)], 4).unwrap();
+        let mut storage = Storage::open(dir.path(), layout, vec![[0; 20]; 2]).unwrap();
+        let cancel = AtomicBool::new(false);
+        let report = storage
+            .recheck(CheckMode::Full, &cancel, |_| {
+                cancel.store(true, Ordering::Relaxed)
+            })
+            .unwrap();
+        assert!(report.cancelled);
+        assert_eq!((report.checked, report.total), (1, 2));
+    }
+
+    #[test]
+    fn test_recheck_read_only() {
+        let dir = tempdir().unwrap();
+        let (data, layout, piece_hashes) = test_torrent();
+        std::fs::create_dir(dir.path().join("t")).unwrap();
+        // `a` is missing and `b` stops short of its last piece
+        std::fs::write(dir.path().join("t/b"), &data[3..8]).unwrap();
+        let options = StorageOptions {
+            read_only: true,
+            ..Default::default()
+        };
+        let priorities = vec![Priority::Normal; 2];
+        let backend = Box::new(FileBackend::new());
+        let mut storage = Storage::open_with_backend(
+            dir.path(),
+            layout,
+            piece_hashes,
+            priorities,
+            backend,
+            options,
+        )
+        .unwrap();
+
+        let mut pool = HashPool::new(2, None);
+        let report = storage
+            .recheck_parallel(CheckMode::Full, &mut pool, &AtomicBool::new(false), |_| {})
+            .unwrap();
+        assert_eq!(report.bad, vec![0, 2]);
+        assert!(!dir.path().join("t/a").exists());
+        assert_eq!(std::fs::read(dir.path().join("t/b")).unwrap().len(), 5);
+        assert!(storage.write_block(0, 0, &[1]).is_err());
+    }
+}
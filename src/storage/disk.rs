@@ -0,0 +1,784 @@
+use super::error::StorageError;
+use super::hasher::{HashPool, HashResult};
+use super::{Storage, BLOCK_SIZE};
+use crate::bitfield::BitField;
+use anyhow::{anyhow, Result};
+use bytes::Bytes;
+use crossbeam::channel::{self, Receiver, Select, Sender, TryRecvError};
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread::JoinHandle;
+
+/// default number of bytes waiting for the disk thread before peers should be slowed down
+pub const DEFAULT_MAX_QUEUED: usize = 16 * 1024 * 1024;
+/// default number of bytes of incomplete pieces kept in memory before the oldest is flushed
+pub const DEFAULT_MAX_BUFFERED: usize = 32 * 1024 * 1024;
+
+/// Something the disk thread reports back to the event loop
+#[derive(Debug)]
+pub enum DiskEvent {
+    /// every block of a piece is on disk and it was checked against its hash, a piece that
+    /// failed has to be downloaded again
+    PieceVerified { piece_index: usize, valid: bool },
+    /// the outcome of `DiskIo::read`
+    Read {
+        tag: usize,
+        piece_index: usize,
+        offset: usize,
+        block: Result<Bytes>,
+    },
+    /// writing or verifying failed and the disk thread stopped writing, blocks received from
+    /// now on are kept in memory until `DiskIo::resume`
+    Error(StorageError),
+    /// `DiskIo::resume` wrote every block kept in memory, writes go to the disk again
+    Resumed,
+    /// a block that could neither be written nor kept in memory, it has to be downloaded again
+    Discarded {
+        piece_index: usize,
+        offset: usize,
+        length: usize,
+    },
+    /// the outcome of `DiskIo::finish`
+    Finished(Result<()>),
+}
+
+enum Job {
+    Write {
+        piece_index: usize,
+        offset: usize,
+        data: Bytes,
+    },
+    Read {
+        tag: usize,
+        piece_index: usize,
+        offset: usize,
+        length: usize,
+    },
+    Call(Box<dyn FnOnce(&mut Storage) + Send>),
+    Resume,
+    Finish(Option<PathBuf>),
+}
+
+/// A piece being assembled in memory
+struct PieceBuffer {
+    data: Vec<u8>,
+    blocks: BitField,
+    /// order the buffer was created in, the oldest is flushed first
+    created: u64,
+}
+
+/// Runs on the disk thread and owns the storage
+struct Worker {
+    storage: Storage,
+    /// hashes completed pieces on every core while the disk thread goes on writing
+    pool: HashPool,
+    buffers: HashMap<usize, PieceBuffer>,
+    buffered: usize,
+    max_buffered: usize,
+    next_buffer: u64,
+    events: Sender<DiskEvent>,
+    waker: Option<Arc<mio::Waker>>,
+    queued: Arc<AtomicUsize>,
+    /// set after a failure until `resume`, nothing is written meanwhile
+    paused: bool,
+    /// blocks received or left unwritten while paused, in order
+    held: Vec<(usize, usize, Bytes)>,
+    held_bytes: usize,
+    /// pieces written whose verification failed to read them back
+    unverified: Vec<usize>,
+}
+
+impl Worker {
+    fn run(mut self, jobs: Receiver<Job>) -> Storage {
+        loop {
+            let mut select = Select::new();
+            let job_ready = select.recv(&jobs);
+            select.recv(self.pool.receiver());
+            if select.ready() == job_ready {
+                match jobs.try_recv() {
+                    Ok(job) => self.run_job(job),
+                    Err(TryRecvError::Disconnected) => break,
+                    Err(TryRecvError::Empty) => {}
+                }
+            }
+            while let Some(result) = self.pool.try_recv() {
+                self.hashed(result);
+            }
+        }
+        // last chance for whatever is kept in memory
+        if self.paused {
+            self.resume();
+        }
+        let pieces: Vec<usize> = self.buffers.keys().copied().collect();
+        for piece_index in pieces {
+            self.flush(piece_index);
+        }
+        while let Some(result) = self.pool.recv() {
+            self.hashed(result);
+        }
+        self.storage
+    }
+
+    fn run_job(&mut self, job: Job) {
+        match job {
+            Job::Write {
+                piece_index,
+                offset,
+                data,
+            } => {
+                let len = data.len();
+                self.write(piece_index, offset, data);
+                self.queued.fetch_sub(len, Ordering::Relaxed);
+            }
+            Job::Read {
+                tag,
+                piece_index,
+                offset,
+                length,
+            } => {
+                // blocks still in memory have to hit the disk before they can be read back
+                self.flush(piece_index);
+                let block = self.storage.read_block(piece_index, offset, length);
+                self.send(DiskEvent::Read {
+                    tag,
+                    piece_index,
+                    offset,
+                    block,
+                });
+            }
+            Job::Call(f) => f(&mut self.storage),
+            Job::Resume => self.resume(),
+            Job::Finish(completed) => {
+                let result = self.storage.finish(completed.as_deref());
+                self.send(DiskEvent::Finished(result));
+            }
+        }
+    }
+
+    fn send(&self, event: DiskEvent) {
+        if self.events.send(event).is_ok() {
+            if let Some(waker) = &self.waker {
+                let _ = waker.wake();
+            }
+        }
+    }
+
+    /// Enters the paused state, only the first failure is reported
+    fn fail(&mut self, piece_index: usize, error: anyhow::Error) {
+        if !self.paused {
+            self.paused = true;
+            self.send(DiskEvent::Error(StorageError::new(piece_index, error)));
+        }
+    }
+
+    /// Keeps a block that can't be written now, as long as it fits in memory
+    fn hold(&mut self, piece_index: usize, offset: usize, data: Bytes) {
+        if self.held_bytes + data.len() > self.max_buffered {
+            self.send(DiskEvent::Discarded {
+                piece_index,
+                offset,
+                length: data.len(),
+            });
+            return;
+        }
+        self.held_bytes += data.len();
+        self.held.push((piece_index, offset, data));
+    }
+
+    fn hold_buffer(&mut self, piece_index: usize, buffer: PieceBuffer) {
+        let data = Bytes::from(buffer.data);
+        for block in buffer.blocks.pieces() {
+            let start = block * BLOCK_SIZE;
+            let end = (start + BLOCK_SIZE).min(data.len());
+            self.hold(piece_index, start, data.slice(start..end));
+        }
+    }
+
+    /// Leaves the paused state and writes the blocks kept meanwhile, pausing again if the
+    /// problem persists
+    fn resume(&mut self) {
+        self.paused = false;
+        self.held_bytes = 0;
+        for (piece_index, offset, data) in std::mem::take(&mut self.held) {
+            self.write(piece_index, offset, data);
+        }
+        for piece_index in std::mem::take(&mut self.unverified) {
+            if self.paused {
+                self.unverified.push(piece_index);
+            } else {
+                self.verify(piece_index);
+            }
+        }
+        if !self.paused {
+            self.send(DiskEvent::Resumed);
+        }
+    }
+
+    /// Buffers block-aligned writes until their piece is complete, anything else goes straight
+    /// to the storage
+    fn write(&mut self, piece_index: usize, offset: usize, data: Bytes) {
+        // a block outside of its piece came from a misbehaving peer, the storage is fine
+        if let Err(error) = self.storage.check_block(piece_index, offset, data.len()) {
+            log::debug!(
+                "dropping block {} of piece {}: {}",
+                offset,
+                piece_index,
+                error
+            );
+            return;
+        }
+        if self.paused {
+            self.hold(piece_index, offset, data);
+            return;
+        }
+        let piece_size = self.storage.layout().piece_size(piece_index) as usize;
+        let aligned = offset.is_multiple_of(BLOCK_SIZE)
+            && (data.len() == BLOCK_SIZE || offset + data.len() == piece_size);
+        if !aligned || offset + data.len() > piece_size {
+            if !self.flush(piece_index) {
+                self.hold(piece_index, offset, data);
+            } else if let Err(error) = self.storage.write_unhashed(piece_index, offset, &data) {
+                self.fail(piece_index, error);
+                self.hold(piece_index, offset, data);
+            } else if self.storage.is_piece_written(piece_index) {
+                self.verify(piece_index);
+            }
+            return;
+        }
+
+        if !self.buffers.contains_key(&piece_index) {
+            self.make_room(piece_size);
+            let buffer = PieceBuffer {
+                data: vec![0; piece_size],
+                blocks: BitField::with_len(self.storage.blocks_in_piece(piece_index)),
+                created: self.next_buffer,
+            };
+            self.next_buffer += 1;
+            self.buffered += piece_size;
+            self.buffers.insert(piece_index, buffer);
+        }
+        let buffer = self.buffers.get_mut(&piece_index).unwrap();
+        buffer.data[offset..offset + data.len()].copy_from_slice(&data);
+        buffer.blocks.set(offset / BLOCK_SIZE);
+
+        let on_disk = self.storage.partial_pieces().get(&piece_index);
+        let complete = match on_disk {
+            None => buffer.blocks.is_complete(),
+            Some(written) => buffer
+                .blocks
+                .iter()
+                .zip(written.iter())
+                .all(|(a, b)| a || b),
+        };
+        if !complete {
+            return;
+        }
+        if on_disk.is_some() {
+            // part of the piece was flushed earlier, whatever of it wasn't hashed on the way out
+            // is read back
+            if self.flush(piece_index) {
+                self.verify(piece_index);
+            }
+            return;
+        }
+
+        // written in one go, the piece is hashed from memory without reading it back
+        let buffer = self.remove_buffer(piece_index).unwrap();
+        match self.storage.write_unhashed(piece_index, 0, &buffer.data) {
+            Ok(()) => {
+                let expected = self.storage.piece_hash(piece_index);
+                self.pool
+                    .submit_data(piece_index, Bytes::from(buffer.data), expected);
+            }
+            Err(error) => {
+                self.fail(piece_index, error);
+                self.hold_buffer(piece_index, buffer);
+            }
+        }
+    }
+
+    /// Reads a written piece back and queues it on the pool
+    fn verify(&mut self, piece_index: usize) {
+        let size = self.storage.layout().piece_size(piece_index) as usize;
+        match self.storage.read_block(piece_index, 0, size) {
+            Ok(data) => {
+                let expected = self.storage.piece_hash(piece_index);
+                self.pool.submit_data(piece_index, data, expected);
+            }
+            Err(error) => {
+                self.fail(piece_index, error);
+                self.unverified.push(piece_index);
+            }
+        }
+    }
+
+    /// Records the outcome of hashing a piece on the pool
+    fn hashed(&mut self, result: HashResult) {
+        let piece_index = result.piece_index;
+        match result.valid {
+            Ok(valid) => {
+                self.storage.record_hash(piece_index, valid);
+                self.send(DiskEvent::PieceVerified { piece_index, valid });
+            }
+            Err(error) => {
+                self.fail(piece_index, error);
+                self.unverified.push(piece_index);
+            }
+        }
+    }
+
+    /// Flushes the oldest pieces until `size` more bytes fit in memory
+    fn make_room(&mut self, size: usize) {
+        while self.buffered + size > self.max_buffered {
+            let oldest = self
+                .buffers
+                .iter()
+                .min_by_key(|(_, buffer)| buffer.created)
+                .map(|(&piece_index, _)| piece_index);
+            match oldest {
+                Some(piece_index) if self.flush(piece_index) => {}
+                _ => break,
+            }
+        }
+    }
+
+    fn remove_buffer(&mut self, piece_index: usize) -> Option<PieceBuffer> {
+        let buffer = self.buffers.remove(&piece_index)?;
+        self.buffered -= buffer.data.len();
+        Some(buffer)
+    }
+
+    /// Writes the blocks of a piece held in memory, each run of contiguous blocks as a single
+    /// write. Returns false if writing failed, the blocks are kept until `resume` then.
+    fn flush(&mut self, piece_index: usize) -> bool {
+        let Some(buffer) = self.remove_buffer(piece_index) else {
+            return true;
+        };
+        let mut block = 0;
+        while block < buffer.blocks.len() {
+            if !buffer.blocks.is_set(block) {
+                block += 1;
+                continue;
+            }
+            let start = block;
+            while block < buffer.blocks.len() && buffer.blocks.is_set(block) {
+                block += 1;
+            }
+            let range = start * BLOCK_SIZE..(block * BLOCK_SIZE).min(buffer.data.len());
+            let offset = range.start;
+            if let Err(error) =
+                self.storage
+                    .write_unhashed(piece_index, offset, &buffer.data[range])
+            {
+                self.fail(piece_index, error);
+                self.hold_buffer(piece_index, buffer);
+                return false;
+            }
+        }
+        true
+    }
+}
+
+/// Moves disk I/O off the network thread. The storage lives on a dedicated thread that takes
+/// writes and reads from a queue, assembles whole pieces in memory so each piece is written in
+/// one go, and reports back through `DiskEvent`s. `is_congested` tells the event loop when to
+/// stop reading from peers.
+pub struct DiskIo {
+    jobs: Option<Sender<Job>>,
+    events: Receiver<DiskEvent>,
+    thread: Option<JoinHandle<Storage>>,
+    /// bytes handed to `write` that the disk thread hasn't handled yet
+    queued: Arc<AtomicUsize>,
+    max_queued: usize,
+}
+
+impl std::fmt::Debug for DiskIo {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("DiskIo")
+            .field("queued", &self.queued_bytes())
+            .field("max_queued", &self.max_queued)
+            .finish()
+    }
+}
+
+impl DiskIo {
+    /// Starts the disk thread with the default limits, `waker` is woken for every event
+    pub fn new(storage: Storage, waker: Option<Arc<mio::Waker>>) -> DiskIo {
+        DiskIo::with_limits(storage, DEFAULT_MAX_QUEUED, DEFAULT_MAX_BUFFERED, waker)
+    }
+
+    /// Starts the disk thread, `max_queued` bytes of pending writes make it congested and at
+    /// most `max_buffered` bytes of incomplete pieces are kept in memory
+    pub fn with_limits(
+        storage: Storage,
+        max_queued: usize,
+        max_buffered: usize,
+        waker: Option<Arc<mio::Waker>>,
+    ) -> DiskIo {
+        let (jobs, job_receiver) = channel::unbounded();
+        let (event_sender, events) = channel::unbounded();
+        let queued = Arc::new(AtomicUsize::new(0));
+        let worker = Worker {
+            storage,
+            pool: HashPool::default(),
+            buffers: HashMap::new(),
+            buffered: 0,
+            max_buffered,
+            next_buffer: 0,
+            events: event_sender,
+            waker,
+            queued: queued.clone(),
+            paused: false,
+            held: Vec::new(),
+            held_bytes: 0,
+            unverified: Vec::new(),
+        };
+        let thread = std::thread::spawn(move || worker.run(job_receiver));
+        DiskIo {
+            jobs: Some(jobs),
+            events,
+            thread: Some(thread),
+            queued,
+            max_queued,
+        }
+    }
+
+    fn submit(&self, job: Job) {
+        // the disk thread only stops once `jobs` is dropped
+        self.jobs
+            .as_ref()
+            .unwrap()
+            .send(job)
+            .expect("disk thread stopped");
+    }
+
+    /// Queues a received block to be written
+    pub fn write(&self, piece_index: usize, offset: usize, data: Bytes) {
+        self.queued.fetch_add(data.len(), Ordering::Relaxed);
+        self.submit(Job::Write {
+            piece_index,
+            offset,
+            data,
+        });
+    }
+
+    /// Queues a block to be read, the result comes back as `DiskEvent::Read` with the same `tag`
+    pub fn read(&self, tag: usize, piece_index: usize, offset: usize, length: usize) {
+        self.submit(Job::Read {
+            tag,
+            piece_index,
+            offset,
+            length,
+        });
+    }
+
+    /// Runs `f` on the disk thread after everything queued so far and waits for its result
+    pub fn call<R: Send + 'static>(&self, f: impl FnOnce(&mut Storage) -> R + Send + 'static) -> R {
+        let (sender, receiver) = channel::bounded(1);
+        self.submit(Job::Call(Box::new(move |storage| {
+            let _ = sender.send(f(storage));
+        })));
+        receiver.recv().expect("disk thread stopped")
+    }
+
+    /// Retries writing after a `DiskEvent::Error`, once the user made room or fixed the
+    /// permissions. `DiskEvent::Resumed` follows if it worked, another `Error` if not.
+    pub fn resume(&self) {
+        self.submit(Job::Resume);
+    }
+
+    /// Calls `Storage::finish` on the disk thread without waiting for it, moving the files may
+    /// take a while. `DiskEvent::Finished` follows.
+    pub fn finish(&self, completed: Option<PathBuf>) {
+        self.submit(Job::Finish(completed));
+    }
+
+    /// Returns the number of bytes waiting to be written
+    pub fn queued_bytes(&self) -> usize {
+        self.queued.load(Ordering::Relaxed)
+    }
+
+    /// Returns true while more than the allowed bytes are waiting to be written
+    pub fn is_congested(&self) -> bool {
+        self.queued_bytes() > self.max_queued
+    }
+
+    /// Returns an event if one is ready, without blocking
+    pub fn try_recv(&self) -> Option<DiskEvent> {
+        self.events.try_recv().ok()
+    }
+
+    /// Waits for the next event
+    pub fn recv(&self) -> Option<DiskEvent> {
+        self.events.recv().ok()
+    }
+
+    /// Writes whatever is still buffered and hands the storage back
+    pub fn shutdown(mut self) -> Result<Storage> {
+        self.stop()
+    }
+
+    fn stop(&mut self) -> Result<Storage> {
+        self.jobs.take();
+        let thread = self
+            .thread
+            .take()
+            .ok_or_else(|| anyhow!("disk thread stopped"))?;
+        thread.join().map_err(|_| anyhow!("disk thread panicked"))
+    }
+}
+
+impl Drop for DiskIo {
+    fn drop(&mut self) {
+        if self.thread.is_some() {
+            let _ = self.stop();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::layout::Layout;
+    use crate::picker::Priority;
+    use crate::storage::backend::{BackendReader, MemoryBackend, StorageBackend};
+    use crate::storage::error::StorageErrorKind;
+    use crate::storage::StorageOptions;
+    use sha1::{Digest, Sha1};
+    use std::path::{Path, PathBuf};
+    use std::sync::atomic::AtomicBool;
+    use tempfile::tempdir;
+
+    /// Keeps files in memory, writes fail while `full` is set
+    #[derive(Debug)]
+    struct FullDisk {
+        inner: MemoryBackend,
+        full: Arc<AtomicBool>,
+    }
+
+    impl StorageBackend for FullDisk {
+        fn open(&mut self, index: usize, path: &Path, create: bool) -> Result<bool> {
+            self.inner.open(index, path, create)
+        }
+
+        fn is_open(&self, index: usize) -> bool {
+            self.inner.is_open(index)
+        }
+
+        fn set_len(&mut self, index: usize, length: u64) -> Result<()> {
+            self.inner.set_len(index, length)
+        }
+
+        fn len(&self, index: usize) -> Result<u64> {
+            self.inner.len(index)
+        }
+
+        fn modified(&self, index: usize) -> Result<i64> {
+            self.inner.modified(index)
+        }
+
+        fn read_at(&mut self, index: usize, offset: u64, buf: &mut [u8]) -> Result<()> {
+            self.inner.read_at(index, offset, buf)
+        }
+
+        fn write_at(&mut self, index: usize, offset: u64, data: &[u8]) -> Result<()> {
+            if self.full.load(Ordering::Relaxed) {
+                return Err(std::io::Error::from(std::io::ErrorKind::StorageFull).into());
+            }
+            self.inner.write_at(index, offset, data)
+        }
+
+        fn remove(&mut self, index: usize, path: &Path) -> Result<()> {
+            self.inner.remove(index, path)
+        }
+
+        fn reader(&self) -> Result<Arc<dyn BackendReader>> {
+            self.inner.reader()
+        }
+    }
+
+    /// two pieces of 3 blocks, the last one shorter
+    fn storage(root: &Path) -> (Storage, Vec<u8>) {
+        let data: Vec<u8> = (0..5 * BLOCK_SIZE + 100).map(|i| i as u8).collect();
+        let piece_length = 3 * BLOCK_SIZE;
+        let piece_hashes = data
+            .chunks(piece_length)
+            .map(|chunk| Sha1::digest(chunk).into())
+            .collect();
+        let layout = Layout::new(
+            vec![
+                (PathBuf::from("t/a"), BLOCK_SIZE as u64 + 10),
+                (PathBuf::from("t/b"), (data.len() - BLOCK_SIZE - 10) as u64),
+            ],
+            piece_length as u64,
+        )
+        .unwrap();
+        (Storage::open(root, layout, piece_hashes).unwrap(), data)
+    }
+
+    fn write(disk: &DiskIo, data: &[u8], piece_index: usize, block: usize) {
+        let start = piece_index * 3 * BLOCK_SIZE + block * BLOCK_SIZE;
+        let end = (start + BLOCK_SIZE).min(data.len());
+        disk.write(
+            piece_index,
+            block * BLOCK_SIZE,
+            Bytes::copy_from_slice(&data[start..end]),
+        );
+    }
+
+    #[test]
+    fn test_disk_assembles_pieces() {
+        let dir = tempdir().unwrap();
+        let (storage, data) = storage(dir.path());
+        let disk = DiskIo::new(storage, None);
+        for block in [2, 0, 1] {
+            write(&disk, &data, 0, block);
+        }
+        match disk.recv().unwrap() {
+            DiskEvent::PieceVerified {
+                piece_index: 0,
+                valid: true,
+            } => {}
+            event => panic!("unexpected {:?}", event),
+        }
+
+        disk.read(7, 0, BLOCK_SIZE, 20);
+        match disk.recv().unwrap() {
+            DiskEvent::Read { tag: 7, block, .. } => {
+                assert_eq!(block.unwrap(), data[BLOCK_SIZE..BLOCK_SIZE + 20])
+            }
+            event => panic!("unexpected {:?}", event),
+        }
+        assert!(disk.call(|storage| storage.have().is_set(0)));
+
+        // an incomplete piece is written out on shutdown
+        write(&disk, &data, 1, 0);
+        let storage = disk.shutdown().unwrap();
+        assert!(storage.partial_pieces()[&1].is_set(0));
+        let file = std::fs::read(dir.path().join("t/b")).unwrap();
+        assert_eq!(
+            file[..3 * BLOCK_SIZE - 10],
+            data[BLOCK_SIZE + 10..4 * BLOCK_SIZE]
+        );
+    }
+
+    #[test]
+    fn test_disk_pauses_on_error() {
+        // only the layout and hashes are taken from the file storage
+        let dir = tempdir().unwrap();
+        let (storage, data) = storage(dir.path());
+        let full = Arc::new(AtomicBool::new(true));
+        let backend = Box::new(FullDisk {
+            inner: MemoryBackend::new(),
+            full: full.clone(),
+        });
+        let storage = Storage::open_with_backend(
+            Path::new("/nowhere"),
+            storage.layout().clone(),
+            (0..2).map(|i| storage.piece_hash(i)).collect(),
+            vec![Priority::Normal; 2],
+            backend,
+            StorageOptions::default(),
+        )
+        .unwrap();
+        let disk = DiskIo::new(storage, None);
+
+        for block in 0..3 {
+            write(&disk, &data, 0, block);
+        }
+        match disk.recv().unwrap() {
+            DiskEvent::Error(error) => {
+                assert_eq!(error.kind, StorageErrorKind::DiskFull);
+                assert_eq!(error.piece_index, 0);
+            }
+            event => panic!("unexpected {:?}", event),
+        }
+        // kept in memory while paused, without further errors
+        write(&disk, &data, 1, 0);
+        disk.resume();
+        assert!(matches!(disk.recv().unwrap(), DiskEvent::Error(_)));
+
+        full.store(false, Ordering::Relaxed);
+        disk.resume();
+        // the piece is hashed on the pool, its result may come after `Resumed`
+        let events = [disk.recv().unwrap(), disk.recv().unwrap()];
+        assert!(events.iter().any(|e| matches!(e, DiskEvent::Resumed)));
+        assert!(events.iter().any(|e| matches!(
+            e,
+            DiskEvent::PieceVerified {
+                piece_index: 0,
+                valid: true
+            }
+        )));
+        let storage = disk.shutdown().unwrap();
+        assert!(storage.partial_pieces()[&1].is_set(0));
+    }
+
+    #[test]
+    fn test_disk_finish() {
+        let dir = tempdir().unwrap();
+        let (storage, data) = storage(dir.path());
+        let disk = DiskIo::new(storage, None);
+        disk.finish(None);
+        assert!(matches!(disk.recv().unwrap(), DiskEvent::Finished(Err(_))));
+
+        for piece_index in 0..2 {
+            for block in 0..3 {
+                write(&disk, &data, piece_index, block);
+            }
+        }
+        for _ in 0..2 {
+            assert!(matches!(
+                disk.recv().unwrap(),
+                DiskEvent::PieceVerified { valid: true, .. }
+            ));
+        }
+        let completed = dir.path().join("completed");
+        disk.finish(Some(completed.clone()));
+        assert!(matches!(disk.recv().unwrap(), DiskEvent::Finished(Ok(()))));
+        assert!(completed.join("t/b").exists());
+    }
+
+    #[test]
+    fn test_disk_flushes_oldest_piece() {
+        let dir = tempdir().unwrap();
+        let (storage, data) = storage(dir.path());
+        // room for a single piece in memory
+        let disk = DiskIo::with_limits(storage, DEFAULT_MAX_QUEUED, 3 * BLOCK_SIZE, None);
+        write(&disk, &data, 0, 0);
+        write(&disk, &data, 0, 1);
+        write(&disk, &data, 1, 0);
+        write(&disk, &data, 0, 2);
+        match disk.recv().unwrap() {
+            DiskEvent::PieceVerified {
+                piece_index: 0,
+                valid: true,
+            } => {}
+            event => panic!("unexpected {:?}", event),
+        }
+        assert!(disk.call(|storage| storage.have().is_set(0)));
+    }
+
+    #[test]
+    fn test_disk_congestion() {
+        let dir = tempdir().unwrap();
+        let (storage, data) = storage(dir.path());
+        let disk = DiskIo::with_limits(storage, BLOCK_SIZE, DEFAULT_MAX_BUFFERED, None);
+
+        // hold the disk thread until the queue has filled up
+        let (release, wait) = channel::bounded::<()>(0);
+        disk.submit(Job::Call(Box::new(move |_| {
+            let _ = wait.recv();
+        })));
+        write(&disk, &data, 0, 0);
+        assert!(!disk.is_congested());
+        write(&disk, &data, 0, 1);
+        assert!(disk.is_congested());
+
+        release.send(()).unwrap();
+        disk.call(|_| ());
+        assert!(!disk.is_congested());
+    }
+}
@@ -0,0 +1,103 @@
+use std::io::ErrorKind;
+
+/// What went wrong with the disk, so the user can be told what to fix
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StorageErrorKind {
+    /// no space left on the device or the quota is used up
+    DiskFull,
+    PermissionDenied,
+    /// a file or directory of the torrent disappeared, e.g. a drive was unmounted
+    NotFound,
+    ReadOnly,
+    Other,
+}
+
+impl StorageErrorKind {
+    /// Looks for an I/O error in the chain of `error` and classifies it
+    pub fn classify(error: &anyhow::Error) -> StorageErrorKind {
+        let Some(io) = error
+            .chain()
+            .find_map(|cause| cause.downcast_ref::<std::io::Error>())
+        else {
+            return StorageErrorKind::Other;
+        };
+        match io.kind() {
+            ErrorKind::StorageFull | ErrorKind::QuotaExceeded => StorageErrorKind::DiskFull,
+            ErrorKind::PermissionDenied => StorageErrorKind::PermissionDenied,
+            ErrorKind::NotFound => StorageErrorKind::NotFound,
+            ErrorKind::ReadOnlyFilesystem => StorageErrorKind::ReadOnly,
+            _ => StorageErrorKind::Other,
+        }
+    }
+}
+
+impl std::fmt::Display for StorageErrorKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            StorageErrorKind::DiskFull => "disk full",
+            StorageErrorKind::PermissionDenied => "permission denied",
+            StorageErrorKind::NotFound => "file missing",
+            StorageErrorKind::ReadOnly => "read-only file system",
+            StorageErrorKind::Other => "I/O error",
+        })
+    }
+}
+
+/// A failed disk operation on a piece, classified
+#[derive(Debug)]
+pub struct StorageError {
+    pub kind: StorageErrorKind,
+    pub piece_index: usize,
+    pub error: anyhow::Error,
+}
+
+impl StorageError {
+    pub fn new(piece_index: usize, error: anyhow::Error) -> StorageError {
+        StorageError {
+            kind: StorageErrorKind::classify(&error),
+            piece_index,
+            error,
+        }
+    }
+}
+
+impl std::fmt::Display for StorageError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} on piece {}: {}",
+            self.kind, self.piece_index, self.error
+        )
+    }
+}
+
+impl std::error::Error for StorageError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::Context;
+
+    #[test]
+    fn test_classify() {
+        let full: anyhow::Error = std::io::Error::from(ErrorKind::StorageFull).into();
+        assert_eq!(
+            StorageErrorKind::classify(&full),
+            StorageErrorKind::DiskFull
+        );
+
+        // found behind context as well
+        let denied = Err::<(), _>(std::io::Error::from(ErrorKind::PermissionDenied))
+            .context("writing t/a")
+            .unwrap_err();
+        let error = StorageError::new(3, denied);
+        assert_eq!(error.kind, StorageErrorKind::PermissionDenied);
+        assert_eq!(
+            error.to_string(),
+            "permission denied on piece 3: writing t/a"
+        );
+
+        let other = anyhow::anyhow!("Write exceeds file size");
+        assert_eq!(StorageErrorKind::classify(&other), StorageErrorKind::Other);
+    }
+}
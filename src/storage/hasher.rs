@@ -0,0 +1,302 @@
+use super::backend::BackendReader;
+use super::part_file::PartFile;
+use super::{Storage, BLOCK_SIZE};
+use crate::layout::Layout;
+use anyhow::Result;
+use bytes::Bytes;
+use crossbeam::channel::{self, Receiver, Sender};
+use sha1::{Digest, Sha1};
+use std::sync::Arc;
+use std::thread::JoinHandle;
+
+/// A read-only view of the storage that hashing threads read pieces from. Files created after
+/// it was taken, e.g. by un-skipping them, need a new one.
+#[derive(Debug)]
+pub struct PieceReader {
+    layout: Layout,
+    reader: Arc<dyn BackendReader>,
+    /// backend index of the part file
+    part_index: usize,
+}
+
+impl PieceReader {
+    /// Hashes a piece block by block
+    pub fn hash_piece(&self, piece_index: usize) -> Result<[u8; 20]> {
+        let start = self.layout.piece_offset(piece_index);
+        let size = self.layout.piece_size(piece_index);
+        let mut hasher = Sha1::new();
+        let mut buffer = vec![0u8; BLOCK_SIZE];
+        let mut offset = 0;
+        while offset < size {
+            let len = (size - offset).min(BLOCK_SIZE as u64) as usize;
+            self.read_at(start + offset, &mut buffer[..len])?;
+            hasher.update(&buffer[..len]);
+            offset += len as u64;
+        }
+        Ok(hasher.finalize().into())
+    }
+
+    /// Fills `buf` from the concatenated files starting at `offset` in the torrent
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> Result<()> {
+        let mut read = 0;
+        for slice in self.layout.map(offset, buf.len() as u64) {
+            let len = slice.length as usize;
+            let buf = &mut buf[read..read + len];
+            if self.layout.files()[slice.file_index].attributes.padding {
+                buf.fill(0);
+            } else if self.reader.is_open(slice.file_index) {
+                self.read_file(slice.file_index, slice.offset, buf)?;
+            } else {
+                let offset = self.layout.files()[slice.file_index].offset + slice.offset;
+                PartFile::read_from(&*self.reader, self.part_index, offset, buf)?;
+            }
+            read += len;
+        }
+        Ok(())
+    }
+
+    /// Reads from a file, a file shorter than the torrent says, e.g. one being verified
+    /// read-only, reads as zeroes past its end
+    fn read_file(&self, index: usize, offset: u64, buf: &mut [u8]) -> Result<()> {
+        let Err(e) = self.reader.read_at(index, offset, buf) else {
+            return Ok(());
+        };
+        let short = e
+            .downcast_ref::<std::io::Error>()
+            .is_some_and(|e| e.kind() == std::io::ErrorKind::UnexpectedEof);
+        if !short {
+            return Err(e);
+        }
+        buf.fill(0);
+        let len = self.reader.len(index)?;
+        if offset < len {
+            let available = ((len - offset) as usize).min(buf.len());
+            self.reader.read_at(index, offset, &mut buf[..available])?;
+        }
+        Ok(())
+    }
+}
+
+impl Storage {
+    /// Takes a read-only view of the files for a `HashPool`
+    pub fn piece_reader(&self) -> Result<Arc<PieceReader>> {
+        Ok(Arc::new(PieceReader {
+            layout: self.layout.clone(),
+            reader: self.backend.reader()?,
+            part_index: self.part_file.index(),
+        }))
+    }
+
+    /// Returns the expected SHA-1 of a piece
+    pub fn piece_hash(&self, piece_index: usize) -> [u8; 20] {
+        self.piece_hashes[piece_index]
+    }
+}
+
+/// The outcome of hashing one piece on the pool
+#[derive(Debug)]
+pub struct HashResult {
+    pub piece_index: usize,
+    /// whether the piece matched its hash, or the error reading it
+    pub valid: Result<bool>,
+}
+
+/// where a worker gets the bytes of a piece from
+enum HashSource {
+    Reader(Arc<PieceReader>),
+    /// the whole piece, already in memory
+    Data(Bytes),
+}
+
+struct HashJob {
+    source: HashSource,
+    piece_index: usize,
+    expected: [u8; 20],
+}
+
+impl HashJob {
+    fn run(self) -> HashResult {
+        let hash = match &self.source {
+            HashSource::Reader(reader) => reader.hash_piece(self.piece_index),
+            HashSource::Data(data) => Ok(Sha1::digest(data).into()),
+        };
+        HashResult {
+            piece_index: self.piece_index,
+            valid: hash.map(|hash| hash == self.expected),
+        }
+    }
+}
+
+/// Verifies pieces on a pool of worker threads so hashing doesn't stall the event loop. Results
+/// come back in completion order, and an optional `mio::Waker` wakes the event loop for each.
+pub struct HashPool {
+    jobs: Option<Sender<HashJob>>,
+    results: Receiver<HashResult>,
+    workers: Vec<JoinHandle<()>>,
+    /// pieces submitted whose result wasn't received yet
+    pending: usize,
+}
+
+impl std::fmt::Debug for HashPool {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("HashPool")
+            .field("workers", &self.workers.len())
+            .field("pending", &self.pending)
+            .finish()
+    }
+}
+
+impl Default for HashPool {
+    /// One worker per core
+    fn default() -> Self {
+        let threads = std::thread::available_parallelism().map_or(1, |n| n.get());
+        HashPool::new(threads, None)
+    }
+}
+
+impl HashPool {
+    /// Starts `threads` workers, `waker` is woken whenever a result is ready
+    pub fn new(threads: usize, waker: Option<Arc<mio::Waker>>) -> HashPool {
+        let (jobs, job_receiver) = channel::unbounded::<HashJob>();
+        let (result_sender, results) = channel::unbounded();
+        let workers = (0..threads.max(1))
+            .map(|_| {
+                let jobs = job_receiver.clone();
+                let results = result_sender.clone();
+                let waker = waker.clone();
+                std::thread::spawn(move || {
+                    for job in jobs {
+                        if results.send(job.run()).is_err() {
+                            break;
+                        }
+                        if let Some(waker) = &waker {
+                            let _ = waker.wake();
+                        }
+                    }
+                })
+            })
+            .collect();
+        HashPool {
+            jobs: Some(jobs),
+            results,
+            workers,
+            pending: 0,
+        }
+    }
+
+    pub fn threads(&self) -> usize {
+        self.workers.len()
+    }
+
+    /// Queues a piece to be read from disk and hashed against `expected`
+    pub fn submit(&mut self, reader: &Arc<PieceReader>, piece_index: usize, expected: [u8; 20]) {
+        self.send(HashJob {
+            source: HashSource::Reader(reader.clone()),
+            piece_index,
+            expected,
+        });
+    }
+
+    /// Queues a piece that is still in memory, saving a read from disk
+    pub fn submit_data(&mut self, piece_index: usize, data: Bytes, expected: [u8; 20]) {
+        self.send(HashJob {
+            source: HashSource::Data(data),
+            piece_index,
+            expected,
+        });
+    }
+
+    fn send(&mut self, job: HashJob) {
+        // the workers only stop once `jobs` is dropped
+        self.jobs.as_ref().unwrap().send(job).unwrap();
+        self.pending += 1;
+    }
+
+    /// Returns the channel results arrive on, waiting on it in a `Select` tells when
+    /// `try_recv` has one
+    pub(crate) fn receiver(&self) -> &Receiver<HashResult> {
+        &self.results
+    }
+
+    /// Returns the number of pieces still being hashed
+    pub fn pending(&self) -> usize {
+        self.pending
+    }
+
+    /// Returns a result if one is ready, without blocking
+    pub fn try_recv(&mut self) -> Option<HashResult> {
+        let result = self.results.try_recv().ok()?;
+        self.pending -= 1;
+        Some(result)
+    }
+
+    /// Waits for the next result, `None` if nothing is pending
+    pub fn recv(&mut self) -> Option<HashResult> {
+        if self.pending == 0 {
+            return None;
+        }
+        let result = self.results.recv().ok()?;
+        self.pending -= 1;
+        Some(result)
+    }
+}
+
+impl Drop for HashPool {
+    fn drop(&mut self) {
+        self.jobs.take();
+        for worker in self.workers.drain(..) {
+            let _ = worker.join();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_hash_pool() {
+        let dir = tempdir().unwrap();
+        let data: Vec<u8> = (0..100).collect();
+        let piece_hashes: Vec<[u8; 20]> = data
+            .chunks(8)
+            .map(|chunk| Sha1::digest(chunk).into())
+            .collect();
+        let layout = Layout::new(
+            vec![(PathBuf::from("t/a"), 30), (PathBuf::from("t/b"), 70)],
+            8,
+        )
+        .unwrap();
+        let mut storage = Storage::open(dir.path(), layout, piece_hashes.clone()).unwrap();
+        for (i, chunk) in data.chunks(8).enumerate() {
+            // leave piece 5 empty
+            if i != 5 {
+                storage.write_block(i, 0, chunk).unwrap();
+            }
+        }
+
+        let mut pool = HashPool::new(4, None);
+        let reader = storage.piece_reader().unwrap();
+        for (i, hash) in piece_hashes.iter().enumerate() {
+            pool.submit(&reader, i, *hash);
+        }
+        assert_eq!(pool.pending(), piece_hashes.len());
+        let mut results: Vec<(usize, bool)> = std::iter::from_fn(|| pool.recv())
+            .map(|r| (r.piece_index, r.valid.unwrap()))
+            .collect();
+        results.sort();
+        assert_eq!(results.len(), piece_hashes.len());
+        assert!(results.iter().all(|&(i, valid)| valid == (i != 5)));
+        assert!(pool.try_recv().is_none());
+
+        pool.submit_data(0, Bytes::from(data[..8].to_vec()), piece_hashes[0]);
+        pool.submit_data(1, Bytes::from(data[..8].to_vec()), piece_hashes[1]);
+        let mut results: Vec<(usize, bool)> = std::iter::from_fn(|| pool.recv())
+            .map(|r| (r.piece_index, r.valid.unwrap()))
+            .collect();
+        results.sort();
+        assert_eq!(results, vec![(0, true), (1, false)]);
+    }
+}
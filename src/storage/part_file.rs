@@ -0,0 +1,127 @@
+use super::backend::{BackendReader, StorageBackend};
+use anyhow::{bail, Result};
+use std::collections::BTreeSet;
+use std::path::{Path, PathBuf};
+
+/// Holds the bytes of pieces that fall inside skipped files, so pieces straddling a skipped and
+/// a wanted file can still be completed and verified. Data is stored at its offset in the
+/// torrent in a sparse file, so only the pieces actually written take up space.
+#[derive(Debug)]
+pub struct PartFile {
+    path: PathBuf,
+    /// index of the part file in the storage backend, after the torrent files
+    index: usize,
+    piece_length: u64,
+    /// pieces with at least one byte in the part file
+    pieces: BTreeSet<usize>,
+}
+
+impl PartFile {
+    /// Opens the part file at `path` if it exists, otherwise it is created on the first write
+    pub fn open(
+        backend: &mut dyn StorageBackend,
+        index: usize,
+        path: &Path,
+        piece_length: u64,
+    ) -> Result<PartFile> {
+        backend.open(index, path, false)?;
+        Ok(PartFile {
+            path: path.to_path_buf(),
+            index,
+            piece_length,
+            pieces: BTreeSet::new(),
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn index(&self) -> usize {
+        self.index
+    }
+
+    /// Returns the pieces that have data in the part file
+    pub fn pieces(&self) -> impl Iterator<Item = usize> + '_ {
+        self.pieces.iter().copied()
+    }
+
+    /// Restores the set of pieces with data, e.g. from resume data
+    pub fn restore_pieces(&mut self, backend: &dyn StorageBackend, pieces: &[usize]) {
+        if backend.is_open(self.index) {
+            self.pieces.extend(pieces);
+        }
+    }
+
+    /// Writes `data` found at `offset` in the torrent
+    pub fn write_at(
+        &mut self,
+        backend: &mut dyn StorageBackend,
+        offset: u64,
+        data: &[u8],
+    ) -> Result<()> {
+        if !backend.is_open(self.index) {
+            backend.open(self.index, &self.path, true)?;
+        }
+        backend.write_at(self.index, offset, data)?;
+
+        let first = offset / self.piece_length;
+        let last = (offset + data.len() as u64).saturating_sub(1) / self.piece_length;
+        self.pieces.extend(first as usize..=last as usize);
+        Ok(())
+    }
+
+    /// Reads the bytes at `offset` in the torrent, ranges never written read back as zeroes
+    pub fn read_at(
+        &self,
+        backend: &mut dyn StorageBackend,
+        offset: u64,
+        buf: &mut [u8],
+    ) -> Result<()> {
+        buf.fill(0);
+        if !backend.is_open(self.index) {
+            return Ok(());
+        }
+        let len = backend.len(self.index)?;
+        if offset < len {
+            let available = ((len - offset) as usize).min(buf.len());
+            backend.read_at(self.index, offset, &mut buf[..available])?;
+        }
+        Ok(())
+    }
+
+    /// Like `read_at`, through a reader on another thread
+    pub fn read_from(
+        reader: &dyn BackendReader,
+        index: usize,
+        offset: u64,
+        buf: &mut [u8],
+    ) -> Result<()> {
+        buf.fill(0);
+        if !reader.is_open(index) {
+            return Ok(());
+        }
+        let len = reader.len(index)?;
+        if offset < len {
+            let available = ((len - offset) as usize).min(buf.len());
+            reader.read_at(index, offset, &mut buf[..available])?;
+        }
+        Ok(())
+    }
+
+    /// Points the part file at `path` after it was moved there, reopening it if it exists
+    pub fn relocate(&mut self, backend: &mut dyn StorageBackend, path: &Path) -> Result<()> {
+        self.path = path.to_path_buf();
+        if backend.is_open(self.index) && !backend.open(self.index, path, false)? {
+            bail!("Part file {} is missing", path.display());
+        }
+        Ok(())
+    }
+
+    /// Deletes the part file once nothing needs it anymore
+    pub fn remove(&mut self, backend: &mut dyn StorageBackend) -> Result<()> {
+        backend.remove(self.index, &self.path)?;
+        self.pieces.clear();
+        Ok(())
+    }
+}
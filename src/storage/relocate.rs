@@ -0,0 +1,271 @@
+use super::backend::symlink;
+use super::Storage;
+use anyhow::{bail, Result};
+use std::collections::BTreeSet;
+use std::ffi::OsString;
+use std::io::ErrorKind;
+use std::path::{Component, Path, PathBuf};
+
+/// Renames `from` to `to`, copying and deleting when they are on different file systems
+fn move_path(from: &Path, to: &Path) -> Result<()> {
+    match std::fs::rename(from, to) {
+        Ok(()) => Ok(()),
+        Err(e) if e.kind() == ErrorKind::CrossesDevices => copy_then_remove(from, to),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Copies `from` next to `to` under a temporary name and renames it into place, so `to` only
+/// appears once it is whole, then deletes `from`
+fn copy_then_remove(from: &Path, to: &Path) -> Result<()> {
+    let mut name = to.as_os_str().to_owned();
+    name.push(".moving");
+    let tmp = PathBuf::from(name);
+    copy_all(from, &tmp)?;
+    std::fs::rename(&tmp, to)?;
+    if std::fs::symlink_metadata(from)?.is_dir() {
+        std::fs::remove_dir_all(from)?;
+    } else {
+        std::fs::remove_file(from)?;
+    }
+    Ok(())
+}
+
+/// Copies a file or a directory tree, symlinks are recreated rather than followed
+fn copy_all(from: &Path, to: &Path) -> Result<()> {
+    let file_type = std::fs::symlink_metadata(from)?.file_type();
+    if file_type.is_symlink() {
+        symlink(to, &std::fs::read_link(from)?)?;
+    } else if file_type.is_dir() {
+        std::fs::create_dir_all(to)?;
+        for entry in std::fs::read_dir(from)? {
+            let entry = entry?;
+            copy_all(&entry.path(), &to.join(entry.file_name()))?;
+        }
+    } else {
+        std::fs::copy(from, to)?;
+    }
+    Ok(())
+}
+
+impl Storage {
+    /// Moves the data of the torrent under `root` while it keeps running, e.g. to seed from
+    /// another drive. The torrent directory (or single file) and the part file are each moved
+    /// with one rename when `root` is on the same file system, and copied then deleted when it
+    /// isn't. Only for storage backed by regular files.
+    pub fn relocate(&mut self, root: &Path) -> Result<()> {
+        if root == self.root {
+            return Ok(());
+        }
+        if self.options.read_only {
+            bail!("Storage is read-only");
+        }
+        let entries = self.top_level_entries();
+        for entry in &entries {
+            if root.join(entry).exists() {
+                bail!("{} already exists", root.join(entry).display());
+            }
+        }
+        std::fs::create_dir_all(root)?;
+        for entry in &entries {
+            let from = self.root.join(entry);
+            if from.exists() {
+                move_path(&from, &root.join(entry))?;
+            }
+        }
+        self.root = root.to_path_buf();
+        self.reopen()
+    }
+
+    /// Drops the `.part` suffix of every file once all wanted pieces are verified, then moves
+    /// the data under `completed` if given. Skipped files that were never created stay missing,
+    /// and the part file is kept while pieces of skipped files are served from it.
+    pub fn finish(&mut self, completed: Option<&Path>) -> Result<()> {
+        if self.options.read_only {
+            bail!("Storage is read-only");
+        }
+        if !self.is_done() {
+            bail!("Torrent is not complete");
+        }
+        if self.options.part_suffix {
+            let from = self.paths();
+            self.options.part_suffix = false;
+            for (index, from) in from.iter().enumerate() {
+                if self.backend.is_open(index) {
+                    std::fs::rename(from, self.file_path(index))?;
+                }
+            }
+            self.reopen()?;
+        }
+        if self.have.is_complete() {
+            self.part_file.remove(&mut *self.backend)?;
+        }
+        match completed {
+            Some(root) => self.relocate(root),
+            None => Ok(()),
+        }
+    }
+
+    /// Returns the names under the root that hold our files: the torrent directory or single
+    /// file, and the part file
+    fn top_level_entries(&self) -> BTreeSet<OsString> {
+        let part_path = self.part_file.path().to_path_buf();
+        self.paths()
+            .into_iter()
+            .chain(std::iter::once(part_path))
+            .filter_map(
+                |path| match path.strip_prefix(&self.root).ok()?.components().next()? {
+                    Component::Normal(name) => Some(name.to_owned()),
+                    _ => None,
+                },
+            )
+            .collect()
+    }
+
+    /// Reopens every open file at its current path after the files were renamed
+    fn reopen(&mut self) -> Result<()> {
+        for index in 0..self.layout.files().len() {
+            if self.backend.is_open(index) {
+                let path = self.file_path(index);
+                if !self.backend.open(index, &path, false)? {
+                    bail!("{} is missing", path.display());
+                }
+            }
+        }
+        let name = self.part_file.path().file_name().unwrap_or_default();
+        let path = self.root.join(name);
+        self.part_file.relocate(&mut *self.backend, &path)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::layout::test_torrent;
+    use crate::picker::Priority;
+    use crate::storage::backend::FileBackend;
+    use crate::storage::StorageOptions;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_finish_and_relocate() {
+        let dir = tempdir().unwrap();
+        let incomplete = dir.path().join("incomplete");
+        let (data, layout, piece_hashes) = test_torrent();
+        let options = StorageOptions {
+            part_suffix: true,
+            ..StorageOptions::default()
+        };
+        let mut storage = Storage::open_with_backend(
+            &incomplete,
+            layout,
+            piece_hashes,
+            vec![Priority::Normal, Priority::Skip],
+            Box::new(FileBackend::new()),
+            options,
+        )
+        .unwrap();
+        assert!(incomplete.join("t/a.part").exists());
+        assert!(storage.finish(None).is_err());
+
+        // `b` is wanted again after the part file got its first byte
+        storage.write_block(0, 0, &data[..4]).unwrap();
+        storage.set_file_priority(1, Priority::Normal).unwrap();
+        storage.write_block(1, 0, &data[4..8]).unwrap();
+        storage.write_block(2, 0, &data[8..]).unwrap();
+        for i in 0..3 {
+            assert!(storage.verify_piece(i).unwrap());
+        }
+
+        let completed = dir.path().join("completed");
+        storage.finish(Some(&completed)).unwrap();
+        assert_eq!(std::fs::read(completed.join("t/a")).unwrap(), [0, 1, 2]);
+        assert!(!incomplete.join("t").exists());
+        assert!(!completed.join("t/b.part").exists());
+        // nothing is skipped anymore, the part file isn't needed
+        assert!(!incomplete.join(".t.parts").exists());
+        assert!(!completed.join(".t.parts").exists());
+        assert_eq!(storage.read_block(0, 2, 4).unwrap(), [2, 3, 4, 5][..]);
+
+        // moving again while seeding, refusing to clobber what's there
+        let other = dir.path().join("other");
+        std::fs::create_dir_all(other.join("t")).unwrap();
+        assert!(storage.relocate(&other).is_err());
+        std::fs::remove_dir(other.join("t")).unwrap();
+        storage.relocate(&other).unwrap();
+        assert_eq!(storage.root(), other);
+        assert_eq!(storage.read_block(2, 0, 1).unwrap(), [8][..]);
+    }
+
+    #[test]
+    fn test_finish_with_skipped_file() {
+        let dir = tempdir().unwrap();
+        let incomplete = dir.path().join("incomplete");
+        let (data, layout, piece_hashes) = test_torrent();
+        let options = StorageOptions {
+            part_suffix: true,
+            ..StorageOptions::default()
+        };
+        let mut storage = Storage::open_with_backend(
+            &incomplete,
+            layout,
+            piece_hashes,
+            vec![Priority::Normal, Priority::Skip],
+            Box::new(FileBackend::new()),
+            options,
+        )
+        .unwrap();
+
+        // the only piece touching `a` is all we want
+        storage.write_block(0, 0, &data[..4]).unwrap();
+        assert!(storage.verify_piece(0).unwrap());
+        assert!(storage.is_done() && !storage.is_complete());
+
+        let completed = dir.path().join("completed");
+        storage.finish(Some(&completed)).unwrap();
+        assert_eq!(std::fs::read(completed.join("t/a")).unwrap(), [0, 1, 2]);
+        assert!(!completed.join("t/b").exists());
+        assert!(!completed.join("t/b.part").exists());
+        // the part file moved along with the data, the piece is still served from it
+        assert!(completed.join(".t.parts").exists());
+        assert!(!incomplete.join(".t.parts").exists());
+        assert_eq!(storage.read_block(0, 0, 4).unwrap(), data[..4]);
+    }
+
+    #[test]
+    fn test_copy_then_remove() {
+        let dir = tempdir().unwrap();
+        let from = dir.path().join("from");
+        std::fs::create_dir_all(from.join("sub")).unwrap();
+        std::fs::write(from.join("sub/a"), [1, 2]).unwrap();
+        let to = dir.path().join("to");
+        copy_then_remove(&from, &to).unwrap();
+        assert!(!from.exists());
+        assert!(!dir.path().join("to.moving").exists());
+        assert_eq!(std::fs::read(to.join("sub/a")).unwrap(), [1, 2]);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_copy_then_remove_keeps_symlinks() {
+        let dir = tempdir().unwrap();
+        let from = dir.path().join("from");
+        std::fs::create_dir_all(from.join("sub")).unwrap();
+        std::fs::write(from.join("sub/a"), [1, 2]).unwrap();
+        std::os::unix::fs::symlink("a", from.join("sub/link")).unwrap();
+        // following this one would never end
+        std::os::unix::fs::symlink("..", from.join("sub/up")).unwrap();
+        let to = dir.path().join("to");
+        copy_then_remove(&from, &to).unwrap();
+        assert!(!from.exists());
+        assert_eq!(
+            std::fs::read_link(to.join("sub/link")).unwrap(),
+            Path::new("a")
+        );
+        assert_eq!(
+            std::fs::read_link(to.join("sub/up")).unwrap(),
+            Path::new("..")
+        );
+        assert_eq!(std::fs::read(to.join("sub/link")).unwrap(), [1, 2]);
+    }
+}
@@ -1,125 +1,416 @@
-use anyhow::Context;
-use serde::{Deserialize, Serialize};
-use serde_bencode::{from_bytes, to_bytes};
-use serde_bytes::ByteBuf;
+use crate::bencode::{self, Dict, Value};
+use crate::layout::Layout;
+use encoding_rs::{Encoding, UTF_8};
 use sha1::{Digest, Sha1};
 use std::io::Read;
 
-#[derive(Debug, Deserialize, Serialize)]
-pub struct Node(String, i64);
+pub mod builder;
 
-/// a file can be single xor multi file torrent, if length is None, it's a multi file torrent, else it's a single file torrent
-#[derive(Debug, Deserialize, Serialize)]
+/// Why a metainfo file was rejected
+#[derive(Debug)]
+pub enum MetainfoError {
+    /// not valid bencode
+    Bencode(bencode::Error),
+    /// a required field isn't there
+    Missing(&'static str),
+    /// a field has the wrong type or a value that can't be right
+    Invalid(&'static str),
+}
+
+impl std::fmt::Display for MetainfoError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            MetainfoError::Bencode(e) => write!(f, "invalid bencode: {}", e),
+            MetainfoError::Missing(field) => write!(f, "missing field {:?}", field),
+            MetainfoError::Invalid(field) => write!(f, "invalid field {:?}", field),
+        }
+    }
+}
+
+impl std::error::Error for MetainfoError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            MetainfoError::Bencode(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<bencode::Error> for MetainfoError {
+    fn from(e: bencode::Error) -> Self {
+        MetainfoError::Bencode(e)
+    }
+}
+
+/// A DHT node to bootstrap from (BEP 5)
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Node {
+    pub host: String,
+    pub port: u16,
+}
+
+/// A file of a multi-file torrent
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct File {
-    /// The length of the file in bytes (integer)
-    pub path: Vec<String>,
-    /// The length of the file in bytes (integer)
-    pub length: i64,
+    /// The length of the file in bytes
+    pub length: u64,
+    /// path components, in the torrent's `encoding` (UTF-8 if absent) which isn't always honoured
+    pub path: Vec<Vec<u8>>,
+    /// (optional) the same path in UTF-8, written by clients next to a legacy `path`
+    pub path_utf8: Option<Vec<Vec<u8>>>,
     /// (optional) a 32-character hexadecimal string corresponding to the MD5 sum of the file. This is not used by BitTorrent at all, but it is included by some programs for greater compatibility.
-    #[serde(default)]
     pub md5sum: Option<String>,
+    /// (optional, BEP 47) attribute letters: `p` padding, `x` executable, `h` hidden, `l` symlink
+    pub attr: Option<String>,
+    /// (optional, BEP 47) SHA-1 of the whole file
+    pub sha1: Option<[u8; 20]>,
+    /// (optional, BEP 47) target of a symlink, as path components under the torrent directory
+    pub symlink_path: Option<Vec<Vec<u8>>>,
 }
 
-#[allow(dead_code)]
-#[derive(Debug, Deserialize, Serialize)]
+/// The `info` dictionary, which the info hash identifies
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct Info {
-    pub name: String,
+    /// in the torrent's `encoding` like `File::path`
+    pub name: Vec<u8>,
+    /// (optional) the name in UTF-8
+    pub name_utf8: Option<Vec<u8>>,
+    /// number of bytes in each piece
+    pub piece_length: u64,
     /// string consisting of the concatenation of all 20-byte SHA1 hash values, one per piece (byte string, i.e. not urlencoded)
-    pub pieces: ByteBuf,
-    /// number of bytes in each piece (integer)
-    #[serde(rename = "piece length")]
-    pub piece_length: i64,
-    #[serde(default)]
+    pub pieces: Vec<u8>,
+    /// length of the file of a single-file torrent, `None` for a multi-file torrent
+    pub length: Option<u64>,
     pub md5sum: Option<String>,
-    #[serde(default)]
-    pub length: Option<i64>,
-    #[serde(default)]
+    /// (optional, BEP 47) attributes of a single-file torrent's file, see `File::attr`
+    pub attr: Option<String>,
+    /// (optional, BEP 47) SHA-1 of a single-file torrent's file
+    pub sha1: Option<[u8; 20]>,
+    /// files of a multi-file torrent, `None` for a single-file torrent
     pub files: Option<Vec<File>>,
-    #[serde(default)]
-    pub private: Option<u8>,
-    #[serde(default)]
-    pub path: Option<Vec<String>>,
-    #[serde(default)]
-    #[serde(rename = "root hash")]
-    pub root_hash: Option<String>,
+    /// (BEP 27) peers only come from the trackers of the torrent, not DHT or peer exchange
+    pub private: bool,
+    /// (optional) tag that gives torrents of the same data on different sites different info
+    /// hashes
+    pub source: Option<String>,
 }
 
-#[derive(Debug, Deserialize, Serialize)]
+#[derive(Debug, Clone)]
 pub struct Torrent {
     pub info: Info,
-    #[serde(default)]
-    /// The announce URL of the tracker (string)
+    /// The announce URL of the tracker
     announce: Option<String>,
-    /// (optional) this is an extension to the official specification, offering backwards-compatibility. (list of lists of strings).
-    #[serde(default)]
-    nodes: Option<Vec<Node>>,
-    #[serde(default)]
-    encoding: Option<String>,
-    /// (optional) the creation time of the torrent, in standard UNIX epoch format (integer, seconds since 1-Jan-1970 00:00:00 UTC)
-    #[serde(default)]
-    httpseeds: Option<Vec<String>>,
-    /// (optional) free-form textual comments of the author (string)
-    #[serde(default)]
-    #[serde(rename = "announce-list")]
-    announce_list: Option<Vec<Vec<String>>>,
-    /// (optional) name and version of the program used to create the .torrent (string)
-    #[serde(default)]
-    #[serde(rename = "creation date")]
-    creation_date: Option<i64>,
-    /// (optional) the string encoding format used to generate the pieces part of the info dictionary in the .torrent metafile (string)
-    #[serde(rename = "comment")]
+    /// (BEP 12) tiers of tracker URLs, tried in order
+    announce_list: Vec<Vec<String>>,
+    /// (BEP 5) DHT nodes for trackerless torrents
+    nodes: Vec<Node>,
+    /// (BEP 19) web seeds serving the files over HTTP or FTP
+    url_list: Vec<String>,
+    /// (BEP 17) HTTP seeds serving pieces by info hash
+    httpseeds: Vec<String>,
+    /// free-form textual comments of the author
     comment: Option<String>,
-    #[serde(default)]
-    #[serde(rename = "created by")]
+    /// name and version of the program used to create the .torrent
     created_by: Option<String>,
+    /// the creation time of the torrent, in seconds since the UNIX epoch
+    creation_date: Option<i64>,
+    /// the character set of the strings in the torrent, e.g. `GBK`
+    encoding: Option<String>,
+    /// the `info` dictionary exactly as it appears in the file, which is what the info hash is
+    /// computed over
+    info_bytes: Vec<u8>,
+    info_hash: [u8; 20],
+    /// `info.name` decoded once at load time
+    name: String,
+    /// total length of the files, checked once at load time
+    length: u64,
+}
+
+/// Decodes a name from a torrent into text that is always the same for the same bytes. Bytes
+/// that aren't valid in `encoding` are kept as `%XX` so distinct names stay distinct.
+fn decode_text(bytes: &[u8], encoding: &'static Encoding) -> String {
+    if let Some(text) = encoding.decode_without_bom_handling_and_without_replacement(bytes) {
+        return text.into_owned();
+    }
+    let mut text = String::new();
+    for chunk in bytes.utf8_chunks() {
+        text.push_str(chunk.valid());
+        for byte in chunk.invalid() {
+            text.push_str(&format!("%{:02X}", byte));
+        }
+    }
+    text
+}
+
+/// Returns the UTF-8 variant of a string if the torrent has a valid one
+fn utf8(bytes: Option<&[u8]>) -> Option<String> {
+    String::from_utf8(bytes?.to_vec()).ok()
+}
+
+/// Reads an optional non-negative integer, failing if it has another type
+fn u64_field(dict: &Dict, key: &'static str) -> Result<Option<u64>, MetainfoError> {
+    match dict.get(key) {
+        None => Ok(None),
+        Some(value) => value
+            .as_int()
+            .and_then(|n| u64::try_from(n).ok())
+            .map(Some)
+            .ok_or(MetainfoError::Invalid(key)),
+    }
+}
+
+/// Reads an optional SHA-1, failing if it isn't 20 bytes
+fn sha1_field(dict: &Dict, key: &'static str) -> Result<Option<[u8; 20]>, MetainfoError> {
+    match dict.get(key) {
+        None => Ok(None),
+        Some(value) => value
+            .as_bytes()
+            .and_then(|bytes| bytes.try_into().ok())
+            .map(Some)
+            .ok_or(MetainfoError::Invalid(key)),
+    }
+}
+
+/// Reads an optional list of path components, failing if it is empty or has another type
+fn path_field(dict: &Dict, key: &'static str) -> Result<Option<Vec<Vec<u8>>>, MetainfoError> {
+    let Some(value) = dict.get(key) else {
+        return Ok(None);
+    };
+    let path: Option<Vec<Vec<u8>>> =
+        value
+            .as_list()
+            .filter(|list| !list.is_empty())
+            .and_then(|list| {
+                list.iter()
+                    .map(|c| c.as_bytes().map(<[u8]>::to_vec))
+                    .collect()
+            });
+    path.map(Some).ok_or(MetainfoError::Invalid(key))
+}
+
+/// Reads a list of strings, skipping anything that isn't one
+fn strings(list: &[Value]) -> Vec<String> {
+    list.iter()
+        .filter_map(|value| value.as_str().map(str::to_string))
+        .collect()
+}
+
+impl File {
+    fn from_value(value: &Value) -> Result<File, MetainfoError> {
+        let dict = value.as_dict().ok_or(MetainfoError::Invalid("files"))?;
+        Ok(File {
+            length: u64_field(dict, "length")?.ok_or(MetainfoError::Missing("length"))?,
+            path: path_field(dict, "path")?.ok_or(MetainfoError::Missing("path"))?,
+            path_utf8: path_field(dict, "path.utf-8")?,
+            md5sum: dict.get_str("md5sum").map(str::to_string),
+            attr: dict.get_str("attr").map(str::to_string),
+            sha1: sha1_field(dict, "sha1")?,
+            symlink_path: path_field(dict, "symlink path")?,
+        })
+    }
+}
+
+impl Info {
+    fn from_dict(dict: &Dict) -> Result<Info, MetainfoError> {
+        let piece_length =
+            u64_field(dict, "piece length")?.ok_or(MetainfoError::Missing("piece length"))?;
+        if piece_length == 0 {
+            return Err(MetainfoError::Invalid("piece length"));
+        }
+        let pieces = dict
+            .get_bytes("pieces")
+            .ok_or(MetainfoError::Missing("pieces"))?;
+        if pieces.len() % 20 != 0 {
+            return Err(MetainfoError::Invalid("pieces"));
+        }
+        let files = match dict.get("files") {
+            None => None,
+            Some(files) => Some(
+                files
+                    .as_list()
+                    .filter(|files| !files.is_empty())
+                    .ok_or(MetainfoError::Invalid("files"))?
+                    .iter()
+                    .map(File::from_value)
+                    .collect::<Result<Vec<_>, _>>()?,
+            ),
+        };
+        let length = u64_field(dict, "length")?;
+        // exactly one of them says whether this is a single or a multi-file torrent
+        match (length, &files) {
+            (None, None) => return Err(MetainfoError::Missing("length")),
+            (Some(_), Some(_)) => return Err(MetainfoError::Invalid("length")),
+            _ => {}
+        }
+        Ok(Info {
+            name: dict
+                .get_bytes("name")
+                .ok_or(MetainfoError::Missing("name"))?
+                .to_vec(),
+            name_utf8: dict.get_bytes("name.utf-8").map(<[u8]>::to_vec),
+            piece_length,
+            pieces: pieces.to_vec(),
+            length,
+            md5sum: dict.get_str("md5sum").map(str::to_string),
+            attr: dict.get_str("attr").map(str::to_string),
+            sha1: sha1_field(dict, "sha1")?,
+            files,
+            private: dict.get_int("private") == Some(1),
+            source: dict.get_str("source").map(str::to_string),
+        })
+    }
+
+    /// Total length of the files, rejected when it doesn't fit in a `u64`
+    pub fn total_length(&self) -> Result<u64, MetainfoError> {
+        match &self.files {
+            Some(files) => files
+                .iter()
+                .try_fold(0u64, |total, f| total.checked_add(f.length))
+                .ok_or(MetainfoError::Invalid("length")),
+            None => Ok(self.length.unwrap_or_default()),
+        }
+    }
 }
 
 impl Torrent {
-    pub fn from_bytes(bytes: &[u8]) -> anyhow::Result<Self> {
-        from_bytes(bytes).context("failed to deserialize torrent")
+    /// Parses a metainfo file, accepting the unsorted keys and padded numbers some tools write.
+    /// The info hash is taken over the info dictionary exactly as it appears in `bytes`.
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, MetainfoError> {
+        let value = bencode::Decoder::new(bytes).lenient().decode_all()?;
+        let dict = value.as_dict().ok_or(MetainfoError::Invalid("torrent"))?;
+        let info_dict = dict
+            .get_dict("info")
+            .ok_or(MetainfoError::Missing("info"))?;
+        let info = Info::from_dict(info_dict)?;
+        let length = info.total_length()?;
+        if info.pieces.len() / 20 != length.div_ceil(info.piece_length) as usize {
+            return Err(MetainfoError::Invalid("pieces"));
+        }
+
+        let encoding = dict.get_str("encoding").map(str::to_string);
+        let charset = encoding
+            .as_deref()
+            .and_then(|label| Encoding::for_label(label.as_bytes()))
+            .unwrap_or(UTF_8);
+        // free text prefers its `.utf-8` variant like names do
+        let text = |key: &str| {
+            utf8(dict.get_bytes(format!("{}.utf-8", key)))
+                .or_else(|| dict.get_bytes(key).map(|bytes| decode_text(bytes, charset)))
+        };
+        let url_list = match dict.get("url-list") {
+            Some(Value::List(urls)) => strings(urls),
+            Some(url) => url.as_str().map(str::to_string).into_iter().collect(),
+            None => Vec::new(),
+        };
+        let nodes = dict
+            .get_list("nodes")
+            .unwrap_or_default()
+            .iter()
+            .filter_map(|node| match node.as_list()? {
+                [host, port] => Some(Node {
+                    host: host.as_str()?.to_string(),
+                    port: u16::try_from(port.as_int()?).ok()?,
+                }),
+                _ => None,
+            })
+            .collect();
+        let info_bytes = info_dict.raw().unwrap_or_default().to_vec();
+        Ok(Torrent {
+            name: utf8(info.name_utf8.as_deref())
+                .unwrap_or_else(|| decode_text(&info.name, charset)),
+            info,
+            length,
+            announce: dict.get_str("announce").map(str::to_string),
+            announce_list: dict
+                .get_list("announce-list")
+                .unwrap_or_default()
+                .iter()
+                .filter_map(|tier| Some(strings(tier.as_list()?)))
+                .filter(|tier| !tier.is_empty())
+                .collect(),
+            nodes,
+            url_list,
+            httpseeds: strings(dict.get_list("httpseeds").unwrap_or_default()),
+            comment: text("comment"),
+            created_by: text("created by"),
+            creation_date: dict.get_int("creation date"),
+            encoding,
+            info_hash: Sha1::digest(&info_bytes).into(),
+            info_bytes,
+        })
     }
 
     pub fn from_file(path: &str) -> anyhow::Result<Self> {
-        let mut file = std::fs::File::open(path)?;
-        let mut buf = Vec::new();
-        file.read_to_end(&mut buf)?;
-        Self::from_bytes(&buf)
+        Self::from_path(std::path::Path::new(path))
     }
 
     pub fn from_path(path: &std::path::Path) -> anyhow::Result<Self> {
         let mut file = std::fs::File::open(path)?;
         let mut buf = Vec::new();
         file.read_to_end(&mut buf)?;
-        Self::from_bytes(&buf)
+        Ok(Self::from_bytes(&buf)?)
     }
 
+    /// SHA-1 of the `info` dictionary as it was read, so keys `Info` doesn't know about count
     pub fn info_hash(&self) -> [u8; 20] {
-        let bytes = to_bytes(&self.info).unwrap();
-        let mut hasher = Sha1::new();
-        hasher.update(bytes);
-        hasher.finalize().into()
+        self.info_hash
     }
 
-    pub fn announce(&self) -> &str {
-        self.announce.as_ref().unwrap()
+    /// The bencoded `info` dictionary as it was read
+    pub fn info_bytes(&self) -> &[u8] {
+        &self.info_bytes
     }
 
-    // if length is None, it's a multi file torrent, else it's a single file torrent
-    pub fn length(&self) -> i64 {
-        if let Some(length) = self.info.length {
-            length
-        } else {
-            self.info
-                .files
-                .as_ref()
-                .unwrap()
-                .iter()
-                .map(|f| f.length)
-                .sum()
-        }
+    /// The URL of the tracker, `None` for a trackerless torrent
+    pub fn announce(&self) -> Option<&str> {
+        self.announce.as_deref()
+    }
+
+    /// The tiers of tracker URLs from `announce-list`
+    pub fn tiers(&self) -> &[Vec<String>] {
+        &self.announce_list
+    }
+
+    pub fn nodes(&self) -> &[Node] {
+        &self.nodes
     }
 
-    pub fn piece_length(&self) -> i64 {
+    /// Web seed URLs from `url-list`
+    pub fn url_list(&self) -> &[String] {
+        &self.url_list
+    }
+
+    pub fn httpseeds(&self) -> &[String] {
+        &self.httpseeds
+    }
+
+    pub fn comment(&self) -> Option<&str> {
+        self.comment.as_deref()
+    }
+
+    pub fn created_by(&self) -> Option<&str> {
+        self.created_by.as_deref()
+    }
+
+    /// Seconds since the UNIX epoch
+    pub fn creation_date(&self) -> Option<i64> {
+        self.creation_date
+    }
+
+    pub fn is_private(&self) -> bool {
+        self.info.private
+    }
+
+    pub fn source(&self) -> Option<&str> {
+        self.info.source.as_deref()
+    }
+
+    /// Total length of the files of the torrent
+    pub fn length(&self) -> u64 {
+        self.length
+    }
+
+    pub fn piece_length(&self) -> u64 {
         self.info.piece_length
     }
 
@@ -135,19 +426,46 @@ impl Torrent {
             .collect()
     }
 
+    /// The name of the torrent as text, preferring `name.utf-8`
     pub fn name(&self) -> &str {
-        &self.info.name
+        &self.name
+    }
+
+    /// The character set the torrent's strings are in, from its `encoding` field
+    pub fn encoding(&self) -> &'static Encoding {
+        self.encoding
+            .as_deref()
+            .and_then(|label| Encoding::for_label(label.as_bytes()))
+            .unwrap_or(UTF_8)
+    }
+
+    /// Decodes a string of the torrent in its `encoding`
+    pub fn decode_text(&self, bytes: &[u8]) -> String {
+        decode_text(bytes, self.encoding())
+    }
+
+    /// The path components of a file as text, preferring `path.utf-8`
+    pub fn file_path(&self, file: &File) -> Vec<String> {
+        if let Some(path) = &file.path_utf8 {
+            if let Some(path) = path.iter().map(|c| utf8(Some(c))).collect() {
+                return path;
+            }
+        }
+        file.path.iter().map(|c| self.decode_text(c)).collect()
+    }
+
+    /// Returns which files each piece covers and which pieces each file covers
+    pub fn layout(&self) -> Layout {
+        Layout::from_torrent(self)
     }
 
     /// Returns the announce list as a vector of SocketAddr
     pub fn announce_list(&self) -> Vec<std::net::SocketAddr> {
         let mut addrs = Vec::new();
-        if let Some(announce_list) = &self.announce_list {
-            for urls in announce_list {
-                for url in urls {
-                    if let Ok(addr) = url.parse::<std::net::SocketAddr>() {
-                        addrs.push(addr);
-                    }
+        for urls in &self.announce_list {
+            for url in urls {
+                if let Ok(addr) = url.parse::<std::net::SocketAddr>() {
+                    addrs.push(addr);
                 }
             }
         }
@@ -165,16 +483,170 @@ impl Torrent {
 mod tests {
     use super::*;
     use crate::DEBIAN_FILE;
+    use std::path::PathBuf;
 
     #[test]
     fn test_torrent_announce() {
         let mut file = std::fs::File::open(DEBIAN_FILE).unwrap();
         let mut buf = Vec::new();
         file.read_to_end(&mut buf).unwrap();
-        let torrent: Torrent = from_bytes(&buf).unwrap();
+        let torrent = Torrent::from_bytes(&buf).unwrap();
         assert_eq!(
             torrent.announce(),
-            "http://bttracker.debian.org:6969/announce"
+            Some("http://bttracker.debian.org:6969/announce")
+        );
+    }
+
+    #[test]
+    fn test_torrent_info_hash() {
+        let torrent = Torrent::from_file(DEBIAN_FILE).unwrap();
+        assert_eq!(
+            torrent.info_hash(),
+            <[u8; 20]>::from(Sha1::digest(torrent.info_bytes()))
         );
+        let expected = [
+            0x9f, 0x3e, 0x5f, 0xc6, 0x2e, 0x9d, 0x0e, 0x80, 0xee, 0x32, 0x2a, 0x35, 0x55, 0x89,
+            0x6e, 0xd6, 0xef, 0x49, 0x89, 0x85,
+        ];
+        assert_eq!(torrent.info_hash(), expected);
+
+        // keys `Info` doesn't model still count
+        let info =
+            b"d6:lengthi1e4:name1:a12:piece lengthi4e6:pieces20:aaaaaaaaaaaaaaaaaaaa7:x-extra3:fooe";
+        let mut bytes = b"d8:announce3:url4:info".to_vec();
+        bytes.extend_from_slice(info);
+        bytes.extend_from_slice(b"4:name5:outere");
+        let torrent = Torrent::from_bytes(&bytes).unwrap();
+        assert_eq!(torrent.info_bytes(), &info[..]);
+        assert_eq!(torrent.info_hash(), <[u8; 20]>::from(Sha1::digest(info)));
+
+        assert!(Torrent::from_bytes(b"d4:infod4:name1:ae").is_err());
+    }
+
+    #[test]
+    fn test_torrent_legacy_names() {
+        let pieces = b"6:pieces20:aaaaaaaaaaaaaaaaaaaa";
+        let torrent = |encoding: &[u8], info: &[u8]| {
+            let bytes = [
+                encoding,
+                b"4:infod",
+                info,
+                b"12:piece lengthi4e",
+                pieces,
+                b"ee",
+            ];
+            Torrent::from_bytes(&[&b"d"[..], &bytes.concat()].concat()).unwrap()
+        };
+
+        // decoded with the declared encoding
+        let latin1 = torrent(b"8:encoding10:ISO-8859-1", b"6:lengthi1e4:name4:caf\xe9");
+        assert_eq!(latin1.name(), "caf\u{e9}");
+
+        // name.utf-8 wins over the legacy name
+        let both = torrent(
+            b"8:encoding9:SHIFT_JIS",
+            b"6:lengthi1e4:name2:\x82\xa010:name.utf-83:\xe3\x81\x82",
+        );
+        assert_eq!(both.name(), "\u{3042}");
+
+        // bytes that don't decode are kept, so the name is stable
+        let broken = torrent(b"", b"6:lengthi1e4:name3:a\xffb");
+        assert_eq!(broken.name(), "a%FFb");
+        assert_eq!(broken.layout().files()[0].path, PathBuf::from("a%FFb"));
+
+        let files = torrent(
+            b"",
+            b"5:filesld6:lengthi1e4:pathl2:\xff\xfee10:path.utf-8l4:\xc3\xa9.teed6:lengthi1e4:pathl1:\xffeee4:name1:t",
+        );
+        let paths: Vec<_> = files
+            .layout()
+            .files()
+            .iter()
+            .map(|f| f.path.clone())
+            .collect();
+        assert_eq!(paths, [PathBuf::from("t/\u{e9}.t"), PathBuf::from("t/%FF")]);
+    }
+
+    #[test]
+    fn test_torrent_metainfo() {
+        let torrent = Torrent::from_file(DEBIAN_FILE).unwrap();
+        assert_eq!(
+            torrent.comment(),
+            Some("\"Debian CD from cdimage.debian.org\"")
+        );
+        assert_eq!(torrent.created_by(), Some("mktorrent 1.1"));
+        assert_eq!(torrent.creation_date(), Some(1702236381));
+        assert_eq!(torrent.url_list().len(), 2);
+        assert_eq!(torrent.length(), 550809600);
+        assert!(!torrent.is_private());
+        assert!(torrent.tiers().is_empty() && torrent.nodes().is_empty());
+
+        let pieces = b"6:pieces20:aaaaaaaaaaaaaaaaaaaa";
+        let info = |fields: &[u8]| [&b"d"[..], fields, pieces, b"e"].concat();
+        let bytes = [
+            &b"d13:announce-listll3:udp3:tcpel0:ee4:info"[..],
+            &info(b"6:lengthi4e4:name1:t12:piece lengthi4e"),
+            b"5:nodesll4:hosti6881eei1ee8:url-list4:seede",
+        ]
+        .concat();
+        let torrent = Torrent::from_bytes(&bytes).unwrap();
+        assert_eq!(torrent.announce(), None);
+        assert_eq!(torrent.comment(), None);
+        assert_eq!(torrent.tiers(), [vec!["udp", "tcp"], vec![""]]);
+        assert_eq!(
+            torrent.nodes(),
+            [Node {
+                host: "host".into(),
+                port: 6881
+            }]
+        );
+        assert_eq!(torrent.url_list(), ["seed"]);
+
+        let error = |info_fields: &[u8]| {
+            let bytes = [&b"d4:info"[..], &info(info_fields), b"e"].concat();
+            Torrent::from_bytes(&bytes).unwrap_err().to_string()
+        };
+        assert_eq!(
+            error(b"4:name1:t12:piece lengthi4e"),
+            "missing field \"length\""
+        );
+        assert_eq!(
+            error(b"6:lengthi-1e4:name1:t12:piece lengthi4e"),
+            "invalid field \"length\""
+        );
+        // a second piece is missing
+        assert_eq!(
+            error(b"6:lengthi9e4:name1:t12:piece lengthi4e"),
+            "invalid field \"pieces\""
+        );
+        assert_eq!(
+            error(b"5:filesle6:lengthi1e4:name1:t12:piece lengthi4e"),
+            "invalid field \"files\""
+        );
+        let huge = &b"d6:lengthi9223372036854775807e4:pathl1:aee"[..];
+        let files = [
+            &b"5:filesl"[..],
+            huge,
+            huge,
+            huge,
+            b"e4:name1:t12:piece lengthi4e",
+        ]
+        .concat();
+        assert_eq!(error(&files), "invalid field \"length\"");
+        assert!(matches!(
+            Torrent::from_bytes(b"d4:infoi1e"),
+            Err(MetainfoError::Bencode(_))
+        ));
+    }
+
+    #[test]
+    fn test_torrent_lenient() {
+        // unsorted keys and a padded length, hashed as they are
+        let info = &b"d4:name1:t6:lengthi04e12:piece lengthi4e6:pieces20:aaaaaaaaaaaaaaaaaaaae"[..];
+        let bytes = [&b"d4:info"[..], info, b"8:announce3:urle"].concat();
+        let torrent = Torrent::from_bytes(&bytes).unwrap();
+        assert_eq!(torrent.length(), 4);
+        assert_eq!(torrent.announce(), Some("url"));
+        assert_eq!(torrent.info_hash(), <[u8; 20]>::from(Sha1::digest(info)));
     }
 }
@@ -0,0 +1,460 @@
+use crate::bencode::{Dict, Value};
+use crate::layout::{FileAttributes, Layout};
+use crate::storage::backend::read_exact_at;
+use anyhow::{bail, Context, Result};
+use sha1::{Digest, Sha1};
+use std::fs::File;
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+
+/// smallest piece length picked or accepted, the size of a block
+pub const MIN_PIECE_LENGTH: u64 = 16 * 1024;
+/// largest piece length picked automatically
+pub const MAX_PIECE_LENGTH: u64 = 16 * 1024 * 1024;
+/// number of pieces the automatic piece length aims for
+const TARGET_PIECES: u64 = 1500;
+
+/// Picks a power of two piece length that splits `total_length` into about 1500 pieces, which
+/// keeps the torrent small without making pieces costly to re-download
+pub fn auto_piece_length(total_length: u64) -> u64 {
+    (total_length / TARGET_PIECES)
+        .next_power_of_two()
+        .clamp(MIN_PIECE_LENGTH, MAX_PIECE_LENGTH)
+}
+
+/// A file going into the torrent
+#[derive(Debug)]
+struct Entry {
+    /// path components under the torrent directory
+    path: Vec<String>,
+    length: u64,
+    /// where to read it from, `None` for a padding file
+    source: Option<PathBuf>,
+}
+
+/// Creates a .torrent from a file or a directory
+#[derive(Debug)]
+pub struct TorrentBuilder {
+    path: PathBuf,
+    piece_length: Option<u64>,
+    /// tiers of tracker URLs, the first one becomes `announce`
+    tiers: Vec<Vec<String>>,
+    web_seeds: Vec<String>,
+    comment: Option<String>,
+    created_by: Option<String>,
+    creation_date: Option<i64>,
+    private: bool,
+    source: Option<String>,
+    /// align every file to a piece with BEP 47 padding files
+    pad_files: bool,
+    threads: usize,
+}
+
+impl TorrentBuilder {
+    /// Starts a torrent of `path`. A directory becomes a multi-file torrent of everything under
+    /// it, in path order.
+    pub fn new(path: impl Into<PathBuf>) -> TorrentBuilder {
+        let creation_date = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map(|now| now.as_secs() as i64)
+            .ok();
+        TorrentBuilder {
+            path: path.into(),
+            piece_length: None,
+            tiers: Vec::new(),
+            web_seeds: Vec::new(),
+            comment: None,
+            created_by: Some(concat!("bobby-bit/", env!("CARGO_PKG_VERSION")).to_string()),
+            creation_date,
+            private: false,
+            source: None,
+            pad_files: false,
+            threads: std::thread::available_parallelism().map_or(1, |n| n.get()),
+        }
+    }
+
+    /// Uses `piece_length` instead of picking one from the total size
+    pub fn set_piece_length(&mut self, piece_length: u64) {
+        self.piece_length = Some(piece_length);
+    }
+
+    /// Adds a tier with a single tracker
+    pub fn add_tracker(&mut self, url: String) {
+        self.tiers.push(vec![url]);
+    }
+
+    /// Adds a tier of trackers that are tried in random order
+    pub fn add_tier(&mut self, urls: Vec<String>) {
+        if !urls.is_empty() {
+            self.tiers.push(urls);
+        }
+    }
+
+    pub fn add_web_seed(&mut self, url: String) {
+        self.web_seeds.push(url);
+    }
+
+    pub fn set_comment(&mut self, comment: String) {
+        self.comment = Some(comment);
+    }
+
+    pub fn set_created_by(&mut self, created_by: Option<String>) {
+        self.created_by = created_by;
+    }
+
+    /// Seconds since the UNIX epoch, now by default. `None` leaves it out so the same files
+    /// always give the same torrent.
+    pub fn set_creation_date(&mut self, creation_date: Option<i64>) {
+        self.creation_date = creation_date;
+    }
+
+    pub fn set_private(&mut self, private: bool) {
+        self.private = private;
+    }
+
+    pub fn set_source(&mut self, source: String) {
+        self.source = Some(source);
+    }
+
+    pub fn set_pad_files(&mut self, pad_files: bool) {
+        self.pad_files = pad_files;
+    }
+
+    /// Threads hashing pieces, one per core by default
+    pub fn set_threads(&mut self, threads: usize) {
+        self.threads = threads.max(1);
+    }
+
+    /// Hashes the files and returns the bencoded torrent
+    pub fn build(self) -> Result<Vec<u8>> {
+        let name = self
+            .path
+            .canonicalize()
+            .with_context(|| format!("reading {}", self.path.display()))?
+            .file_name()
+            .and_then(|name| name.to_str())
+            .map(str::to_string)
+            .context("the name of the torrent is not valid UTF-8")?;
+        let multi_file = self.path.is_dir();
+        let mut entries = if multi_file {
+            let mut entries = Vec::new();
+            walk(&self.path, &mut Vec::new(), &mut entries)?;
+            entries
+        } else {
+            vec![Entry {
+                path: Vec::new(),
+                length: std::fs::metadata(&self.path)?.len(),
+                source: Some(self.path.clone()),
+            }]
+        };
+        let total_length: u64 = entries.iter().map(|entry| entry.length).sum();
+        if total_length == 0 {
+            bail!("{} has no data", self.path.display());
+        }
+
+        let piece_length = match self.piece_length {
+            Some(length) if length < MIN_PIECE_LENGTH || !length.is_power_of_two() => {
+                bail!(
+                    "Piece length {} is not a power of two of at least {}",
+                    length,
+                    MIN_PIECE_LENGTH
+                )
+            }
+            Some(length) => length,
+            None => auto_piece_length(total_length),
+        };
+        if multi_file && self.pad_files {
+            entries = pad(entries, piece_length);
+        }
+        let pieces = self.hash_pieces(&entries, piece_length)?;
+
+        let mut info = Dict::new();
+        info.insert(b"name", Value::Bytes(name.as_bytes()));
+        info.insert(b"piece length", Value::Int(piece_length as i64));
+        info.insert(b"pieces", Value::Bytes(&pieces));
+        if multi_file {
+            let files = entries.iter().map(|entry| {
+                let mut file = Dict::new();
+                file.insert(b"length", Value::Int(entry.length as i64));
+                let path = entry.path.iter().map(|c| Value::Bytes(c.as_bytes()));
+                file.insert(b"path", Value::List(path.collect()));
+                if entry.source.is_none() {
+                    file.insert(b"attr", Value::Bytes(b"p"));
+                }
+                Value::Dict(file)
+            });
+            info.insert(b"files", Value::List(files.collect()));
+        } else {
+            info.insert(b"length", Value::Int(total_length as i64));
+        }
+        if self.private {
+            info.insert(b"private", Value::Int(1));
+        }
+        if let Some(source) = &self.source {
+            info.insert(b"source", Value::Bytes(source.as_bytes()));
+        }
+
+        let mut torrent = Dict::new();
+        torrent.insert(b"info", Value::Dict(info));
+        if let Some(announce) = self.tiers.first().and_then(|tier| tier.first()) {
+            torrent.insert(b"announce", Value::Bytes(announce.as_bytes()));
+        }
+        if self.tiers.iter().map(Vec::len).sum::<usize>() > 1 {
+            let tiers = self.tiers.iter().map(|tier| strings(tier));
+            torrent.insert(b"announce-list", Value::List(tiers.collect()));
+        }
+        if !self.web_seeds.is_empty() {
+            torrent.insert(b"url-list", strings(&self.web_seeds));
+        }
+        if let Some(comment) = &self.comment {
+            torrent.insert(b"comment", Value::Bytes(comment.as_bytes()));
+        }
+        if let Some(created_by) = &self.created_by {
+            torrent.insert(b"created by", Value::Bytes(created_by.as_bytes()));
+        }
+        if let Some(date) = self.creation_date {
+            torrent.insert(b"creation date", Value::Int(date));
+        }
+        Ok(Value::Dict(torrent).encode())
+    }
+
+    /// Hashes the pieces on `threads` threads, each taking a run of consecutive pieces
+    fn hash_pieces(&self, entries: &[Entry], piece_length: u64) -> Result<Vec<u8>> {
+        let files = entries
+            .iter()
+            .map(|entry| (entry.path.iter().collect(), entry.length))
+            .collect();
+        let mut layout = Layout::new(files, piece_length)?;
+        for (index, entry) in entries.iter().enumerate() {
+            if entry.source.is_none() {
+                let padding = FileAttributes {
+                    padding: true,
+                    ..FileAttributes::default()
+                };
+                layout.set_attributes(index, padding, None);
+            }
+        }
+
+        let num_pieces = layout.num_pieces();
+        let per_thread = num_pieces.div_ceil(self.threads);
+        let runs: Vec<Range<usize>> = (0..num_pieces)
+            .step_by(per_thread)
+            .map(|start| start..(start + per_thread).min(num_pieces))
+            .collect();
+        let hashes = std::thread::scope(|scope| {
+            let workers: Vec<_> = runs
+                .into_iter()
+                .map(|run| {
+                    let layout = &layout;
+                    scope.spawn(move || hash_run(layout, entries, run))
+                })
+                .collect();
+            workers
+                .into_iter()
+                .map(|worker| worker.join().unwrap())
+                .collect::<Result<Vec<_>>>()
+        })?;
+        Ok(hashes.concat())
+    }
+}
+
+fn strings(strings: &[String]) -> Value<'_> {
+    Value::List(strings.iter().map(|s| Value::Bytes(s.as_bytes())).collect())
+}
+
+/// Hashes the pieces in `run`, keeping the file being read open
+fn hash_run(layout: &Layout, entries: &[Entry], run: Range<usize>) -> Result<Vec<u8>> {
+    let mut hashes = Vec::with_capacity(run.len() * 20);
+    let mut buffer = Vec::new();
+    let mut open: Option<(usize, File)> = None;
+    for piece_index in run {
+        buffer.resize(layout.piece_size(piece_index) as usize, 0);
+        let mut read = 0;
+        for slice in layout.piece_files(piece_index) {
+            let buf = &mut buffer[read..read + slice.length as usize];
+            read += buf.len();
+            let Some(source) = &entries[slice.file_index].source else {
+                buf.fill(0);
+                continue;
+            };
+            if open
+                .as_ref()
+                .is_none_or(|(index, _)| *index != slice.file_index)
+            {
+                let file =
+                    File::open(source).with_context(|| format!("reading {}", source.display()))?;
+                open = Some((slice.file_index, file));
+            }
+            let (_, file) = open.as_ref().unwrap();
+            read_exact_at(file, buf, slice.offset)
+                .with_context(|| format!("reading {}", source.display()))?;
+        }
+        hashes.extend_from_slice(&Sha1::digest(&buffer));
+    }
+    Ok(hashes)
+}
+
+/// Collects the files under `dir` in path order, `path` holding the components so far
+fn walk(dir: &Path, path: &mut Vec<String>, entries: &mut Vec<Entry>) -> Result<()> {
+    let mut children = std::fs::read_dir(dir)?.collect::<std::io::Result<Vec<_>>>()?;
+    children.sort_by_key(|child| child.file_name());
+    for child in children {
+        let name = child
+            .file_name()
+            .into_string()
+            .map_err(|name| anyhow::anyhow!("{} is not valid UTF-8", dir.join(name).display()))?;
+        let source = child.path();
+        // a link may point outside the tree or back up it, so links aren't shared at all
+        let metadata = std::fs::symlink_metadata(&source)?;
+        if metadata.is_symlink() {
+            log::warn!("Skipping symlink {}", source.display());
+            continue;
+        }
+        path.push(name);
+        if metadata.is_dir() {
+            walk(&source, path, entries)?;
+        } else {
+            entries.push(Entry {
+                path: path.clone(),
+                length: metadata.len(),
+                source: Some(source),
+            });
+        }
+        path.pop();
+    }
+    Ok(())
+}
+
+/// Inserts a padding file after every file that doesn't end on a piece boundary, except the
+/// last, so each file starts on its own piece (BEP 47)
+fn pad(entries: Vec<Entry>, piece_length: u64) -> Vec<Entry> {
+    let count = entries.len();
+    let mut padded = Vec::with_capacity(count * 2);
+    let mut offset = 0;
+    for (index, entry) in entries.into_iter().enumerate() {
+        offset += entry.length;
+        padded.push(entry);
+        let gap = (piece_length - offset % piece_length) % piece_length;
+        if gap > 0 && index + 1 < count {
+            padded.push(Entry {
+                path: vec![".pad".to_string(), gap.to_string()],
+                length: gap,
+                source: None,
+            });
+            offset += gap;
+        }
+    }
+    padded
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::torrent::Torrent;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_auto_piece_length() {
+        assert_eq!(auto_piece_length(1), MIN_PIECE_LENGTH);
+        assert_eq!(auto_piece_length(700 * 1024 * 1024), 512 * 1024);
+        assert_eq!(auto_piece_length(u64::MAX / 2), MAX_PIECE_LENGTH);
+    }
+
+    #[test]
+    fn test_build_directory() {
+        let dir = tempdir().unwrap();
+        let root = dir.path().join("release");
+        std::fs::create_dir_all(root.join("bin")).unwrap();
+        let a: Vec<u8> = (0..40000u32).map(|i| i as u8).collect();
+        let b: Vec<u8> = (0..10000u32).map(|i| (i * 7) as u8).collect();
+        std::fs::write(root.join("bin/tool"), &a).unwrap();
+        std::fs::write(root.join("README"), &b).unwrap();
+        std::fs::write(root.join("empty"), []).unwrap();
+        #[cfg(unix)]
+        {
+            std::os::unix::fs::symlink("README", root.join("link")).unwrap();
+            std::os::unix::fs::symlink("..", root.join("bin/up")).unwrap();
+        }
+
+        let build = |threads: usize| {
+            let mut builder = TorrentBuilder::new(&root);
+            builder.set_piece_length(MIN_PIECE_LENGTH);
+            builder.add_tier(vec!["http://a/announce".into(), "http://b/announce".into()]);
+            builder.add_tracker("udp://c:80".into());
+            builder.add_web_seed("https://mirror/release".into());
+            builder.set_comment("nightly".into());
+            builder.set_private(true);
+            builder.set_source("ci".into());
+            builder.set_creation_date(None);
+            builder.set_threads(threads);
+            builder.build().unwrap()
+        };
+        let bytes = build(3);
+        // the same bytes whatever the threads, and canonical
+        assert_eq!(bytes, build(1));
+        assert_eq!(crate::bencode::decode(&bytes).unwrap().encode(), bytes);
+
+        let torrent = Torrent::from_bytes(&bytes).unwrap();
+        assert_eq!(torrent.name(), "release");
+        assert_eq!(torrent.announce(), Some("http://a/announce"));
+        assert_eq!(torrent.tiers().len(), 2);
+        assert_eq!(torrent.url_list(), ["https://mirror/release"]);
+        assert_eq!(torrent.comment(), Some("nightly"));
+        assert_eq!(torrent.creation_date(), None);
+        assert!(torrent.is_private());
+        assert_eq!(torrent.source(), Some("ci"));
+
+        // files in path order, hashed as one stream
+        let paths: Vec<_> = torrent
+            .info
+            .files
+            .as_ref()
+            .unwrap()
+            .iter()
+            .map(|f| torrent.file_path(f).join("/"))
+            .collect();
+        assert_eq!(paths, ["README", "bin/tool", "empty"]);
+        let data = [b.clone(), a.clone()].concat();
+        let expected: Vec<[u8; 20]> = data
+            .chunks(MIN_PIECE_LENGTH as usize)
+            .map(|piece| Sha1::digest(piece).into())
+            .collect();
+        assert_eq!(torrent.piece_hashes(), expected);
+    }
+
+    #[test]
+    fn test_build_padded_and_single_file() {
+        let dir = tempdir().unwrap();
+        let root = dir.path().join("t");
+        std::fs::create_dir_all(&root).unwrap();
+        std::fs::write(root.join("a"), [1; 100]).unwrap();
+        std::fs::write(root.join("b"), [2; 20000]).unwrap();
+
+        let mut builder = TorrentBuilder::new(&root);
+        builder.set_pad_files(true);
+        let torrent = Torrent::from_bytes(&builder.build().unwrap()).unwrap();
+        let layout = torrent.layout();
+        let files = layout.files();
+        assert_eq!(files.len(), 3);
+        assert!(files[1].attributes.padding);
+        assert_eq!(files[1].length, MIN_PIECE_LENGTH - 100);
+        assert_eq!(files[2].offset, MIN_PIECE_LENGTH);
+        let mut first = vec![1; 100];
+        first.resize(MIN_PIECE_LENGTH as usize, 0);
+        assert_eq!(
+            torrent.piece_hashes()[0],
+            <[u8; 20]>::from(Sha1::digest(&first))
+        );
+
+        let torrent =
+            Torrent::from_bytes(&TorrentBuilder::new(root.join("b")).build().unwrap()).unwrap();
+        assert_eq!(torrent.name(), "b");
+        assert_eq!(torrent.length(), 20000);
+        assert!(torrent.info.files.is_none());
+        assert!(torrent.creation_date().is_some());
+
+        let mut builder = TorrentBuilder::new(root.join("a"));
+        builder.set_piece_length(1000);
+        assert!(builder.build().is_err());
+    }
+}
@@ -1,3 +1,4 @@
+use crate::bencode::{Decoder, Value};
 use crate::torrent::Torrent;
 use anyhow::{anyhow, Result};
 use mio::net::TcpStream;
@@ -104,99 +105,55 @@ impl AnnounceRequest {
     }
 }
 
-/// deserialize peers from compact representation for both ipv4 and ipv6
+/// peers in the compact representation for both ipv4 and ipv6, or as a list of dictionaries
 mod peers {
-    use serde::de::{self, Deserialize, Deserializer, Visitor};
-    use serde::ser::{Serialize, Serializer};
-    use std::fmt;
-    use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6};
+    use crate::bencode::Value;
+    use anyhow::{anyhow, Result};
+    use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6};
 
     #[derive(Debug, Clone)]
     pub struct Peers(pub Vec<SocketAddr>);
-    struct PeersVisitor;
 
-    impl<'de> Visitor<'de> for PeersVisitor {
-        type Value = Peers;
-
-        fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
-            formatter.write_str("compact representation of peers")
-        }
-
-        fn visit_bytes<E>(self, v: &[u8]) -> Result<Self::Value, E>
-        where
-            E: de::Error,
-        {
-            let mut peers = Vec::new();
-            let mut i = 0;
-            while i < v.len() {
-                if i + 6 <= v.len() {
-                    let addr = Ipv4Addr::new(v[i], v[i + 1], v[i + 2], v[i + 3]);
-                    let port = u16::from_be_bytes([v[i + 4], v[i + 5]]);
-                    peers.push(SocketAddr::V4(SocketAddrV4::new(addr, port)));
-                    i += 6;
-                } else if i + 18 <= v.len() {
-                    let addr = Ipv6Addr::from([
-                        v[i],
-                        v[i + 1],
-                        v[i + 2],
-                        v[i + 3],
-                        v[i + 4],
-                        v[i + 5],
-                        v[i + 6],
-                        v[i + 7],
-                        v[i + 8],
-                        v[i + 9],
-                        v[i + 10],
-                        v[i + 11],
-                        v[i + 12],
-                        v[i + 13],
-                        v[i + 14],
-                        v[i + 15],
-                    ]);
-                    let port = u16::from_be_bytes([v[i + 16], v[i + 17]]);
-                    peers.push(SocketAddr::V6(SocketAddrV6::new(addr, port, 0, 0)));
-                    i += 18;
-                } else {
-                    return Err(E::custom("Invalid peer length"));
-                }
+    pub fn from_compact(v: &[u8]) -> Result<Vec<SocketAddr>> {
+        let mut peers = Vec::new();
+        let mut i = 0;
+        while i < v.len() {
+            if i + 6 <= v.len() {
+                let addr = Ipv4Addr::new(v[i], v[i + 1], v[i + 2], v[i + 3]);
+                let port = u16::from_be_bytes([v[i + 4], v[i + 5]]);
+                peers.push(SocketAddr::V4(SocketAddrV4::new(addr, port)));
+                i += 6;
+            } else if i + 18 <= v.len() {
+                let octets: [u8; 16] = v[i..i + 16].try_into().unwrap();
+                let port = u16::from_be_bytes([v[i + 16], v[i + 17]]);
+                peers.push(SocketAddr::V6(SocketAddrV6::new(
+                    Ipv6Addr::from(octets),
+                    port,
+                    0,
+                    0,
+                )));
+                i += 18;
+            } else {
+                return Err(anyhow!("Invalid peer length"));
             }
-            Ok(Peers(peers))
-        }
-    }
-
-    impl<'de> Deserialize<'de> for Peers {
-        fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
-        where
-            D: Deserializer<'de>,
-        {
-            deserializer.deserialize_bytes(PeersVisitor)
         }
+        Ok(peers)
     }
 
-    impl Serialize for Peers {
-        fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
-        where
-            S: Serializer,
-        {
-            let mut single_slice = Vec::new();
-            for peer in &self.0 {
-                match peer {
-                    SocketAddr::V4(addr) => {
-                        single_slice.extend(addr.ip().octets());
-                        single_slice.extend(addr.port().to_be_bytes());
-                    }
-                    SocketAddr::V6(addr) => {
-                        single_slice.extend(addr.ip().octets());
-                        single_slice.extend(addr.port().to_be_bytes());
-                    }
-                }
-            }
-            serializer.serialize_bytes(&single_slice)
-        }
+    /// the non-compact form, `{ip, port, peer id}` dictionaries, skipping unusable entries
+    pub fn from_dicts(list: &[Value]) -> Vec<SocketAddr> {
+        list.iter()
+            .filter_map(|peer| {
+                let peer = peer.as_dict()?;
+                let ip: IpAddr = peer.get_str("ip")?.parse().ok()?;
+                let port = u16::try_from(peer.get_int("port")?).ok()?;
+                Some(SocketAddr::new(ip, port))
+            })
+            .collect()
     }
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug)]
 pub struct AnnounceResponse {
     /// can still have a 200 ok, but this indicates a failure within the BT protocol request
     pub failure_reason: Option<String>,
@@ -240,6 +197,35 @@ impl AnnounceResponse {
     pub fn peers(&self) -> Vec<SocketAddr> {
         self.peers.0.clone()
     }
+
+    /// Reads the bencoded body of an announce response
+    pub fn from_bencode(body: &[u8]) -> Result<AnnounceResponse> {
+        let value = Decoder::new(body).lenient().decode_all()?;
+        let dict = value
+            .as_dict()
+            .ok_or(anyhow!("Announce response is not a dictionary"))?;
+        let string = |key: &str| {
+            dict.get_bytes(key)
+                .map(|bytes| String::from_utf8_lossy(bytes).into_owned())
+        };
+        let int = |key: &str| dict.get_int(key).and_then(|n| u64::try_from(n).ok());
+        let peers = match dict.get("peers") {
+            Some(Value::Bytes(compact)) => peers::from_compact(compact)?,
+            Some(Value::List(list)) => peers::from_dicts(list),
+            _ => Vec::new(),
+        };
+        Ok(AnnounceResponse {
+            failure_reason: string("failure reason"),
+            warning_message: string("warning message"),
+            // a failure doesn't come with an interval
+            interval: int("interval").unwrap_or_default(),
+            min_interval: int("min interval"),
+            tracker_id: string("tracker id"),
+            complete: int("complete"),
+            incomplete: int("incomplete"),
+            peers: peers::Peers(peers),
+        })
+    }
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -252,56 +238,43 @@ pub struct ScrapeResponse {
     pub files: HashMap<Vec<u8>, ScrapeResponseFile>,
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, PartialEq, Eq)]
 pub struct ScrapeResponseFile {
     pub complete: u64,
     pub incomplete: u64,
     pub downloaded: u64,
 }
 
-struct ScrapeResponseVisitor;
-
-impl<'de> serde::de::Visitor<'de> for ScrapeResponseVisitor {
-    type Value = ScrapeResponse;
-
-    fn expecting(&self, formatter: &mut std::fmt::Formatter) -> std::fmt::Result {
-        formatter.write_str("a bencoded dictionary")
-    }
-
-    fn visit_map<A>(self, mut map: A) -> Result<ScrapeResponse, A::Error>
-    where
-        A: serde::de::MapAccess<'de>,
-    {
-        let mut files = HashMap::new();
-        while let Some(key) = map.next_key::<Vec<u8>>()? {
-            let file = map.next_value::<ScrapeResponseFile>()?;
-            files.insert(key, file);
-        }
-        Ok(ScrapeResponse { files })
-    }
-
-    fn visit_seq<A>(self, mut seq: A) -> Result<ScrapeResponse, A::Error>
-    where
-        A: serde::de::SeqAccess<'de>,
-    {
-        let mut files = HashMap::new();
-        while let Some(key) = seq.next_element::<Vec<u8>>()? {
-            let file = seq.next_element::<ScrapeResponseFile>()?.unwrap();
-            files.insert(key, file);
-        }
+impl ScrapeResponse {
+    /// Reads the bencoded body of a scrape response, the stats of each torrent keyed by info hash
+    pub fn from_bencode(body: &[u8]) -> Result<ScrapeResponse> {
+        let value = Decoder::new(body).lenient().decode_all()?;
+        let files = value
+            .lookup(&["files"])
+            .and_then(Value::as_dict)
+            .ok_or(anyhow!("Scrape response has no files"))?;
+        let files = files
+            .iter()
+            .filter_map(|(info_hash, stats)| {
+                let stats = stats.as_dict()?;
+                let int = |key: &str| {
+                    stats
+                        .get_int(key)
+                        .and_then(|n| u64::try_from(n).ok())
+                        .unwrap_or_default()
+                };
+                let file = ScrapeResponseFile {
+                    complete: int("complete"),
+                    incomplete: int("incomplete"),
+                    downloaded: int("downloaded"),
+                };
+                Some((info_hash.to_vec(), file))
+            })
+            .collect();
         Ok(ScrapeResponse { files })
     }
 }
 
-impl<'de> Deserialize<'de> for ScrapeResponse {
-    fn deserialize<D>(deserializer: D) -> Result<ScrapeResponse, D::Error>
-    where
-        D: serde::Deserializer<'de>,
-    {
-        deserializer.deserialize_map(ScrapeResponseVisitor)
-    }
-}
-
 #[derive(Debug)]
 pub struct HttpTracker {
     poll: Poll,
@@ -322,7 +295,11 @@ impl HttpTracker {
         my_port: u16,
         compact: Option<u8>,
     ) -> Result<AnnounceResponse> {
-        let announce_url = Url::parse(torrent.announce())?;
+        let announce_url = Url::parse(
+            torrent
+                .announce()
+                .ok_or(anyhow!("Torrent has no tracker"))?,
+        )?;
         let host = announce_url.host_str().ok_or(anyhow!("no host"))?;
         let port = announce_url.port().unwrap();
         let addr = format!("{}:{}", host, port)
@@ -364,7 +341,7 @@ impl HttpTracker {
                 .poll(&mut self.events, Some(Duration::from_secs(3)))?;
             for event in self.events.iter() {
                 match event.token() {
-                    token if token == token => {
+                    t if t == token => {
                         if self.events.is_empty() {
                             return Err(anyhow!("Timeout waiting for tracker response"));
                         }
@@ -400,7 +377,11 @@ impl HttpTracker {
         let mut poll = Poll::new()?;
         let mut events = Events::with_capacity(1024);
 
-        let announce_url = Url::parse(torrent.announce())?;
+        let announce_url = Url::parse(
+            torrent
+                .announce()
+                .ok_or(anyhow!("Torrent has no tracker"))?,
+        )?;
         // change /announce in the url to /scrape
         let mut scrape_url = announce_url.clone();
         let mut path = scrape_url.path().to_string();
@@ -437,7 +418,7 @@ impl HttpTracker {
             poll.poll(&mut events, Some(Duration::from_secs(5)))?;
             for event in events.iter() {
                 match event.token() {
-                    token if token == token => {
+                    t if t == token => {
                         if events.is_empty() {
                             return Err(anyhow!("Timeout waiting for tracker response"));
                         }
@@ -480,8 +461,7 @@ fn parse_announce_response(raw: &[u8]) -> Result<AnnounceResponse> {
 
     log::debug!("Body: {:?}", body);
 
-    let body = serde_bencode::from_bytes::<AnnounceResponse>(&body)?;
-    Ok(body)
+    AnnounceResponse::from_bencode(&body)
 }
 
 fn parse_scrape_response(raw: &[u8]) -> Result<ScrapeResponse> {
@@ -505,14 +485,7 @@ fn parse_scrape_response(raw: &[u8]) -> Result<ScrapeResponse> {
     let body = &raw[header_end..];
     log::debug!("Body: {:?}", body);
 
-    // try to put it in a string
-    let body = String::from_utf8_lossy(body);
-    log::debug!("Body: {}", body);
-
-    // Deserialize the bencoded response body directly from bytes
-    let scrape_response = serde_bencode::from_bytes::<ScrapeResponse>(body.as_bytes())?;
-
-    Ok(scrape_response)
+    ScrapeResponse::from_bencode(body)
 }
 
 #[cfg(test)]
@@ -539,4 +512,42 @@ mod tests {
     fn test_scrape() {
         // TODO: fix this test
     }
+
+    #[test]
+    fn test_parse_announce_response() {
+        let mut raw = b"HTTP/1.1 200 OK\r\nContent-Type: text/plain\r\n\r\n".to_vec();
+        raw.extend_from_slice(b"d8:completei4e8:intervali1800e12:min intervali60e5:peers6:");
+        raw.extend_from_slice(&[127, 0, 0, 1, 0x1a, 0xe1]);
+        raw.extend_from_slice(b"e");
+        let response = parse_announce_response(&raw).unwrap();
+        assert_eq!(response.interval, 1800);
+        assert_eq!(response.min_interval, Some(60));
+        assert_eq!(response.complete, Some(4));
+        assert_eq!(response.peers(), vec!["127.0.0.1:6881".parse().unwrap()]);
+
+        let response = AnnounceResponse::from_bencode(
+            b"d5:peersld2:ip3:::14:porti80eed2:ip5:bogus4:porti1eeee",
+        )
+        .unwrap();
+        assert_eq!(response.peers(), vec!["[::1]:80".parse().unwrap()]);
+
+        let response = AnnounceResponse::from_bencode(b"d14:failure reason6:bannede").unwrap();
+        assert_eq!(response.failure_reason.as_deref(), Some("banned"));
+    }
+
+    #[test]
+    fn test_parse_scrape_response() {
+        let mut body = b"d5:filesd20:".to_vec();
+        body.extend_from_slice(&[7; 20]);
+        body.extend_from_slice(b"d8:completei5e10:downloadedi50e10:incompletei10eeee");
+        let response = ScrapeResponse::from_bencode(&body).unwrap();
+        assert_eq!(
+            response.files[&vec![7; 20]],
+            ScrapeResponseFile {
+                complete: 5,
+                incomplete: 10,
+                downloaded: 50
+            }
+        );
+    }
 }
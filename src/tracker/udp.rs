@@ -75,6 +75,7 @@ pub struct ScrapeResponse {
     leechers: u32,
 }
 
+#[allow(dead_code)]
 #[derive(Debug, Serialize, Deserialize)]
 struct Error {
     action: u32,
@@ -159,7 +160,7 @@ impl UdpTracker {
             info_hash: torrent.info_hash(),
             peer_id: generate_peer_id(),
             downloaded: 0,
-            left: torrent.length(),
+            left: torrent.length() as i64,
             uploaded: 0,
             event: 0,
             ip_address: 0,
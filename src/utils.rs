@@ -24,7 +24,7 @@ pub fn find_peers(torrent: &Torrent, peer_id: [u8; 20], port: u16) -> Vec<std::n
         let announce_list = torrent.announce_list();
         let tracker_response = udp_tracker
             .expect("udp tracker")
-            .announce(announce_list[0], &torrent)
+            .announce(announce_list[0], torrent)
             .unwrap();
         log::info!("tracker response: {:?}", tracker_response);
 
@@ -34,7 +34,7 @@ pub fn find_peers(torrent: &Torrent, peer_id: [u8; 20], port: u16) -> Vec<std::n
         let http_tracker = HttpTracker::new();
         let tracker_response = http_tracker
             .expect("http tracker")
-            .announce(&torrent, peer_id, port, Some(1))
+            .announce(torrent, peer_id, port, Some(1))
             .unwrap();
         log::info!("tracker response: {:?}", tracker_response);
 